@@ -1,7 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{FlushPolicy, KvStore, KvStoreConfig, KvsEngine, SledConfig, SledKvsEngine};
 use rand::prelude::*;
 use sled;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -34,6 +35,27 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    group.bench_function("sled_periodic_flush", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let config = SledConfig {
+                    flush: FlushPolicy::Periodic(Duration::from_millis(500)),
+                    ..SledConfig::default()
+                };
+                (
+                    SledKvsEngine::open_with_config(&temp_dir, config).unwrap(),
+                    temp_dir,
+                )
+            },
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
@@ -56,6 +78,27 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("kvs_hashed_index_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = KvStoreConfig {
+                index_memory_budget: Some(0),
+                ..KvStoreConfig::default()
+            };
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        });
+    }
     for i in &vec![8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
@@ -0,0 +1,138 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// An operation an ACL rule allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    /// Getting keys, their history, and watching them
+    Read,
+    /// Setting keys
+    Write,
+    /// Removing keys
+    Delete,
+    /// The statistics, compaction and backups of the whole store
+    Admin,
+}
+
+impl Permission {
+    fn parse(s: &str) -> Option<Permission> {
+        match s {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "delete" => Some(Permission::Delete),
+            "admin" => Some(Permission::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+            Permission::Admin => "admin",
+        })
+    }
+}
+
+/// What each identity may do on which keys, read from a file and reloadable
+/// while the server runs. Clones share the rules, reloading one reloads them all.
+///
+/// An ACL file has a rule per line, `IDENTITY PERMISSIONS PREFIX*`: the identity
+/// as authenticated, or `*` for every connection, the permissions among `read`,
+/// `write`, `delete` and `admin` separated by commas, and the prefix of the keys
+/// the rule covers followed by `*`, so a lone `*` covers every key. Rules only grant,
+/// a request is allowed when a rule grants it. `admin` is only granted by rules
+/// covering every key. Blank lines and lines starting with `#` are ignored.
+#[derive(Clone)]
+pub struct Acl {
+    path: PathBuf,
+    rules: Arc<RwLock<Vec<Rule>>>,
+}
+
+struct Rule {
+    ///`None` for every connection
+    identity: Option<String>,
+    permissions: Vec<Permission>,
+    prefix: String,
+}
+
+impl Acl {
+    /// Reads the rules of a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Acl> {
+        let path = path.as_ref().to_owned();
+        let rules = read_rules(&path)?;
+        Ok(Acl {
+            path,
+            rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    /// Reads the file again, the rules apply from the next request on.
+    /// When the file is invalid the rules in use are kept.
+    pub fn reload(&self) -> Result<()> {
+        let rules = read_rules(&self.path)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    ///whether `identity` has `permission` on every key starting with `prefix`,
+    ///a key is checked as the prefix of itself
+    pub(crate) fn allows(
+        &self,
+        identity: Option<&str>,
+        permission: Permission,
+        prefix: &str,
+    ) -> bool {
+        self.rules.read().unwrap().iter().any(|rule| {
+            rule.identity
+                .as_deref()
+                .is_none_or(|name| Some(name) == identity)
+                && rule.permissions.contains(&permission)
+                && prefix.starts_with(&rule.prefix)
+        })
+    }
+}
+
+fn read_rules(path: &Path) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        rules.push(parse_rule(line).ok_or_else(|| {
+            KvsError::StringError(format!(
+                "{}:{}: expected `IDENTITY PERMISSIONS PREFIX*`",
+                path.display(),
+                i + 1
+            ))
+        })?);
+    }
+    Ok(rules)
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (identity, permissions, pattern) = match fields[..] {
+        [identity, permissions, pattern] => (identity, permissions, pattern),
+        _ => return None,
+    };
+    let permissions = permissions
+        .split(',')
+        .map(Permission::parse)
+        .collect::<Option<Vec<_>>>()?;
+    Some(Rule {
+        identity: Some(identity)
+            .filter(|&name| name != "*")
+            .map(str::to_owned),
+        permissions,
+        prefix: pattern.strip_suffix('*')?.to_owned(),
+    })
+}
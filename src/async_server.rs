@@ -0,0 +1,615 @@
+use crate::auth::Session;
+use crate::common::{Request, SetResponse};
+use crate::http;
+use crate::protocol::{self, Protocol, Reply};
+use crate::resp::{self, RespState};
+use crate::server::{
+    guard, refuse_request, respond, spawn_watch, Service, DEFAULT_SHUTDOWN_TIMEOUT, READ_BUF_SIZE,
+};
+use crate::shutdown::{Connection, Connections};
+use crate::tls::{AsyncStream, ServerTls};
+use crate::{
+    Accounts, Acl, AsyncKvsEngine, KvsEngine, KvsError, Result, ShutdownHandle, SizeLimits,
+};
+use log::{debug, error, warn};
+use serde_json::Deserializer;
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::runtime::Builder;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_rustls::TlsAcceptor;
+
+///requests of a connection running at once, beyond that it is not read
+const MAX_RUNNING: usize = 128;
+
+/// A server holding every connection as a task on an async runtime.
+///
+/// Idle connections cost no thread, so thousands of them can be held
+/// on a handful of worker threads. The engine itself still blocks,
+/// it runs on the runtime's blocking thread pool through `AsyncKvsEngine`.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: AsyncKvsEngine<E>,
+    worker_threads: usize,
+    limits: SizeLimits,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    resp_addr: Option<SocketAddr>,
+    resp: RespState,
+    http_addr: Option<SocketAddr>,
+    backup_dir: Option<Arc<PathBuf>>,
+    tls: Option<ServerTls>,
+    accounts: Option<Arc<Accounts>>,
+    acl: Option<Acl>,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    /// Creates a server running connections on `worker_threads` threads.
+    pub fn new(engine: E, worker_threads: usize) -> Self {
+        AsyncKvsServer {
+            engine: AsyncKvsEngine::new(engine),
+            worker_threads,
+            limits: SizeLimits::default(),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            resp_addr: None,
+            resp: RespState::default(),
+            http_addr: None,
+            backup_dir: None,
+            tls: None,
+            accounts: None,
+            acl: None,
+        }
+    }
+
+    /// Sets the largest keys, values and requests the server accepts.
+    pub fn with_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how long a shutdown waits for the requests being served, 5 seconds by default.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Also serves Redis clients on `addr`, speaking RESP, like `KvsServer::with_resp`.
+    pub fn with_resp(mut self, addr: SocketAddr) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// Also serves HTTP clients on `addr`, with JSON bodies, like `KvsServer::with_http`.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Lets HTTP clients back the store up into `dir`, like `KvsServer::with_backup_dir`.
+    pub fn with_backup_dir<D: Into<PathBuf>>(mut self, dir: D) -> Self {
+        self.backup_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// Serves every listener over TLS only, like `KvsServer::with_tls`.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Requires clients to authenticate, like `KvsServer::with_accounts`.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = Some(Arc::new(accounts));
+        self
+    }
+
+    /// Only serves the requests `acl` grants, like `KvsServer::with_acl`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Returns a handle stopping the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Starts a runtime and serves clients until the shutdown handle is used.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(self.worker_threads.max(1))
+            .enable_io()
+            .build()?;
+        let res = runtime.block_on(self.serve(addr));
+        //requests still running after the shutdown timeout are not waited for
+        runtime.shutdown_background();
+        res
+    }
+
+    /// Serves clients on the current runtime until the shutdown handle is used.
+    pub async fn serve<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let tls = match &self.tls {
+            Some(tls) => Some(TlsAcceptor::from(tls.config()?)),
+            None => None,
+        };
+        let listener = TcpListener::bind(addr).await?;
+        self.shutdown.listening_on(listener.local_addr()?);
+        let resp_listener = self.bind(self.resp_addr).await?;
+        let http_listener = self.bind(self.http_addr).await?;
+        let sweeper = match self.resp_addr {
+            Some(_) => Some(
+                self.resp
+                    .spawn_sweeper(self.engine.get_ref().clone(), self.shutdown.clone())?,
+            ),
+            None => None,
+        };
+        while !self.shutdown.is_requested() {
+            let (service, accepted) = tokio::select! {
+                accepted = listener.accept() => (Service::Kvs, accepted),
+                accepted = accept(resp_listener.as_ref()) => (Service::Resp, accepted),
+                accepted = accept(http_listener.as_ref()) => (Service::Http, accepted),
+            };
+            let tcp = match accepted {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    error!("Connection failed:{}", e);
+                    continue;
+                }
+            };
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let engine = self.engine.clone();
+            let limits = self.limits;
+            let resp = self.resp.clone();
+            let backup_dir = self.backup_dir.clone();
+            let connections = self.shutdown.connections().clone();
+            let tls = tls.clone();
+            let session = Session::new(self.accounts.clone(), self.acl.clone());
+            tokio::spawn(async move {
+                let served = match open(tcp, tls.as_ref(), &connections).await {
+                    Ok((stream, connection)) => match service {
+                        Service::Kvs => serve(engine, stream, connection, session, limits).await,
+                        Service::Resp => {
+                            serve_resp(engine, stream, connection, session, resp, limits).await
+                        }
+                        Service::Http => {
+                            serve_http(engine, stream, connection, session, limits, backup_dir)
+                                .await
+                        }
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = served {
+                    error!("Error on serving client:{}", e);
+                }
+            });
+        }
+        drop(listener);
+        drop(resp_listener);
+        drop(http_listener);
+
+        let shutdown = self.shutdown.clone();
+        let timeout = self.shutdown_timeout;
+        task::spawn_blocking(move || {
+            shutdown.drain(timeout);
+            sweeper.map(JoinHandle::join)
+        })
+        .await
+        .map_err(|e| KvsError::StringError(format!("shutdown task failed: {}", e)))?;
+        self.engine.call(|engine| engine.sync()).await
+    }
+
+    ///the listener of an optional service
+    async fn bind(&self, addr: Option<SocketAddr>) -> Result<Option<TcpListener>> {
+        match addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                self.shutdown.listening_on(listener.local_addr()?);
+                Ok(Some(listener))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+///accept on a listener that may not be there, never ready then
+async fn accept(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+///register a connection and run its TLS handshake if the server has TLS
+async fn open(
+    tcp: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    connections: &Connections,
+) -> Result<(AsyncStream, Connection)> {
+    //registered through a std handle of the socket, a shutdown stops reading from it
+    let tcp = tcp.into_std()?;
+    let connection = connections.add(&tcp)?;
+    let tcp = TcpStream::from_std(tcp)?;
+    let stream = match tls {
+        Some(acceptor) => AsyncStream::Tls(Box::new(acceptor.accept(tcp).await?)),
+        None => AsyncStream::Plain(tcp),
+    };
+    Ok((stream, connection))
+}
+
+///write out a response, flushed since a TLS session holds back what it encrypts
+async fn send(stream: &mut AsyncStream, buf: &[u8]) -> Result<()> {
+    stream.write_all(buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn serve<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    mut stream: AsyncStream,
+    connection: Connection,
+    mut session: Session,
+    limits: SizeLimits,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    //bytes received but not parsed yet, a request may span several reads
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUF_SIZE];
+    let mut scanner = JsonScanner::default();
+    let mut protocol = None;
+    let mut running = Running::new();
+    loop {
+        if protocol.is_none() {
+            if let Some((detected, len)) = protocol::detect(&buf)? {
+                buf.drain(..len);
+                if detected == Protocol::Binary {
+                    send(&mut stream, &protocol::hello()).await?;
+                }
+                protocol = Some(detected);
+            }
+        }
+        let parsed = match protocol {
+            Some(Protocol::Json) => parse_requests(&buf, &mut scanner, limits)?,
+            Some(Protocol::Binary) => parse_frames(&buf, limits)?,
+            None => Parsed::default(),
+        };
+        buf.drain(..parsed.len);
+        for (reply, req) in parsed.reqs {
+            let req = match req {
+                Ok(req) => req,
+                Err(e) => {
+                    warn!("Malformed request from {}: {}", peer_addr, e);
+                    send(&mut stream, &reply.encode(&SetResponse::Err(e.into()))?).await?;
+                    continue;
+                }
+            };
+            debug!("Receive request from {}: {:?}", peer_addr, req);
+            if let Request::Auth { .. } = req {
+                //checking a password takes a while, it runs off the connection tasks
+                let (authenticated, resp) = task::spawn_blocking(move || {
+                    let resp = guard(&mut session, &req, peer_addr, reply);
+                    (session, resp)
+                })
+                .await
+                .map_err(|e| KvsError::StringError(format!("auth task failed: {}", e)))?;
+                session = authenticated;
+                if let Some(resp) = resp? {
+                    send(&mut stream, &resp).await?;
+                }
+                continue;
+            }
+            if let Some(resp) = guard(&mut session, &req, peer_addr, reply)? {
+                send(&mut stream, &resp).await?;
+                continue;
+            }
+            if let Request::Watch { prefix } = req {
+                //a subscription streams from a blocking watcher,
+                //it gets its own thread like in `KvsServer`
+                running.finish(&mut stream).await?;
+                let stream = stream.into_std()?;
+                return spawn_watch(engine.get_ref().clone(), prefix, stream, connection, reply);
+            }
+            let resp = engine.call(move |engine| respond(engine, req, &limits, peer_addr, reply));
+            match reply {
+                //JSON responses carry no id, they are answered in order
+                Reply::Json => send(&mut stream, &resp.await?).await?,
+                Reply::Binary { .. } => running.spawn(resp),
+            }
+        }
+        if let Some(reply) = parsed.too_large {
+            running.finish(&mut stream).await?;
+            let stream = stream.into_std()?;
+            return task::spawn_blocking(move || refuse_request(&stream, limits, reply))
+                .await
+                .map_err(|e| KvsError::StringError(format!("connection task failed: {}", e)))?;
+        }
+
+        tokio::select! {
+            len = stream.read(&mut chunk), if running.count < MAX_RUNNING => {
+                let len = len?;
+                if len == 0 {
+                    running.finish(&mut stream).await?;
+                    debug!("Client {} disconnected", peer_addr);
+                    return Ok(());
+                }
+                buf.extend_from_slice(&chunk[..len]);
+            }
+            resp = running.next() => send(&mut stream, &resp?).await?,
+        }
+    }
+}
+
+async fn serve_resp<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    mut stream: AsyncStream,
+    _connection: Connection,
+    mut session: Session,
+    state: RespState,
+    limits: SizeLimits,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUF_SIZE];
+    loop {
+        let parsed = resp::parse_commands(&buf, &limits);
+        buf.drain(..parsed.len);
+        let mut out = Vec::new();
+        for args in parsed.commands {
+            debug!(
+                "Receive RESP command from {}: {:?}",
+                peer_addr,
+                args.first()
+                    .map(|name| String::from_utf8_lossy(name).into_owned())
+            );
+            let quit = resp::is_quit(&args);
+            let state = state.clone();
+            //the session moves to the blocking pool and back, `AUTH` may hash a password
+            let (executed, authenticated) = engine
+                .call(move |engine| {
+                    let executed = resp::execute(engine, &state, &mut session, &limits, args);
+                    Ok((executed, session))
+                })
+                .await?;
+            session = authenticated;
+            out.extend(executed);
+            if quit {
+                send(&mut stream, &out).await?;
+                return Ok(());
+            }
+        }
+        if let Some(e) = parsed.error {
+            out.extend(resp::protocol_error(&e));
+            send(&mut stream, &out).await?;
+            return Err(e);
+        }
+        send(&mut stream, &out).await?;
+
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            debug!("Client {} disconnected", peer_addr);
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+async fn serve_http<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    mut stream: AsyncStream,
+    _connection: Connection,
+    session: Session,
+    limits: SizeLimits,
+    backup_dir: Option<Arc<PathBuf>>,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUF_SIZE];
+    loop {
+        let parsed = http::parse_requests(&buf, &limits);
+        buf.drain(..parsed.len);
+        let mut out = Vec::new();
+        for req in parsed.requests {
+            debug!(
+                "Receive HTTP request from {}: {} {}",
+                peer_addr, req.method, req.target
+            );
+            let close = req.close;
+            let session = session.clone();
+            let backup_dir = backup_dir.clone();
+            out.extend(
+                engine
+                    .call(move |engine| {
+                        let backup_dir = backup_dir.as_deref().map(PathBuf::as_path);
+                        Ok(http::respond(engine, &limits, &session, backup_dir, &req))
+                    })
+                    .await?,
+            );
+            if close {
+                send(&mut stream, &out).await?;
+                return Ok(());
+            }
+        }
+        if let Some(e) = parsed.error {
+            out.extend(http::protocol_error(&e));
+            send(&mut stream, &out).await?;
+            return Err(e);
+        }
+        send(&mut stream, &out).await?;
+
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            debug!("Client {} disconnected", peer_addr);
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+///The binary requests of a connection running on the engine,
+///their responses are written as they finish
+struct Running {
+    count: usize,
+    done: mpsc::UnboundedSender<Result<Vec<u8>>>,
+    finished: mpsc::UnboundedReceiver<Result<Vec<u8>>>,
+}
+
+impl Running {
+    fn new() -> Running {
+        let (done, finished) = mpsc::unbounded_channel();
+        Running {
+            count: 0,
+            done,
+            finished,
+        }
+    }
+
+    fn spawn<F>(&mut self, resp: F)
+    where
+        F: Future<Output = Result<Vec<u8>>> + Send + 'static,
+    {
+        let done = self.done.clone();
+        tokio::spawn(async move {
+            //the connection may be gone
+            let _ = done.send(resp.await);
+        });
+        self.count += 1;
+    }
+
+    ///the response of the next request to finish, never ready while none runs
+    async fn next(&mut self) -> Result<Vec<u8>> {
+        let resp = self.finished.recv().await;
+        self.count -= 1;
+        resp.expect("the sender is kept")
+    }
+
+    ///write the responses of every request still running
+    async fn finish(&mut self, stream: &mut AsyncStream) -> Result<()> {
+        while self.count > 0 {
+            let resp = self.next().await?;
+            send(stream, &resp).await?;
+        }
+        Ok(())
+    }
+}
+
+///The complete requests at the start of a connection's buffer
+#[derive(Default)]
+struct Parsed {
+    ///each with how to answer it, binary requests that failed to decode are answered too
+    reqs: Vec<(Reply, Result<Request>)>,
+    ///how many bytes they take
+    len: usize,
+    ///the request after them is over the size limit
+    too_large: Option<Reply>,
+}
+
+///Finds where the JSON values at the start of a connection's buffer end
+///without parsing them, so a request arriving in many reads is parsed once.
+#[derive(Default)]
+struct JsonScanner {
+    ///how much of the buffer was scanned
+    pos: usize,
+    ///objects and arrays open at `pos`
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonScanner {
+    ///Scans what arrived since the last call, returning where the last complete value ends.
+    ///A value that is neither an object, an array nor a string is left to the parser.
+    fn scan(&mut self, buf: &[u8]) -> usize {
+        let mut end = 0;
+        while self.pos < buf.len() {
+            let byte = buf[self.pos];
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        end = self.pos + 1;
+                    }
+                }
+            } else {
+                match byte {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' if self.depth > 0 => {
+                        self.depth -= 1;
+                        if self.depth == 0 {
+                            end = self.pos + 1;
+                        }
+                    }
+                    _ if self.depth > 0 || byte.is_ascii_whitespace() => {}
+                    //scanned again next time, the parser reports it
+                    _ => return buf.len(),
+                }
+            }
+            self.pos += 1;
+        }
+        end
+    }
+
+    ///the first `len` bytes left the buffer
+    fn consume(&mut self, len: usize) {
+        self.pos = self.pos.saturating_sub(len);
+    }
+}
+
+fn parse_requests(buf: &[u8], scanner: &mut JsonScanner, limits: SizeLimits) -> Result<Parsed> {
+    let end = scanner.scan(buf);
+    let mut stream = Deserializer::from_slice(&buf[..end]).into_iter::<Request>();
+    let mut reqs = Vec::new();
+    loop {
+        match stream.next() {
+            Some(Ok(req)) => reqs.push((Reply::Json, Ok(req))),
+            //the rest has not arrived yet
+            Some(Err(ref e)) if e.is_eof() => break,
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+    let len = stream.byte_offset();
+    scanner.consume(len);
+    //what is left is the start of a single request
+    let too_large = if (buf.len() - len) as u64 > limits.max_request_size {
+        Some(Reply::Json)
+    } else {
+        None
+    };
+    Ok(Parsed {
+        reqs,
+        len,
+        too_large,
+    })
+}
+
+fn parse_frames(buf: &[u8], limits: SizeLimits) -> Result<Parsed> {
+    let mut parsed = Parsed::default();
+    while let Some(header) = protocol::parse_header(&buf[parsed.len..])? {
+        if header.frame_len() > limits.max_request_size {
+            parsed.too_large = Some(header.reply());
+            break;
+        }
+        let payload = match protocol::parse_payload(&buf[parsed.len..], &header) {
+            Some(payload) => payload,
+            None => break,
+        };
+        let req = protocol::decode_request(header.opcode, payload);
+        parsed.reqs.push((header.reply(), req));
+        parsed.len += header.frame_len() as usize;
+    }
+    Ok(parsed)
+}
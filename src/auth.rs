@@ -0,0 +1,284 @@
+use crate::{Acl, KvsError, Permission, Result};
+use ring::digest::{digest, SHA256};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Arc;
+
+///PBKDF2 iterations of new password hashes
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// What a client proves its identity with.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A static token, standing for the identity it is configured for
+    Token(String),
+    /// The password of a user
+    Password {
+        /// the identity
+        user: String,
+        /// its password
+        password: String,
+    },
+}
+
+//requests are logged, secrets are not
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => f.write_str("Token(..)"),
+            Credentials::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// The credentials a server accepts, each standing for an identity.
+///
+/// An accounts file has a credential per line, `NAME password HASH` or `NAME token HASH`,
+/// with the hashes of `hash_password` and `hash_token`. `kvs-admin credential` prints
+/// such lines. An identity may have several credentials. Blank lines and lines
+/// starting with `#` are ignored.
+pub struct Accounts {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    name: String,
+    secret: Secret,
+}
+
+enum Secret {
+    Password {
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    ///the SHA-256 of the token
+    Token(Vec<u8>),
+}
+
+impl Accounts {
+    /// Reads the accounts of a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Accounts> {
+        let path = path.as_ref();
+        let mut entries = Vec::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            entries.push(parse_entry(line).ok_or_else(|| {
+                KvsError::StringError(format!(
+                    "{}:{}: expected `NAME password HASH` or `NAME token HASH`",
+                    path.display(),
+                    i + 1
+                ))
+            })?);
+        }
+        Ok(Accounts { entries })
+    }
+
+    ///the identity the credentials stand for
+    pub(crate) fn verify(&self, credentials: &Credentials) -> Result<String> {
+        let found = match credentials {
+            Credentials::Token(token) => {
+                let hashed = digest(&SHA256, token.as_bytes());
+                self.entries.iter().find(|entry| match &entry.secret {
+                    Secret::Token(hash) => hash[..] == *hashed.as_ref(),
+                    Secret::Password { .. } => false,
+                })
+            }
+            Credentials::Password { user, password } => {
+                self.entries.iter().find(|entry| match &entry.secret {
+                    Secret::Password {
+                        iterations,
+                        salt,
+                        hash,
+                    } if entry.name == *user => pbkdf2::verify(
+                        pbkdf2::PBKDF2_HMAC_SHA256,
+                        *iterations,
+                        salt,
+                        password.as_bytes(),
+                        hash,
+                    )
+                    .is_ok(),
+                    _ => false,
+                })
+            }
+        };
+        found
+            .map(|entry| entry.name.clone())
+            .ok_or(KvsError::InvalidCredentials)
+    }
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (name, kind, hash) = match fields[..] {
+        [name, kind, hash] => (name, kind, hash),
+        _ => return None,
+    };
+    let parts: Vec<&str> = hash.split('$').collect();
+    let secret = match (kind, &parts[..]) {
+        ("password", ["pbkdf2-sha256", iterations, salt, hash]) => Secret::Password {
+            iterations: iterations.parse().ok()?,
+            salt: from_hex(salt)?,
+            hash: from_hex(hash).filter(|hash| hash.len() == HASH_LEN)?,
+        },
+        ("token", ["sha256", hash]) => {
+            Secret::Token(from_hex(hash).filter(|hash| hash.len() == HASH_LEN)?)
+        }
+        _ => return None,
+    };
+    Some(Entry {
+        name: name.to_owned(),
+        secret,
+    })
+}
+
+/// Hashes a password for an accounts file, with PBKDF2-HMAC-SHA256 and a random salt.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| KvsError::StringError("no randomness for a salt".to_owned()))?;
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "pbkdf2-sha256${}${}${}",
+        iterations,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Hashes a token for an accounts file, with SHA-256.
+///
+/// Tokens are compared by their hash alone, so they should be long and random.
+pub fn hash_token(token: &str) -> String {
+    format!(
+        "sha256${}",
+        to_hex(digest(&SHA256, token.as_bytes()).as_ref())
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+///What a connection is authenticated as, and what it may do.
+///Without accounts the server is open, and without an ACL
+///every connection may do anything.
+#[derive(Clone)]
+pub(crate) struct Session {
+    accounts: Option<Arc<Accounts>>,
+    acl: Option<Acl>,
+    identity: Option<String>,
+}
+
+impl Session {
+    pub fn new(accounts: Option<Arc<Accounts>>, acl: Option<Acl>) -> Session {
+        Session {
+            accounts,
+            acl,
+            identity: None,
+        }
+    }
+
+    ///a failed attempt keeps what the connection was authenticated as
+    pub fn authenticate(&mut self, credentials: &Credentials) -> Result<String> {
+        let accounts = self.accounts.as_ref().ok_or_else(|| {
+            KvsError::StringError("the server has no accounts to authenticate with".to_owned())
+        })?;
+        let identity = accounts.verify(credentials)?;
+        self.identity = Some(identity.clone());
+        Ok(identity)
+    }
+
+    ///fails unless the connection may make requests
+    pub fn check(&self) -> Result<()> {
+        match (&self.accounts, &self.identity) {
+            (Some(_), None) => Err(KvsError::Unauthenticated),
+            _ => Ok(()),
+        }
+    }
+
+    ///fails unless the ACL grants `permission` on `key`
+    pub fn permit(&self, permission: Permission, key: &str) -> Result<()> {
+        self.permit_on(permission, key, || format!("{:?}", key))
+    }
+
+    ///fails unless the ACL grants `permission` on every key starting with `prefix`
+    pub fn permit_prefix(&self, permission: Permission, prefix: &str) -> Result<()> {
+        self.permit_on(permission, prefix, || {
+            format!("keys starting with {:?}", prefix)
+        })
+    }
+
+    ///fails unless the ACL grants `admin`, on every key
+    pub fn permit_admin(&self) -> Result<()> {
+        self.permit_on(Permission::Admin, "", || "the store".to_owned())
+    }
+
+    ///fails unless the connection is authenticated and the ACL grants `admin`,
+    ///a server without accounts has no administrators
+    pub fn permit_account_admin(&self) -> Result<()> {
+        match self.identity {
+            Some(_) => self.permit_admin(),
+            None => Err(self.denied(Permission::Admin, "the store".to_owned())),
+        }
+    }
+
+    fn permit_on<F>(&self, permission: Permission, prefix: &str, scope: F) -> Result<()>
+    where
+        F: FnOnce() -> String,
+    {
+        match &self.acl {
+            Some(acl) if !acl.allows(self.identity.as_deref(), permission, prefix) => {
+                Err(self.denied(permission, scope()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn denied(&self, permission: Permission, scope: String) -> KvsError {
+        KvsError::PermissionDenied {
+            identity: self
+                .identity
+                .clone()
+                .unwrap_or_else(|| "anonymous".to_owned()),
+            permission,
+            scope,
+        }
+    }
+}
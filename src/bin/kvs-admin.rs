@@ -0,0 +1,76 @@
+#[macro_use]
+extern crate clap;
+use clap::App;
+use kvs::{hash_password, hash_token, migrate, Result, StoreMeta};
+use std::env::current_dir;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() -> Result<()> {
+    let yaml = load_yaml!("kvs-admin.yml");
+    let m = App::from_yaml(yaml).get_matches();
+
+    match m.subcommand() {
+        ("upgrade", Some(matches)) => {
+            let dir = dir_arg(matches.value_of("DIR"))?;
+            match StoreMeta::upgrade(&dir) {
+                Ok((from, meta)) if from == meta.format_version => println!(
+                    "{} store is already at format version {}",
+                    meta.engine, meta.format_version
+                ),
+                Ok((from, meta)) => println!(
+                    "upgraded {} store from format version {} to {}",
+                    meta.engine, from, meta.format_version
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        ("migrate", Some(matches)) => {
+            let dir = dir_arg(matches.value_of("DIR"))?;
+            let from = matches.value_of("from").unwrap();
+            let to = matches.value_of("to").unwrap();
+            match migrate(&dir, from, to) {
+                Ok(migration) => println!(
+                    "migrated {} keys from {} to {}, the old data is in {}",
+                    migration.keys,
+                    from,
+                    to,
+                    migration.backup.display()
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        ("credential", Some(matches)) => {
+            let name = matches.value_of("NAME").unwrap();
+            let kind = matches.value_of("KIND").unwrap();
+            let mut secret = String::new();
+            io::stdin().lock().read_line(&mut secret)?;
+            let secret = secret.trim_end_matches(['\r', '\n']);
+            if secret.is_empty() || name.contains(char::is_whitespace) {
+                eprintln!("expected a name without spaces and a secret on stdin");
+                exit(1);
+            }
+            let hash = match kind {
+                "password" => hash_password(secret)?,
+                _ => hash_token(secret),
+            };
+            println!("{} {} {}", name, kind, hash);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn dir_arg(dir: Option<&str>) -> Result<PathBuf> {
+    match dir {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(current_dir()?),
+    }
+}
@@ -2,9 +2,11 @@
 extern crate clap;
 use log::warn;
 use std::net::SocketAddr;
+use std::process::exit;
+use std::time::UNIX_EPOCH;
 
-use clap::App;
-use kvs::{KvsClient, Result};
+use clap::{App, ArgMatches};
+use kvs::{ClientTls, Credentials, Event, KvsClient, Protocol, Result};
 
 fn main() -> Result<()> {
     let yaml = load_yaml!("kvs-client.yml");
@@ -15,15 +17,23 @@ fn main() -> Result<()> {
             warn!("dsadas");
             let key = matches.value_of("KEY").unwrap().to_string();
             let value = matches.value_of("VALUE").unwrap().to_string();
-            let addr: SocketAddr = matches.value_of("addr").unwrap().parse().unwrap();
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(matches)?;
             client.set(key, value)?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap().to_string();
-            let addr: SocketAddr = matches.value_of("addr").unwrap().parse().unwrap();
-            let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
+            let mut client = connect(matches)?;
+            let value = match matches.value_of("seq") {
+                Some(seq) => {
+                    let seq = seq.parse().unwrap_or_else(|_| {
+                        eprintln!("invalid sequence number {}", seq);
+                        exit(1)
+                    });
+                    client.get_at(key, seq)?
+                }
+                None => client.get(key)?,
+            };
+            if let Some(value) = value {
                 println!("{}", value);
             } else {
                 println!("Key not found");
@@ -31,11 +41,98 @@ fn main() -> Result<()> {
         }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap().to_string();
-            let addr: SocketAddr = matches.value_of("addr").unwrap().parse().unwrap();
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(matches)?;
             client.remove(key)?;
         }
+        ("stats", Some(matches)) => {
+            let mut client = connect(matches)?;
+            let stats = client.stats()?;
+            println!("keys: {}", stats.key_count);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("uncompacted bytes: {}", stats.uncompacted);
+            println!("generations: {}", stats.generations.len());
+            for gen in &stats.generations {
+                println!("  {}.log: {} bytes", gen.gen, gen.size);
+            }
+            println!("blob files: {}", stats.blob_files.len());
+            for blob in &stats.blob_files {
+                println!("  {}.blob: {} bytes", blob.gen, blob.size);
+            }
+            println!("blob garbage bytes: {}", stats.blob_garbage);
+            println!(
+                "compactions: {} ({:?} total)",
+                stats.compaction_count, stats.compaction_duration
+            );
+            match stats
+                .last_compaction
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            {
+                Some(since_epoch) => println!("last compaction: {}", since_epoch.as_secs()),
+                None => println!("last compaction: never"),
+            }
+        }
+        ("history", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap().to_string();
+            let mut client = connect(matches)?;
+            for version in client.history(key)? {
+                let time = version
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs());
+                match version.value {
+                    Some(value) => println!("{} {} set {}", version.seq, time, value),
+                    None => println!("{} {} rm", version.seq, time),
+                }
+            }
+        }
+        ("watch", Some(matches)) => {
+            let prefix = matches.value_of("PREFIX").unwrap_or("").to_string();
+            let client = connect(matches)?;
+            for event in client.watch(prefix)? {
+                match event? {
+                    Event::Set { key, value } => println!("set {} {}", key, value),
+                    Event::Remove { key } => println!("rm {}", key),
+                }
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+///connect to `--addr`, then authenticate with `--token` or `--user`
+fn connect(matches: &ArgMatches) -> Result<KvsClient> {
+    let mut client = connect_tls(matches)?;
+    let credentials = match (matches.value_of("token"), matches.value_of("user")) {
+        (Some(token), _) => Credentials::Token(token.to_owned()),
+        (None, Some(user)) => Credentials::Password {
+            user: user.to_owned(),
+            password: matches.value_of("password").unwrap().to_owned(),
+        },
+        (None, None) => return Ok(client),
+    };
+    client.authenticate(credentials)?;
+    Ok(client)
+}
+
+///connect to `--addr` with `--protocol`, over TLS if `--tls-ca` is given
+fn connect_tls(matches: &ArgMatches) -> Result<KvsClient> {
+    let addr: SocketAddr = matches.value_of("addr").unwrap().parse().unwrap();
+    let protocol = match matches.value_of("protocol") {
+        Some("binary") => Protocol::Binary,
+        _ => Protocol::Json,
+    };
+    let ca = match matches.value_of("tls-ca") {
+        Some(ca) => ca,
+        None => return KvsClient::connect_with(addr, protocol),
+    };
+    let server_name = match matches.value_of("tls-server-name") {
+        Some(name) => name.to_owned(),
+        None => addr.ip().to_string(),
+    };
+    let mut tls = ClientTls::from_ca_file(ca, &server_name)?;
+    if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        tls = tls.with_client_cert(cert, key)?;
+    }
+    KvsClient::connect_tls_with(addr, &tls, protocol)
+}
@@ -1,21 +1,41 @@
 #[macro_use]
 extern crate clap;
-use clap::App;
+use clap::{App, ArgMatches};
 use kvs::thread_pool::*;
 use kvs::*;
 use log::LevelFilter;
-use log::{error, info, warn};
+use log::{error, info};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::env::current_dir;
-use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const SLED_ARGS: [&str; 5] = [
+    "sled-flush",
+    "sled-cache-bytes",
+    "sled-compression",
+    "sled-mode",
+    "sled-tree",
+];
 arg_enum! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         Kvs,
-        Sled
+        Sled,
+        Lsm,
+        Memory
+    }
+}
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Runtime {
+        Threads,
+        Async
     }
 }
 fn main() -> Result<()> {
@@ -27,47 +47,308 @@ fn main() -> Result<()> {
         Some(addr) => addr,
         _ => DEFAULT_LISTENING_ADDRESS,
     };
-    let opt_engine = match m.value_of("engine") {
-        Some(str) if str == "sled" => Engine::Sled,
+    let engine = match m.value_of("engine") {
+        Some("sled") => Engine::Sled,
+        Some("lsm") => Engine::Lsm,
+        Some("memory") => Engine::Memory,
         _ => Engine::Kvs,
     };
-
-    let engine = match current_engine() {
-        Ok(Some(engine)) if engine != opt_engine => {
-            error!("Wrong engine!");
+    let runtime = match m.value_of("runtime") {
+        Some("async") => Runtime::Async,
+        Some("threads") | None => Runtime::Threads,
+        Some(runtime) => {
+            error!("unknown runtime {}", runtime);
             exit(1);
         }
-        _ => opt_engine,
     };
-    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let retention = match (m.value_of("retain-versions"), m.value_of("retain-for")) {
+        (Some(n), _) => Retention::Versions(parse_or_exit(n, "retain-versions")),
+        (_, Some(secs)) => {
+            Retention::Window(Duration::from_secs(parse_or_exit(secs, "retain-for")))
+        }
+        _ => Retention::Latest,
+    };
+    if retention != Retention::Latest && engine != Engine::Kvs {
+        error!("keeping older versions needs the kvs engine");
+        exit(1);
+    }
+    let memory_capacity = m
+        .value_of("memory-capacity")
+        .map(|bytes| parse_or_exit::<u64>(bytes, "memory-capacity"));
+    if memory_capacity.is_some() && engine != Engine::Memory {
+        error!("--memory-capacity needs the memory engine");
+        exit(1);
+    }
+    let mut limits = SizeLimits::default();
+    if let Some(bytes) = m.value_of("max-key-size") {
+        limits.max_key_size = parse_or_exit(bytes, "max-key-size");
+    }
+    if let Some(bytes) = m.value_of("max-value-size") {
+        limits.max_value_size = parse_or_exit(bytes, "max-value-size");
+    }
+    if let Some(bytes) = m.value_of("max-request-size") {
+        limits.max_request_size = parse_or_exit(bytes, "max-request-size");
+    }
+    let shutdown_timeout = match m.value_of("shutdown-timeout") {
+        Some(secs) => Duration::from_secs(parse_or_exit(secs, "shutdown-timeout")),
+        None => Duration::from_secs(5),
+    };
+    let resp_addr = m
+        .value_of("resp-addr")
+        .map(|addr| parse_or_exit::<SocketAddr>(addr, "resp-addr"));
+    let http_addr = m
+        .value_of("http-addr")
+        .map(|addr| parse_or_exit::<SocketAddr>(addr, "http-addr"));
+    let backup_dir = m.value_of("backup-dir").map(PathBuf::from);
+    let tls = server_tls(&m);
+    let accounts = m.value_of("accounts").map(|path| {
+        Accounts::from_file(path).unwrap_or_else(|e| {
+            error!("invalid accounts file {}: {}", path, e);
+            exit(1)
+        })
+    });
+    let acl = m.value_of("acl").map(|path| {
+        Acl::from_file(path).unwrap_or_else(|e| {
+            error!("invalid ACL file {}: {}", path, e);
+            exit(1)
+        })
+    });
+    let sled_config = sled_config(&m);
+    if engine != Engine::Sled && SLED_ARGS.iter().any(|arg| m.is_present(arg)) {
+        error!("the --sled-* options need the sled engine");
+        exit(1);
+    }
+
+    //the memory engine keeps nothing on disk, the directory is not its own
+    if engine != Engine::Memory {
+        if let Err(e) = StoreMeta::check(&current_dir()?, &engine.to_string().to_lowercase()) {
+            error!("{}", e);
+            exit(1);
+        }
+    }
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
+    info!("Runtime: {}", runtime);
     info!("Listening on {}", addr);
+    if let Some(resp_addr) = resp_addr {
+        info!("Listening for RESP on {}", resp_addr);
+    }
+    if let Some(http_addr) = http_addr {
+        info!("Listening for HTTP on {}", http_addr);
+    }
+    if let Some(backup_dir) = &backup_dir {
+        info!("Backups go to {}", backup_dir.display());
+    }
+    if tls.is_some() {
+        info!("Serving over TLS");
+    }
+    if accounts.is_some() {
+        info!("Clients must authenticate");
+    }
+    if acl.is_some() {
+        info!("Requests are checked against the ACL, SIGHUP reloads it");
+    }
     info!("nmsl");
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
     let addr: SocketAddr = addr.parse().unwrap();
+    let server = Server {
+        runtime,
+        addr,
+        resp_addr,
+        http_addr,
+        backup_dir,
+        limits,
+        shutdown_timeout,
+        tls,
+        accounts,
+        acl,
+    };
 
     match engine {
-        Engine::Kvs => run_with(KvStore::open(current_dir()?)?, pool, addr),
-        Engine::Sled => run_with(SledKvsEngine::new(sled::open(current_dir()?)?), pool, addr),
+        Engine::Kvs => {
+            let config = KvStoreConfig {
+                retention,
+                max_key_size: Some(limits.max_key_size),
+                max_value_size: Some(limits.max_value_size),
+                ..KvStoreConfig::default()
+            };
+            server.run(KvStore::open_with_config(current_dir()?, config)?)
+        }
+        Engine::Sled => server.run(SledKvsEngine::open_with_config(
+            current_dir()?,
+            SledConfig {
+                max_key_size: Some(limits.max_key_size),
+                max_value_size: Some(limits.max_value_size),
+                ..sled_config
+            },
+        )?),
+        Engine::Lsm => {
+            let config = LsmConfig {
+                max_key_size: Some(limits.max_key_size),
+                max_value_size: Some(limits.max_value_size),
+                ..LsmConfig::default()
+            };
+            server.run(LsmKvsEngine::open_with_config(current_dir()?, config)?)
+        }
+        Engine::Memory => {
+            let engine = match memory_capacity {
+                Some(capacity) => MemoryKvsEngine::with_capacity(capacity),
+                None => MemoryKvsEngine::new(),
+            };
+            server.run(
+                engine.with_size_limits(Some(limits.max_key_size), Some(limits.max_value_size)),
+            )
+        }
     }
 }
-fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+fn sled_config(m: &ArgMatches) -> SledConfig {
+    let mut config = SledConfig::default();
+    match m.value_of("sled-flush") {
+        Some("every-write") | None => {}
+        Some("manual") => config.flush = FlushPolicy::Manual,
+        Some(ms) => {
+            config.flush =
+                FlushPolicy::Periodic(Duration::from_millis(parse_or_exit(ms, "sled-flush")))
+        }
+    }
+    if let Some(bytes) = m.value_of("sled-cache-bytes") {
+        config.cache_capacity = Some(parse_or_exit(bytes, "sled-cache-bytes"));
+    }
+    if let Some(level) = m.value_of("sled-compression") {
+        config.compression = Some(parse_or_exit(level, "sled-compression"));
+    }
+    match m.value_of("sled-mode") {
+        Some("low-space") | None => {}
+        Some("high-throughput") => config.mode = sled::Mode::HighThroughput,
+        Some(mode) => {
+            error!("invalid value {} for --sled-mode", mode);
+            exit(1);
+        }
+    }
+    config.tree = m.value_of("sled-tree").map(str::to_owned);
+    config
 }
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
+fn server_tls(m: &ArgMatches) -> Option<ServerTls> {
+    let (cert, key) = match (m.value_of("tls-cert"), m.value_of("tls-key")) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return None,
+    };
+    let tls =
+        ServerTls::from_pem_files(cert, key).and_then(|tls| match m.value_of("tls-client-ca") {
+            Some(ca) => tls.with_client_ca(ca),
+            None => Ok(tls),
+        });
+    match tls {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            error!("invalid TLS settings: {}", e);
+            exit(1)
+        }
     }
-
-    match fs::read_to_string(engine) {
-        Ok(str) if str.as_str() == "Kvs" => Ok(Some(Engine::Kvs)),
-        Ok(str) if str.as_str() == "Sled" => Ok(Some(Engine::Sled)),
-        _ => {
-            warn!("the content of engine file is invalid");
-            Ok(None)
+}
+fn parse_or_exit<T: std::str::FromStr>(value: &str, arg: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        error!("invalid value {} for --{}", value, arg);
+        exit(1)
+    })
+}
+struct Server {
+    runtime: Runtime,
+    addr: SocketAddr,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    backup_dir: Option<PathBuf>,
+    limits: SizeLimits,
+    shutdown_timeout: Duration,
+    tls: Option<ServerTls>,
+    accounts: Option<Accounts>,
+    acl: Option<Acl>,
+}
+impl Server {
+    fn run<E: KvsEngine>(self, engine: E) -> Result<()> {
+        match self.runtime {
+            Runtime::Threads => {
+                let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+                let mut server = KvsServer::new(engine, pool)
+                    .with_limits(self.limits)
+                    .with_shutdown_timeout(self.shutdown_timeout);
+                if let Some(addr) = self.resp_addr {
+                    server = server.with_resp(addr);
+                }
+                if let Some(addr) = self.http_addr {
+                    server = server.with_http(addr);
+                }
+                if let Some(dir) = self.backup_dir {
+                    server = server.with_backup_dir(dir);
+                }
+                if let Some(tls) = self.tls {
+                    server = server.with_tls(tls);
+                }
+                if let Some(accounts) = self.accounts {
+                    server = server.with_accounts(accounts);
+                }
+                if let Some(acl) = self.acl {
+                    reload_on_sighup(acl.clone())?;
+                    server = server.with_acl(acl);
+                }
+                stop_on_signals(server.shutdown_handle())?;
+                server.run(self.addr)?;
+            }
+            Runtime::Async => {
+                let mut server = AsyncKvsServer::new(engine, num_cpus::get())
+                    .with_limits(self.limits)
+                    .with_shutdown_timeout(self.shutdown_timeout);
+                if let Some(addr) = self.resp_addr {
+                    server = server.with_resp(addr);
+                }
+                if let Some(addr) = self.http_addr {
+                    server = server.with_http(addr);
+                }
+                if let Some(dir) = self.backup_dir {
+                    server = server.with_backup_dir(dir);
+                }
+                if let Some(tls) = self.tls {
+                    server = server.with_tls(tls);
+                }
+                if let Some(accounts) = self.accounts {
+                    server = server.with_accounts(accounts);
+                }
+                if let Some(acl) = self.acl {
+                    reload_on_sighup(acl.clone())?;
+                    server = server.with_acl(acl);
+                }
+                stop_on_signals(server.shutdown_handle())?;
+                server.run(self.addr)?;
+            }
         }
+        info!("kvs-server stopped");
+        Ok(())
     }
 }
+///shut down gracefully on SIGINT or SIGTERM, and right away on a second one
+fn stop_on_signals(handle: ShutdownHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if handle.is_requested() {
+                error!("Received signal {} again, exiting", signal);
+                exit(1);
+            }
+            info!("Received signal {}, shutting down", signal);
+            handle.shutdown();
+        }
+    });
+    Ok(())
+}
+///read the ACL file again on SIGHUP, keeping the rules in use when it is invalid
+fn reload_on_sighup(acl: Acl) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match acl.reload() {
+                Ok(()) => info!("Reloaded the ACL"),
+                Err(e) => error!("Cannot reload the ACL, keeping the rules in use: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
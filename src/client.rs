@@ -1,54 +1,344 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::Write;
+use crate::common::{
+    AuthResponse, GetManyResponse, GetResponse, HistoryResponse, RemoveManyResponse,
+    RemoveResponse, Request, Response, SetResponse, StatsResponse, WatchResponse,
+};
+use crate::protocol::{self, Protocol};
+use crate::tls::{ClientTls, Stream};
+use crate::{Credentials, EngineStats, Event, KvsError, Result, Version};
+use serde_json::de::Deserializer;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::iter;
+use std::marker::PhantomData;
 use std::{
     io::{BufReader, BufWriter},
     net::{TcpStream, ToSocketAddrs},
 };
 
+///requests a pipeline sends before it reads responses, so that neither side
+///blocks writing to the other while nobody reads
+const MAX_UNREAD: usize = 1024;
+
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    protocol: Protocol,
+    ///the id of the next binary request
+    next_id: u64,
 }
 
 impl KvsClient {
+    /// Connects to a server speaking the JSON protocol, which every server understands.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+        KvsClient::connect_with(addr, Protocol::Json)
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(e) => Err(KvsError::StringError(e)),
+    /// Connects to a server speaking the given protocol.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, protocol: Protocol) -> Result<Self> {
+        KvsClient::open(Stream::plain(TcpStream::connect(addr)?), protocol)
+    }
+
+    /// Connects over TLS to a server speaking the JSON protocol, like `connect`.
+    ///
+    /// The server's certificate is verified against the CAs and the name of `tls`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, tls: &ClientTls) -> Result<Self> {
+        KvsClient::connect_tls_with(addr, tls, Protocol::Json)
+    }
+
+    /// Connects over TLS to a server speaking the given protocol.
+    pub fn connect_tls_with<A: ToSocketAddrs>(
+        addr: A,
+        tls: &ClientTls,
+        protocol: Protocol,
+    ) -> Result<Self> {
+        let stream = Stream::connect(TcpStream::connect(addr)?, tls)?;
+        KvsClient::open(stream, protocol)
+    }
+
+    fn open(stream: Stream, protocol: Protocol) -> Result<Self> {
+        let mut client = KvsClient {
+            reader: BufReader::new(stream.clone()),
+            writer: BufWriter::new(stream),
+            protocol,
+            next_id: 0,
+        };
+        if protocol == Protocol::Binary {
+            client.writer.write_all(&protocol::hello())?;
+            client.writer.flush()?;
+            protocol::read_hello(&mut client.reader)?;
         }
+        Ok(client)
+    }
+
+    /// Authenticates the connection, returning the identity the credentials stand for.
+    ///
+    /// A server with accounts refuses other requests with `KvsError::Unauthenticated`
+    /// until then, and wrong credentials with `KvsError::InvalidCredentials`.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<String> {
+        self.call::<AuthResponse>(&Request::Auth { credentials })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call::<GetResponse>(&Request::Get { key })
     }
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
-        }
+        self.call::<SetResponse>(&Request::Set { key, value })
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        self.call::<RemoveResponse>(&Request::Remove { key })
+    }
+
+    /// Gets the values of several keys in one round-trip, in the order of `keys`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.call::<GetManyResponse>(&Request::GetMany { keys })
+    }
+
+    /// Sets several keys in one round-trip.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.call::<SetResponse>(&Request::SetMany { pairs })
+    }
+
+    /// Removes several keys in one round-trip, telling for each of them whether it was there.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.call::<RemoveManyResponse>(&Request::RemoveMany { keys })
+    }
+
+    /// Fetches the statistics of the server's storage engine.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        self.call::<StatsResponse>(&Request::Stats)
+    }
+
+    /// Gets the value the key held right after the write with sequence number `seq`.
+    pub fn get_at(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        self.call::<GetResponse>(&Request::GetAt { key, seq })
+    }
+
+    /// Fetches the versions of the key the server retains, oldest first.
+    pub fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.call::<HistoryResponse>(&Request::History { key })
+    }
+
+    ///Send the request and read the response.
+    ///A server refusing a request stops reading it, the response tells why.
+    fn call<R: Response>(&mut self, req: &Request) -> Result<R::Output> {
+        let id = self.next_id();
+        let sent = self.send(req, id).and_then(|()| Ok(self.writer.flush()?));
+        let resp = match self.protocol {
+            Protocol::Json => R::deserialize(&mut Deserializer::from_reader(&mut self.reader))
+                .map_err(KvsError::from),
+            Protocol::Binary => protocol::read_response(&mut self.reader, id)
+                .and_then(|resp| resp.ok_or_else(connection_closed)),
+        };
+        match sent {
+            Ok(()) => resp?.into_result(),
+            Err(e) => resp.map_err(|_| e)?.into_result(),
+        }
+    }
+
+    /// Starts sending requests without waiting for their responses.
+    ///
+    /// Only the binary protocol carries request ids, a JSON client fails with
+    /// `KvsError::Protocol`.
+    pub fn pipeline(&mut self) -> Result<Pipeline<'_>> {
+        if self.protocol != Protocol::Binary {
+            return Err(KvsError::Protocol(
+                "pipelining needs the binary protocol".to_owned(),
+            ));
+        }
+        Ok(Pipeline {
+            client: self,
+            arrived: HashMap::new(),
+            waiting: HashSet::new(),
+        })
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    ///buffer the request, JSON requests carry no id
+    fn send(&mut self, req: &Request, id: u64) -> Result<()> {
+        match self.protocol {
+            Protocol::Json => serde_json::to_writer(&mut self.writer, req)?,
+            Protocol::Binary => self.writer.write_all(&protocol::encode_request(req, id)?)?,
+        }
+        Ok(())
+    }
+
+    /// Subscribes to writes of keys starting with `prefix`.
+    ///
+    /// The connection is dedicated to the subscription afterwards,
+    /// so the client is consumed.
+    pub fn watch(mut self, prefix: String) -> Result<impl Iterator<Item = Result<Event>>> {
+        let id = self.next_id();
+        self.send(&Request::Watch { prefix }, id)?;
         self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        let mut reader = self.reader;
+        let resps: Box<dyn Iterator<Item = Result<WatchResponse>> + Send> = match self.protocol {
+            Protocol::Json => Box::new(
+                Deserializer::from_reader(reader)
+                    .into_iter::<WatchResponse>()
+                    .map(|resp| Ok(resp?)),
+            ),
+            Protocol::Binary => {
+                //like the JSON stream, nothing more is read after an error
+                let mut failed = false;
+                Box::new(iter::from_fn(move || {
+                    if failed {
+                        return None;
+                    }
+                    let resp = protocol::read_response(&mut reader, id).transpose();
+                    failed = matches!(resp, Some(Err(_)));
+                    resp
+                }))
+            }
+        };
+        Ok(resps.map(|resp| match resp? {
+            WatchResponse::Event(event) => Ok(event),
+            WatchResponse::Err(e) => Err(e.into()),
+        }))
+    }
+}
+
+fn connection_closed() -> KvsError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed").into()
+}
+
+/// Requests sent on a `KvsClient` without waiting for their responses.
+///
+/// Each request returns a `Pending` handle, `wait` gives its result.
+/// The server may run the requests concurrently and in any order,
+/// so wait for a write before sending requests that depend on it.
+/// Requests are buffered until a handle is waited for or `flush` is called.
+/// Dropping the pipeline reads the responses nobody waited for.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    ///responses read while waiting for another one
+    arrived: HashMap<u64, Vec<u8>>,
+    ///requests sent whose handle has not been waited for
+    waiting: HashSet<u64>,
+}
+
+/// A request sent on a `Pipeline`, its result is given by `Pipeline::wait`.
+#[must_use = "the result of a request is only known by waiting for it"]
+pub struct Pending<T> {
+    id: u64,
+    decode: fn(&[u8]) -> Result<T>,
+    output: PhantomData<T>,
+}
+
+fn decode<R: Response>(payload: &[u8]) -> Result<R::Output> {
+    bincode::deserialize::<R>(payload)?.into_result()
+}
+
+impl<'a> Pipeline<'a> {
+    /// Sends a get.
+    pub fn get(&mut self, key: String) -> Result<Pending<Option<String>>> {
+        self.send::<GetResponse>(&Request::Get { key })
+    }
+
+    /// Sends a set.
+    pub fn set(&mut self, key: String, value: String) -> Result<Pending<()>> {
+        self.send::<SetResponse>(&Request::Set { key, value })
+    }
+
+    /// Sends a remove.
+    pub fn remove(&mut self, key: String) -> Result<Pending<()>> {
+        self.send::<RemoveResponse>(&Request::Remove { key })
+    }
+
+    /// Sends a get of several keys.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Pending<Vec<Option<String>>>> {
+        self.send::<GetManyResponse>(&Request::GetMany { keys })
+    }
+
+    /// Sends a set of several keys.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Pending<()>> {
+        self.send::<SetResponse>(&Request::SetMany { pairs })
+    }
+
+    /// Sends a remove of several keys.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Pending<Vec<bool>>> {
+        self.send::<RemoveManyResponse>(&Request::RemoveMany { keys })
+    }
+
+    /// Asks for the statistics of the server's storage engine.
+    pub fn stats(&mut self) -> Result<Pending<EngineStats>> {
+        self.send::<StatsResponse>(&Request::Stats)
+    }
+
+    /// Sends a get of the value the key held right after the write with sequence number `seq`.
+    pub fn get_at(&mut self, key: String, seq: u64) -> Result<Pending<Option<String>>> {
+        self.send::<GetResponse>(&Request::GetAt { key, seq })
+    }
+
+    /// Asks for the versions of the key the server retains.
+    pub fn history(&mut self, key: String) -> Result<Pending<Vec<Version>>> {
+        self.send::<HistoryResponse>(&Request::History { key })
+    }
+
+    /// Sends the buffered requests.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.client.writer.flush()?)
+    }
+
+    /// Waits for the response to a request sent on this pipeline.
+    pub fn wait<T>(&mut self, pending: Pending<T>) -> Result<T> {
+        if !self.waiting.contains(&pending.id) {
+            return Err(KvsError::Protocol(format!(
+                "request {} was not sent on this pipeline",
+                pending.id
+            )));
+        }
+        self.flush()?;
+        while !self.arrived.contains_key(&pending.id) {
+            self.read_response()?;
+        }
+        self.waiting.remove(&pending.id);
+        (pending.decode)(&self.arrived.remove(&pending.id).unwrap())
+    }
+
+    fn send<R: Response>(&mut self, req: &Request) -> Result<Pending<R::Output>> {
+        if self.waiting.len() - self.arrived.len() >= MAX_UNREAD {
+            self.flush()?;
+            self.read_response()?;
+        }
+        let id = self.client.next_id();
+        self.client.send(req, id)?;
+        self.waiting.insert(id);
+        Ok(Pending {
+            id,
+            decode: decode::<R>,
+            output: PhantomData,
+        })
+    }
+
+    fn read_response(&mut self) -> Result<()> {
+        let (id, payload) =
+            protocol::read_frame(&mut self.client.reader)?.ok_or_else(connection_closed)?;
+        if !self.waiting.contains(&id) {
+            return Err(KvsError::Protocol(format!(
+                "response to request {} nobody waits for",
+                id
+            )));
+        }
+        self.arrived.insert(id, payload);
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Pipeline<'a> {
+    ///the next request of the client must not read these responses
+    fn drop(&mut self) {
+        if self.flush().is_err() {
+            return;
+        }
+        while self.waiting.len() > self.arrived.len() {
+            if self.read_response().is_err() {
+                return;
+            }
         }
     }
 }
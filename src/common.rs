@@ -1,3 +1,5 @@
+use crate::{Credentials, EngineStats, Event, KvsError, Permission, Result, Version};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -5,20 +7,157 @@ pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    Watch { prefix: String },
+    Stats,
+    GetAt { key: String, seq: u64 },
+    History { key: String },
+    GetMany { keys: Vec<String> },
+    SetMany { pairs: Vec<(String, String)> },
+    RemoveMany { keys: Vec<String> },
+    Auth { credentials: Credentials },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(RemoteError),
 }
 #[derive(Deserialize, Serialize, Debug)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum WatchResponse {
+    Event(Event),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum GetManyResponse {
+    Ok(Vec<Option<String>>),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RemoveManyResponse {
+    Ok(Vec<bool>),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HistoryResponse {
+    Ok(Vec<Version>),
+    Err(RemoteError),
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AuthResponse {
+    ///the identity the connection is authenticated as
+    Ok(String),
+    Err(RemoteError),
+}
+
+///A response as the client hands it over
+pub(crate) trait Response: DeserializeOwned {
+    type Output;
+    fn into_result(self) -> Result<Self::Output>;
+}
+
+macro_rules! response {
+    ($resp:ident, $output:ty) => {
+        impl Response for $resp {
+            type Output = $output;
+            fn into_result(self) -> Result<$output> {
+                match self {
+                    $resp::Ok(value) => Ok(value),
+                    $resp::Err(e) => Err(e.into()),
+                }
+            }
+        }
+    };
+}
+
+response!(GetResponse, Option<String>);
+response!(SetResponse, ());
+response!(RemoveResponse, ());
+response!(StatsResponse, EngineStats);
+response!(GetManyResponse, Vec<Option<String>>);
+response!(RemoveManyResponse, Vec<bool>);
+response!(HistoryResponse, Vec<Version>);
+response!(AuthResponse, String);
+
+///An error as it travels to the client.
+///The ones a client may want to handle keep their variant, the rest only their message.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RemoteError {
+    KeyTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    ValueTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    RequestTooLarge {
+        limit: u64,
+    },
+    Unauthenticated,
+    InvalidCredentials,
+    PermissionDenied {
+        identity: String,
+        permission: Permission,
+        scope: String,
+    },
+    Other(String),
+}
+
+impl From<KvsError> for RemoteError {
+    fn from(e: KvsError) -> RemoteError {
+        match e {
+            KvsError::KeyTooLarge { size, limit } => RemoteError::KeyTooLarge { size, limit },
+            KvsError::ValueTooLarge { size, limit } => RemoteError::ValueTooLarge { size, limit },
+            KvsError::RequestTooLarge { limit } => RemoteError::RequestTooLarge { limit },
+            KvsError::Unauthenticated => RemoteError::Unauthenticated,
+            KvsError::InvalidCredentials => RemoteError::InvalidCredentials,
+            KvsError::PermissionDenied {
+                identity,
+                permission,
+                scope,
+            } => RemoteError::PermissionDenied {
+                identity,
+                permission,
+                scope,
+            },
+            e => RemoteError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<RemoteError> for KvsError {
+    fn from(e: RemoteError) -> KvsError {
+        match e {
+            RemoteError::KeyTooLarge { size, limit } => KvsError::KeyTooLarge { size, limit },
+            RemoteError::ValueTooLarge { size, limit } => KvsError::ValueTooLarge { size, limit },
+            RemoteError::RequestTooLarge { limit } => KvsError::RequestTooLarge { limit },
+            RemoteError::Unauthenticated => KvsError::Unauthenticated,
+            RemoteError::InvalidCredentials => KvsError::InvalidCredentials,
+            RemoteError::PermissionDenied {
+                identity,
+                permission,
+                scope,
+            } => KvsError::PermissionDenied {
+                identity,
+                permission,
+                scope,
+            },
+            RemoteError::Other(msg) => KvsError::StringError(msg),
+        }
+    }
 }
@@ -0,0 +1,95 @@
+use super::{EngineStats, KvsEngine, Version};
+use crate::{KvsError, Result};
+use std::future::Future;
+use tokio::task;
+
+/// An async face of a `KvsEngine`.
+///
+/// Every call runs the blocking engine on the runtime's blocking thread pool,
+/// so the async worker threads never wait for disk I/O.
+/// It has to be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncKvsEngine<E: KvsEngine>(E);
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    /// Wraps a blocking engine.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsEngine(engine)
+    }
+
+    /// Gets the blocking engine.
+    pub fn get_ref(&self) -> &E {
+        &self.0
+    }
+
+    /// Runs `f` with the engine on the blocking thread pool.
+    ///
+    /// The future owns a clone of the engine, engines need not be `Sync`.
+    pub fn call<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.0.clone();
+        async move {
+            task::spawn_blocking(move || f(&engine))
+                .await
+                .map_err(|e| KvsError::StringError(format!("engine task failed: {}", e)))?
+        }
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.call(move |engine| engine.set(key, value))
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.call(move |engine| engine.get(key))
+    }
+
+    /// Removes a given key.
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.call(move |engine| engine.remove(key))
+    }
+
+    /// Gets the values of several keys, in the order of `keys`.
+    pub fn get_many(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<String>>>> + Send {
+        self.call(move |engine| engine.get_many(keys))
+    }
+
+    /// Sets several keys, in order.
+    pub fn set_many(
+        &self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        self.call(move |engine| engine.set_many(pairs))
+    }
+
+    /// Removes several keys, telling for each of them whether it was there.
+    pub fn remove_many(&self, keys: Vec<String>) -> impl Future<Output = Result<Vec<bool>>> + Send {
+        self.call(move |engine| engine.remove_many(keys))
+    }
+
+    /// Returns a snapshot of the engine's size and compaction state.
+    pub fn stats(&self) -> impl Future<Output = Result<EngineStats>> + Send {
+        self.call(|engine| engine.stats())
+    }
+
+    /// Gets the value the key held right after the write with sequence number `seq`.
+    pub fn get_at(
+        &self,
+        key: String,
+        seq: u64,
+    ) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.call(move |engine| engine.get_at(key, seq))
+    }
+
+    /// Returns the versions of the key the engine retains, oldest first.
+    pub fn history(&self, key: String) -> impl Future<Output = Result<Vec<Version>>> + Send {
+        self.call(move |engine| engine.history(key))
+    }
+}
@@ -0,0 +1,109 @@
+use super::storage::{numbered_files, BufWriterWithPos, Storage};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+///a blob file is not written to any more once it is bigger than this
+pub(super) const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+///where a large value lives: file id, offset and length in the blob file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct BlobPos {
+    pub id: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+///Tracks which key references which blob, so the writer knows
+///how many bytes of every blob file are garbage without reading the log.
+///Only keys whose current value is a blob are kept here.
+#[derive(Default)]
+pub(super) struct BlobIndex {
+    refs: HashMap<String, BlobPos>,
+    ///(blob id,unreferenced bytes)
+    garbage: BTreeMap<u64, u64>,
+    total_garbage: u64,
+    ///the highest blob id any command mentions, even a superseded one,
+    ///new blob files get higher ids so stale commands never point into them
+    max_id: u64,
+}
+
+impl BlobIndex {
+    ///point the key to a new blob (or to an inline value with `None`),
+    ///turning the blob it referenced before into garbage
+    pub fn replace(&mut self, key: &str, blob: Option<BlobPos>) {
+        let old = match blob {
+            Some(blob) => {
+                self.max_id = self.max_id.max(blob.id);
+                self.refs.insert(key.to_owned(), blob)
+            }
+            None => self.refs.remove(key),
+        };
+        if let Some(old) = old {
+            *self.garbage.entry(old.id).or_insert(0) += old.len;
+            self.total_garbage += old.len;
+        }
+    }
+
+    ///count the bytes of every blob file that no key references as garbage,
+    ///this also covers values written by a crashed process before their pointer.
+    ///Files superseded commands point to may be gone already, they hold no garbage.
+    pub fn account_files(&mut self, storage: &dyn Storage) -> Result<()> {
+        let mut live: BTreeMap<u64, u64> = BTreeMap::new();
+        for blob in self.refs.values() {
+            *live.entry(blob.id).or_insert(0) += blob.len;
+        }
+        self.garbage.clear();
+        self.total_garbage = 0;
+        for id in blob_file_list(storage)? {
+            let size = storage.open(&blob_name(id))?.size()?;
+            let garbage = size.saturating_sub(live.get(&id).cloned().unwrap_or(0));
+            if garbage > 0 {
+                self.garbage.insert(id, garbage);
+                self.total_garbage += garbage;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn max_id(&self) -> u64 {
+        self.max_id
+    }
+
+    pub fn total_garbage(&self) -> u64 {
+        self.total_garbage
+    }
+
+    ///blob files holding garbage
+    pub fn dirty_files(&self) -> Vec<u64> {
+        self.garbage.keys().cloned().collect()
+    }
+
+    ///(key,blob) of every live value stored in the blob file
+    pub fn live_in(&self, id: u64) -> Vec<(String, BlobPos)> {
+        self.refs
+            .iter()
+            .filter(|(_, blob)| blob.id == id)
+            .map(|(key, blob)| (key.clone(), *blob))
+            .collect()
+    }
+
+    ///forget the garbage of a blob file that has been deleted
+    pub fn forget(&mut self, id: u64) {
+        if let Some(garbage) = self.garbage.remove(&id) {
+            self.total_garbage -= garbage;
+        }
+    }
+}
+
+pub(super) fn new_blob_file(storage: &dyn Storage, id: u64) -> Result<BufWriterWithPos> {
+    Ok(BufWriterWithPos::new(storage.create(&blob_name(id))?)?)
+}
+
+pub(super) fn blob_name(id: u64) -> String {
+    format!("{}.blob", id)
+}
+
+pub(super) fn blob_file_list(storage: &dyn Storage) -> Result<Vec<u64>> {
+    Ok(numbered_files(storage, "blob")?)
+}
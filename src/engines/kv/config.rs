@@ -0,0 +1,61 @@
+use crate::{KvsError, Result};
+use std::time::Duration;
+
+/// Options for opening a `KvStore`
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// Values at least this many bytes long are written to blob files
+    /// and only referenced from the log, so compaction does not copy them.
+    /// `None`, the default, keeps every value in the log.
+    pub blob_threshold: Option<u64>,
+    /// Unreferenced blob bytes that trigger a blob garbage collection
+    pub blob_gc_threshold: u64,
+    /// Once the keys in the in-memory index take more than this many bytes,
+    /// the index keeps only a hash per key and reads keys back from the log.
+    /// `Some(0)` always does so, `None` keeps every key in memory.
+    pub index_memory_budget: Option<u64>,
+    /// Which superseded versions of a key compaction keeps for `get_at` and `history`.
+    /// Keeping more than the latest stores every value in the log and never collects blob files.
+    /// The versions are kept by key, so it cannot go with an `index_memory_budget`.
+    pub retention: Retention,
+    /// Sets with a longer key fail with `KvsError::KeyTooLarge`, `None` accepts any key
+    pub max_key_size: Option<u64>,
+    /// Sets with a longer value fail with `KvsError::ValueTooLarge`, `None` accepts any value
+    pub max_value_size: Option<u64>,
+}
+
+/// Which versions of a key a `KvStore` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// Only the latest version
+    Latest,
+    /// The latest `n` versions, removals included
+    Versions(usize),
+    /// The versions written within the window, and the latest one
+    Window(Duration),
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            blob_threshold: None,
+            blob_gc_threshold: 64 * 1024 * 1024,
+            index_memory_budget: None,
+            retention: Retention::Latest,
+            max_key_size: None,
+            max_value_size: None,
+        }
+    }
+}
+
+impl KvStoreConfig {
+    ///fails on options that do not go together
+    pub(super) fn check(&self) -> Result<()> {
+        if self.index_memory_budget.is_some() && self.retention != Retention::Latest {
+            return Err(KvsError::StringError(
+                "a retention keeping old versions cannot go with an index memory budget".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use super::config::Retention;
+use super::CommandPos;
+use crate::Result;
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///where one version of a key lives in the log
+#[derive(Debug, Clone, Copy)]
+pub(super) struct VersionPos {
+    pub seq: u64,
+    ///seconds since the unix epoch
+    pub time: u64,
+    pub pos: CommandPos,
+    pub removed: bool,
+}
+
+///Hands out sequence numbers and keeps the versions of every key
+///the retention asks for, oldest first.
+///Only the latest version is kept by default, and the index knows that one,
+///so then nothing is stored here.
+pub(super) struct History {
+    retention: Retention,
+    keys: HashMap<String, VecDeque<VersionPos>>,
+    last_seq: u64,
+}
+
+impl History {
+    pub fn new(retention: Retention) -> History {
+        History {
+            retention,
+            keys: HashMap::new(),
+            last_seq: 0,
+        }
+    }
+
+    ///whether superseded versions are kept
+    pub fn is_enabled(&self) -> bool {
+        self.retention != Retention::Latest
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    ///make sure later sequence numbers are bigger than `seq`
+    pub fn observe(&mut self, seq: u64) {
+        self.last_seq = self.last_seq.max(seq);
+    }
+
+    ///(sequence number,time) of a new write
+    pub fn stamp(&mut self) -> (u64, u64) {
+        self.last_seq += 1;
+        (self.last_seq, now())
+    }
+
+    ///Record the newest version of the key, `superseded` is the set it replaces.
+    ///Returns how many bytes of the log are not needed any more.
+    pub fn push(&mut self, key: &str, version: VersionPos, superseded: Option<CommandPos>) -> u64 {
+        self.observe(version.seq);
+        if !self.is_enabled() {
+            let mut stale = superseded.map_or(0, |pos| pos.len);
+            //nothing is left to remove once compacted
+            if version.removed {
+                stale += version.pos.len;
+            }
+            return stale;
+        }
+
+        let versions = self.keys.entry(key.to_owned()).or_default();
+        versions.push_back(version);
+        prune(self.retention, versions, now())
+    }
+
+    ///the versions kept for the key, oldest first
+    pub fn versions(&self, key: &str) -> Vec<VersionPos> {
+        self.keys
+            .get(key)
+            .map_or_else(Vec::new, |versions| versions.iter().cloned().collect())
+    }
+
+    ///drop the versions that fell out of the window, returning their bytes
+    pub fn prune_all(&mut self) -> u64 {
+        let retention = self.retention;
+        let now = now();
+        let mut stale = 0;
+        self.keys.retain(|_, versions| {
+            stale += prune(retention, versions, now);
+            !versions.is_empty()
+        });
+        stale
+    }
+
+    ///Replace the position of every version with the one `f` returns for it, used by compaction.
+    ///The versions of a key are visited oldest first.
+    pub fn update_all<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(CommandPos) -> Result<CommandPos>,
+    {
+        for versions in self.keys.values_mut() {
+            for version in versions.iter_mut() {
+                version.pos = f(version.pos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///Drop the oldest versions the retention does not keep, returning their bytes.
+///The latest version stays, unless it is a removal that fell out of the window.
+fn prune(retention: Retention, versions: &mut VecDeque<VersionPos>, now: u64) -> u64 {
+    let mut stale = 0;
+    match retention {
+        Retention::Latest => {}
+        Retention::Versions(n) => {
+            while versions.len() > n.max(1) {
+                stale += versions.pop_front().unwrap().pos.len;
+            }
+        }
+        Retention::Window(window) => {
+            let expired =
+                |version: &VersionPos| now.saturating_sub(version.time) > window.as_secs();
+            while versions.len() > 1 && expired(&versions[0]) {
+                stale += versions.pop_front().unwrap().pos.len;
+            }
+            if versions.len() == 1 && versions[0].removed && expired(&versions[0]) {
+                stale += versions.pop_front().unwrap().pos.len;
+            }
+        }
+    }
+    stale
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
@@ -0,0 +1,193 @@
+use super::CommandPos;
+use crate::Result;
+use crossbeam_skiplist::SkipMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+///rough memory taken by one entry of the full index besides its key
+const FULL_ENTRY_OVERHEAD: u64 = 64;
+
+///Reads the key of the record at a position back from the log.
+pub(super) type KeyResolver<'a> = &'a dyn Fn(CommandPos) -> Result<String>;
+
+///The in-memory index from keys to their latest record.
+///
+///It starts out keeping every key. Once the keys take more memory than the budget
+///it switches, for good, to keeping only a 64-bit hash per key and reading
+///the full key back from the log whenever hashes collide.
+///Readers may look keys up while the writer switches.
+pub(super) struct Index {
+    budget: Option<u64>,
+    hashed: AtomicBool,
+    full: SkipMap<String, CommandPos>,
+    full_bytes: AtomicU64,
+    ///(hash of key,collision number) -> position
+    compact: SkipMap<(u64, u32), CommandPos>,
+}
+
+impl Index {
+    ///`budget` is the most memory the full index may take, `None` for no limit
+    pub fn new(budget: Option<u64>) -> Index {
+        Index {
+            budget,
+            hashed: AtomicBool::new(budget == Some(0)),
+            full: SkipMap::new(),
+            full_bytes: AtomicU64::new(0),
+            compact: SkipMap::new(),
+        }
+    }
+
+    pub fn is_hashed(&self) -> bool {
+        self.hashed.load(Ordering::SeqCst)
+    }
+
+    ///Positions that may hold the key, the caller has to check the key of the record.
+    ///Only one position is returned unless hashes collide.
+    pub fn lookup(&self, key: &str) -> Vec<CommandPos> {
+        if !self.is_hashed() {
+            if let Some(entry) = self.full.get(key) {
+                return vec![*entry.value()];
+            }
+            //the writer may have switched and emptied the full index meanwhile
+            if !self.is_hashed() {
+                return Vec::new();
+            }
+        }
+        let hash = hash(key);
+        self.compact
+            .range((hash, 0)..=(hash, u32::MAX))
+            .map(|entry| *entry.value())
+            .collect()
+    }
+
+    ///the position of the key
+    pub fn get(&self, key: &str, resolve: KeyResolver) -> Result<Option<CommandPos>> {
+        if !self.is_hashed() {
+            return Ok(self.full.get(key).map(|entry| *entry.value()));
+        }
+        Ok(self.find(key, resolve)?.map(|(_, pos)| pos))
+    }
+
+    ///point the key to a new position, returning the old one
+    pub fn insert(
+        &self,
+        key: String,
+        pos: CommandPos,
+        resolve: KeyResolver,
+    ) -> Result<Option<CommandPos>> {
+        if !self.is_hashed() {
+            let old = self.full.get(&key).map(|entry| *entry.value());
+            if old.is_none() {
+                self.full_bytes
+                    .fetch_add(key.len() as u64 + FULL_ENTRY_OVERHEAD, Ordering::SeqCst);
+            }
+            self.full.insert(key, pos);
+            if let Some(budget) = self.budget {
+                if self.full_bytes.load(Ordering::SeqCst) > budget {
+                    self.switch_to_hashed();
+                }
+            }
+            return Ok(old);
+        }
+
+        if let Some((slot, old)) = self.find(&key, resolve)? {
+            self.compact.insert(slot, pos);
+            return Ok(Some(old));
+        }
+        let hash = hash(&key);
+        self.compact.insert(next_slot(&self.compact, hash), pos);
+        Ok(None)
+    }
+
+    ///forget the key, returning its position
+    pub fn remove(&self, key: &str, resolve: KeyResolver) -> Result<Option<CommandPos>> {
+        if !self.is_hashed() {
+            let old = self.full.remove(key).map(|entry| *entry.value());
+            if old.is_some() {
+                self.full_bytes
+                    .fetch_sub(key.len() as u64 + FULL_ENTRY_OVERHEAD, Ordering::SeqCst);
+            }
+            return Ok(old);
+        }
+
+        match self.find(key, resolve)? {
+            Some((slot, old)) => {
+                self.compact.remove(&slot);
+                Ok(Some(old))
+            }
+            None => Ok(None),
+        }
+    }
+
+    ///Replace every position with the one `f` returns for it, used by compaction.
+    ///Positions are visited in key order when the full index is used.
+    pub fn update_all<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(CommandPos) -> Result<CommandPos>,
+    {
+        if !self.is_hashed() {
+            for entry in self.full.iter() {
+                self.full.insert(entry.key().clone(), f(*entry.value())?);
+            }
+        } else {
+            for entry in self.compact.iter() {
+                self.compact.insert(*entry.key(), f(*entry.value())?);
+            }
+        }
+        Ok(())
+    }
+
+    ///visit the position of every live key
+    pub fn for_each<F: FnMut(CommandPos)>(&self, mut f: F) {
+        if !self.is_hashed() {
+            self.full.iter().for_each(|entry| f(*entry.value()));
+        } else {
+            self.compact.iter().for_each(|entry| f(*entry.value()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        if !self.is_hashed() {
+            self.full.len()
+        } else {
+            self.compact.len()
+        }
+    }
+
+    ///the slot and position of the key in the hashed index
+    fn find(&self, key: &str, resolve: KeyResolver) -> Result<Option<((u64, u32), CommandPos)>> {
+        let hash = hash(key);
+        for entry in self.compact.range((hash, 0)..=(hash, u32::MAX)) {
+            if resolve(*entry.value())? == key {
+                return Ok(Some((*entry.key(), *entry.value())));
+            }
+        }
+        Ok(None)
+    }
+
+    ///fill the hashed index before readers are told to use it,
+    ///then drop the keys
+    fn switch_to_hashed(&self) {
+        for entry in self.full.iter() {
+            let hash = hash(entry.key());
+            self.compact
+                .insert(next_slot(&self.compact, hash), *entry.value());
+        }
+        self.hashed.store(true, Ordering::SeqCst);
+        self.full.clear();
+        self.full_bytes.store(0, Ordering::SeqCst);
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+///the first free collision number after those taken for the hash
+fn next_slot(compact: &SkipMap<(u64, u32), CommandPos>, hash: u64) -> (u64, u32) {
+    let last = compact.range((hash, 0)..=(hash, u32::MAX)).next_back();
+    (hash, last.map_or(0, |entry| entry.key().1 + 1))
+}
@@ -0,0 +1,41 @@
+use super::storage::{read_file, Storage};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+const MANIFEST: &str = "MANIFEST";
+
+///The generations that make up the store.
+///
+///A generation file that is not listed, like a compaction cut short by a crash,
+///is not part of the store and gets deleted on open.
+///The manifest is replaced atomically, it is either the old or the new list.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(super) struct Manifest {
+    pub gens: Vec<u64>,
+    ///the last sequence number handed out, compaction may drop the command that had it
+    #[serde(default)]
+    pub last_seq: u64,
+}
+
+impl Manifest {
+    ///`None` for a store written before manifests existed
+    pub fn load(storage: &dyn Storage) -> Result<Option<Manifest>> {
+        match read_file(storage, MANIFEST) {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///write a temporary file, sync it, then rename it over the manifest
+    pub fn store(&self, storage: &dyn Storage) -> Result<()> {
+        let tmp_name = "MANIFEST.tmp";
+        let mut file = storage.create(tmp_name)?;
+        file.append(&serde_json::to_vec(self)?)?;
+        file.sync()?;
+        storage.rename(tmp_name, MANIFEST)?;
+        storage.sync()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1032 @@
+use self::blob::{blob_file_list, blob_name, new_blob_file, BlobIndex, BlobPos, BLOB_FILE_SIZE};
+use self::history::{History, VersionPos};
+use self::index::{Index, KeyResolver};
+use self::manifest::Manifest;
+use self::storage::{numbered_files, BufWriterWithPos, SequentialReader};
+use super::{check_size, EngineStats, Event, GenerationStats, KvsEngine, Version, Watcher};
+use crate::{KvsError, Result, StoreMeta};
+use crossbeam::channel::{self, Sender};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::cell::{Cell, RefCell};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod blob;
+mod config;
+mod history;
+mod index;
+mod manifest;
+mod storage;
+
+pub use self::config::{KvStoreConfig, Retention};
+pub use self::storage::{MemoryStorage, OsStorage, Storage, StorageFile};
+
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+#[derive(Clone)]
+pub struct KvStore {
+    ///server启动后内存里的索引树，键值对为(key,cmd_pos)
+    index: Arc<Index>,
+    //old!//readers: HashMap<u64, BufReaderWithPos<File>> //<file_pos,reader>
+    reader: KvStoreReader,
+    //old!//writer: BufWriterWithPos<File>,
+    ///
+    writer: Arc<Mutex<KvStoreWriter>>,
+    ///shared with the writer, so versions are read without its lock
+    history: Arc<RwLock<History>>,
+    ///set by `close`, readers check it without the writer lock
+    closed: Arc<AtomicBool>,
+    //move to the KvStoreWriter
+    //current_gen: u64, //current_file_pos
+    //uncompacted: u64, //useless log waiting for compact
+}
+
+impl KvStore {
+    ///Open a Kvstore with the given path
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_config(path, KvStoreConfig::default())
+    }
+
+    ///Open a Kvstore with the given path and options
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<KvStore> {
+        config.check()?;
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        StoreMeta::check(&path, "kvs")?;
+        KvStore::open_with_storage(OsStorage::new(path), config)
+    }
+
+    ///Open a Kvstore keeping its files in the given storage
+    pub fn open_with_storage(
+        storage: impl Storage + 'static,
+        config: KvStoreConfig,
+    ) -> Result<KvStore> {
+        config.check()?;
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+
+        let index = Arc::new(Index::new(config.index_memory_budget));
+        let mut blobs = BlobIndex::default();
+        let safe_point = Arc::new(AtomicU64::new(0));
+
+        let reader = KvStoreReader {
+            storage: Arc::clone(&storage),
+            safe_point,
+            readers: RefCell::new(BTreeMap::new()),
+            blob_epoch: Arc::new(AtomicU64::new(0)),
+            seen_blob_epoch: Cell::new(0),
+            blob_readers: RefCell::new(BTreeMap::new()),
+        };
+
+        //a store older than the manifest is made of every generation file
+        let mut history = History::new(config.retention);
+        let gen_list = match Manifest::load(&*storage)? {
+            Some(manifest) => {
+                history.observe(manifest.last_seq);
+                for gen in gen_file_list(&*storage)? {
+                    if !manifest.gens.contains(&gen) {
+                        warn!("{} is not in the manifest, deleting it", log_name(gen));
+                        storage.remove(&log_name(gen))?;
+                    }
+                }
+                manifest.gens
+            }
+            None => gen_file_list(&*storage)?,
+        };
+        let mut uncompacted = 0;
+
+        for &gen in &gen_list {
+            let mut gen_file = storage.open(&log_name(gen))?;
+            let gen_reader = SequentialReader::new(&mut *gen_file)?;
+            uncompacted += load(
+                gen,
+                gen_reader,
+                &index,
+                &reader.key_at(),
+                &mut blobs,
+                &mut history,
+            )?;
+            reader.readers.borrow_mut().insert(gen, gen_file);
+        }
+        blobs.account_files(&*storage)?;
+        let current_blob = blob_file_list(&*storage)?
+            .last()
+            .map_or(0, |&id| id)
+            .max(blobs.max_id());
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&*storage, current_gen)?;
+        let mut gens = gen_list;
+        gens.push(current_gen);
+        let manifest = Manifest {
+            gens,
+            last_seq: history.last_seq(),
+        };
+        manifest.store(&*storage)?;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let history = Arc::new(RwLock::new(history));
+        let writer = KvStoreWriter {
+            reader: reader.clone(),
+            writer,
+            current_gen,
+            manifest,
+            uncompacted,
+            storage,
+            index: Arc::clone(&index),
+            watchers: Vec::new(),
+            compaction_count: 0,
+            compaction_duration: Duration::default(),
+            last_compaction: None,
+            config,
+            blobs,
+            blob_writer: None,
+            current_blob,
+            history: Arc::clone(&history),
+            closed: Arc::clone(&closed),
+        };
+
+        Ok(KvStore {
+            reader,
+            index,
+            writer: Arc::new(Mutex::new(writer)),
+            history,
+            closed,
+        })
+    }
+
+    ///Flush the pending writes and wait until they are on disk,
+    ///together with the directory entries of the store's files
+    pub fn sync(&self) -> Result<()> {
+        self.lock_writer()?.sync()
+    }
+
+    ///Sync the store and close it for every clone.
+    ///
+    ///Waits for a running compaction first. Later operations on any clone
+    ///fail with `KvsError::StoreClosed`, and watchers stop. Closing twice does nothing.
+    pub fn close(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        writer.sync()?;
+        writer.watchers.clear();
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    ///the versions of the key, oldest first,
+    ///only the latest one when the retention keeps no more
+    fn versions(&self, key: &str) -> Result<Vec<VersionPos>> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvsError::StoreClosed);
+        }
+        let history = self.history.read().unwrap();
+        if history.is_enabled() {
+            return Ok(history.versions(key));
+        }
+        drop(history);
+        match self.index.get(key, &self.reader.key_at())? {
+            Some(pos) => {
+                let (seq, time) = self.reader.read_command(pos)?.stamp();
+                Ok(vec![VersionPos {
+                    seq,
+                    time,
+                    pos,
+                    removed: false,
+                }])
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    ///the value a version of the key set,
+    ///`None` if its blob file was collected after the version was looked up
+    fn read_version(&self, key: &str, pos: CommandPos) -> Result<Option<String>> {
+        match self.reader.read_value(pos) {
+            Ok(value) => Ok(Some(value)),
+            Err(KvsError::Io(ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                    && !self.index.lookup(key).contains(&pos) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn version_at(&self, key: &str, seq: u64) -> Result<Option<String>> {
+        let versions = self.versions(key)?;
+        match versions.iter().rev().find(|version| version.seq <= seq) {
+            Some(version) if !version.removed => match self.read_version(key, version.pos)? {
+                Some(value) => Ok(Some(value)),
+                None => self.version_at(key, seq),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn versions_of(&self, key: &str) -> Result<Vec<Version>> {
+        let mut history = Vec::new();
+        for version in self.versions(key)? {
+            let value = if version.removed {
+                None
+            } else {
+                match self.read_version(key, version.pos)? {
+                    Some(value) => Some(value),
+                    None => return self.versions_of(key),
+                }
+            };
+            history.push(Version {
+                seq: version.seq,
+                time: UNIX_EPOCH + Duration::from_secs(version.time),
+                value,
+            });
+        }
+        Ok(history)
+    }
+
+    ///the writer, unless the store is closed
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        let writer = self.writer.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvsError::StoreClosed);
+        }
+        Ok(writer)
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// set op
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock_writer()?.set(key, value)
+    }
+
+    /// get op
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvsError::StoreClosed);
+        }
+        //more than one position only if the index keeps hashes and they collide
+        for cmd_pos in self.index.lookup(&key) {
+            match self.reader.read_command(cmd_pos)? {
+                Command::Set {
+                    key: cmd_key,
+                    value,
+                    ..
+                } if cmd_key == key => return Ok(Some(value)),
+                Command::SetBlob {
+                    key: cmd_key, blob, ..
+                } if cmd_key == key => {
+                    return match self.reader.read_blob(blob) {
+                        Ok(value) => Ok(Some(String::from_utf8(value)?)),
+                        //the blob file was collected after we looked the key up,
+                        //by then the index already points to the value's new place
+                        Err(KvsError::Io(ref e))
+                            if e.kind() == io::ErrorKind::NotFound
+                                && !self.index.lookup(&key).contains(&cmd_pos) =>
+                        {
+                            self.get(key)
+                        }
+                        Err(e) => Err(e),
+                    };
+                }
+                Command::Remove { .. } => return Err(KvsError::UnexpectedCommandType),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    //remove op
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.lock_writer()?.remove(key)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let (tx, rx) = channel::unbounded();
+        self.lock_writer()?.watchers.push((prefix, tx));
+        Ok(Watcher::from_channel(rx))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.lock_writer()?.stats()
+    }
+
+    fn for_each<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.lock_writer()?.for_each(f)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.lock_writer()?.set_many(pairs)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.lock_writer()?.compact()
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.lock_writer()?.remove_many(keys)
+    }
+
+    fn sync(&self) -> Result<()> {
+        KvStore::sync(self)
+    }
+
+    fn get_at(&self, key: String, seq: u64) -> Result<Option<String>> {
+        self.version_at(&key, seq)
+    }
+
+    fn history(&self, key: String) -> Result<Vec<Version>> {
+        self.versions_of(&key)
+    }
+}
+
+struct KvStoreReader {
+    storage: Arc<dyn Storage>,
+    safe_point: Arc<AtomicU64>,                            //?
+    readers: RefCell<BTreeMap<u64, Box<dyn StorageFile>>>, //why RefCell?Can i use Box?
+    ///bumped by the writer whenever it deletes blob files
+    blob_epoch: Arc<AtomicU64>,
+    ///the epoch blob_readers were opened in
+    seen_blob_epoch: Cell<u64>,
+    blob_readers: RefCell<BTreeMap<u64, Box<dyn StorageFile>>>,
+}
+
+impl KvStoreReader {
+    ///close those old_gen handles
+    ///because our safepoint is updated to the latest compactcion gen(cur_gen-1)
+    ///so those old_gen handles is useless? not handles but files
+    ///删除所有compaction后的old files
+    ///为什么要放到read方法里做呢?
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let first_gen = *readers.keys().next().unwrap();
+            //顺序一致性
+            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
+        }
+    }
+    ///Read the bytes of the command at CommandPos
+    fn read_bytes(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        //why we need to close the old gen?
+        //because we dont want to get the old value in multithread
+        //and get a incorrect value?->the problem need to deal
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        if !readers.contains_key(&cmd_pos.gen) {
+            let reader = self.storage.open(&log_name(cmd_pos.gen))?;
+            readers.insert(cmd_pos.gen, reader);
+        }
+
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let mut buf = vec![0; cmd_pos.len as usize];
+        reader.read_at(cmd_pos.pos, &mut buf)?;
+        Ok(buf)
+    }
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        Ok(serde_json::from_slice(&self.read_bytes(cmd_pos)?)?)
+    }
+    ///the value a set command at CommandPos wrote
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<String> {
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(value),
+            Command::SetBlob { blob, .. } => Ok(String::from_utf8(self.read_blob(blob)?)?),
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+    ///resolve keys for an index that only keeps their hashes
+    fn key_at(&self) -> impl Fn(CommandPos) -> Result<String> + '_ {
+        move |cmd_pos| Ok(self.read_command(cmd_pos)?.into_key())
+    }
+    ///Read a value from a blob file
+    fn read_blob(&self, blob: BlobPos) -> Result<Vec<u8>> {
+        let mut readers = self.blob_readers.borrow_mut();
+        //handles of deleted blob files would keep their space from being freed
+        let epoch = self.blob_epoch.load(Ordering::SeqCst);
+        if self.seen_blob_epoch.replace(epoch) != epoch {
+            readers.clear();
+        }
+
+        let file = match readers.entry(blob.id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.storage.open(&blob_name(blob.id))?),
+        };
+        let mut value = vec![0; blob.len as usize];
+        file.read_at(blob.pos, &mut value)?;
+        Ok(value)
+    }
+}
+// why not use derive clone?
+// because what it impl is the Arc clone
+// not clone the something in Arc?
+// so we impl clone on hands and use Arc's method clone ?
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            storage: Arc::clone(&self.storage),
+            safe_point: Arc::clone(&self.safe_point),
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+            blob_epoch: Arc::clone(&self.blob_epoch),
+            seen_blob_epoch: Cell::new(self.blob_epoch.load(Ordering::SeqCst)),
+            blob_readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithPos,
+    current_gen: u64,
+    ///the generations the store is made of, as stored on disk
+    manifest: Manifest,
+
+    uncompacted: u64,
+    storage: Arc<dyn Storage>,
+    index: Arc<Index>,
+    ///(prefix,sender) of every live subscription
+    watchers: Vec<(String, Sender<Event>)>,
+    //compaction statistics since open
+    compaction_count: u64,
+    compaction_duration: Duration,
+    last_compaction: Option<SystemTime>,
+    config: KvStoreConfig,
+    blobs: BlobIndex,
+    ///created on the first large value, so idle stores leave no empty blob files
+    blob_writer: Option<BufWriterWithPos>,
+    current_blob: u64,
+    ///sequence numbers and the versions kept by the retention
+    history: Arc<RwLock<History>>,
+    closed: Arc<AtomicBool>,
+}
+impl KvStoreWriter {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_many(vec![(key, value)])
+    }
+
+    ///Append every set, then flush once before the index points readers to them.
+    ///If one fails the ones before it are still applied, so the index agrees with the log.
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        //a batch with one pair too large writes nothing
+        for (key, value) in &pairs {
+            check_size(
+                key,
+                value,
+                self.config.max_key_size,
+                self.config.max_value_size,
+            )?;
+        }
+        let mut written = Vec::with_capacity(pairs.len());
+        let mut res = Ok(());
+        for (key, value) in pairs {
+            match self.append_set(key, value) {
+                Ok(set) => written.push(set),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        self.writer.flush()?;
+
+        for (cmd, range, event) in written {
+            self.apply(cmd, range)?;
+            if let Some(event) = event {
+                self.notify(event);
+            }
+        }
+        res?;
+        self.maintain()
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.remove_many(vec![key])?[0] {
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    ///Like `set_many`, tells for every key whether it was there.
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        //the index only forgets the keys after the flush
+        let mut removing = HashSet::new();
+        let mut found = Vec::with_capacity(keys.len());
+        let mut written = Vec::new();
+        let mut res = Ok(());
+        for key in keys {
+            if removing.contains(&key) || self.index.get(&key, &self.reader.key_at())?.is_none() {
+                found.push(false);
+                continue;
+            }
+            let (seq, time) = self.history.write().unwrap().stamp();
+            let cmd = Command::remove(key.clone(), seq, time);
+            match self.append(&cmd) {
+                Ok(range) => written.push((cmd, range)),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+            removing.insert(key);
+            found.push(true);
+        }
+        if written.is_empty() {
+            return res.map(|()| found);
+        }
+        self.writer.flush()?;
+
+        for (cmd, range) in written {
+            let key = self.apply(cmd, range)?;
+            if !self.watchers.is_empty() {
+                self.notify(Event::Remove { key });
+            }
+        }
+        res?;
+        self.maintain()?;
+        Ok(found)
+    }
+
+    ///append a set to the log without flushing it, large values go to a blob file first
+    fn append_set(
+        &mut self,
+        key: String,
+        value: String,
+    ) -> Result<(Command, Range<u64>, Option<Event>)> {
+        let event = if self.watchers.is_empty() {
+            None
+        } else {
+            Some(Event::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+        };
+        //old versions keep referencing their blobs, which only the latest one tracks
+        let blob = match self.config.blob_threshold {
+            Some(threshold)
+                if value.len() as u64 >= threshold
+                    && !self.history.read().unwrap().is_enabled() =>
+            {
+                Some(self.write_blob(value.as_bytes())?)
+            }
+            _ => None,
+        };
+        let (seq, time) = self.history.write().unwrap().stamp();
+        let cmd = match blob {
+            Some(blob) => Command::set_blob(key, blob, seq, time),
+            None => Command::set(key, value, seq, time),
+        };
+        let range = self.append(&cmd)?;
+        Ok((cmd, range, event))
+    }
+
+    ///where in the current generation the command was written
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let pos = self.writer.pos;
+        serde_json::to_writer(&mut self.writer, cmd)?;
+        Ok(pos..self.writer.pos)
+    }
+
+    ///Point the index to a flushed command of the current generation.
+    ///Returns the key of the command.
+    fn apply(&mut self, cmd: Command, range: Range<u64>) -> Result<String> {
+        let (seq, time) = cmd.stamp();
+        let pos = (self.current_gen, range).into();
+        let (key, blob, removed) = match cmd {
+            Command::Set { key, .. } => (key, None, false),
+            Command::SetBlob { key, blob, .. } => (key, Some(blob), false),
+            Command::Remove { key, .. } => (key, None, true),
+        };
+        let version = VersionPos {
+            seq,
+            time,
+            pos,
+            removed,
+        };
+        let old_pos = if removed {
+            let old_pos = self.index.remove(&key, &self.reader.key_at())?;
+            Some(old_pos.expect("key not found"))
+        } else {
+            self.index.insert(key.clone(), pos, &self.reader.key_at())?
+        };
+        self.uncompacted += self.history.write().unwrap().push(&key, version, old_pos);
+        self.blobs.replace(&key, blob);
+        Ok(key)
+    }
+
+    ///compact the log or collect blob files once enough of them is stale
+    fn maintain(&mut self) -> Result<()> {
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+        if self.blobs.total_garbage() > self.config.blob_gc_threshold
+            && !self.history.read().unwrap().is_enabled()
+        {
+            self.gc_blobs()?;
+        }
+        Ok(())
+    }
+
+    ///append the value to the active blob file, starting a new one when it is full
+    fn write_blob(&mut self, value: &[u8]) -> Result<BlobPos> {
+        let full = match &self.blob_writer {
+            Some(writer) => writer.pos >= BLOB_FILE_SIZE,
+            None => true,
+        };
+        if full {
+            self.finish_blob_file()?;
+            self.current_blob += 1;
+            self.blob_writer = Some(new_blob_file(&*self.storage, self.current_blob)?);
+        }
+
+        let writer = self.blob_writer.as_mut().unwrap();
+        let pos = writer.pos;
+        writer.write_all(value)?;
+        writer.flush()?;
+        Ok(BlobPos {
+            id: self.current_blob,
+            pos,
+            len: value.len() as u64,
+        })
+    }
+
+    ///sync the active blob file and stop writing to it,
+    ///`sync` only reaches the active one
+    fn finish_blob_file(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.blob_writer.take() {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    ///flush and fsync the active files, then the directory listing them
+    fn sync(&mut self) -> Result<()> {
+        self.writer.sync()?;
+        if let Some(writer) = &mut self.blob_writer {
+            writer.sync()?;
+        }
+        self.storage.sync()?;
+        Ok(())
+    }
+
+    ///move the live values out of every blob file holding garbage
+    ///and delete those files, log compaction never touches blob files
+    fn gc_blobs(&mut self) -> Result<()> {
+        //live values go to a fresh blob file, so every dirty file can be deleted
+        self.finish_blob_file()?;
+
+        for id in self.blobs.dirty_files() {
+            for (key, blob) in self.blobs.live_in(id) {
+                let value = self.reader.read_blob(blob)?;
+                let new_blob = self.write_blob(&value)?;
+                //a moved value is still the same write
+                let (seq, time) = match self.index.get(&key, &self.reader.key_at())? {
+                    Some(cmd_pos) => self.reader.read_command(cmd_pos)?.stamp(),
+                    None => self.history.write().unwrap().stamp(),
+                };
+                let cmd = Command::set_blob(key, new_blob, seq, time);
+                let pos = self.writer.pos;
+                serde_json::to_writer(&mut self.writer, &cmd)?;
+                self.writer.flush()?;
+
+                if let Command::SetBlob { key, .. } = cmd {
+                    self.blobs.replace(&key, Some(new_blob));
+                    let new_pos = (self.current_gen, pos..self.writer.pos).into();
+                    if let Some(old_pos) = self.index.insert(key, new_pos, &self.reader.key_at())? {
+                        self.uncompacted += old_pos.len;
+                    }
+                }
+            }
+
+            self.reader.blob_epoch.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = self.storage.remove(&blob_name(id)) {
+                error!("{} cannot be deleted: {}", blob_name(id), e);
+            }
+            self.blobs.forget(id);
+        }
+
+        Ok(())
+    }
+
+    ///send the event to every watcher whose prefix matches,
+    ///and forget those whose receiver has been dropped
+    fn notify(&mut self, event: Event) {
+        self.watchers.retain(|(prefix, tx)| {
+            !event.key().starts_with(prefix.as_str()) || tx.send(event.clone()).is_ok()
+        });
+    }
+
+    ///taken under the writer lock so the index and the files agree
+    fn stats(&self) -> Result<EngineStats> {
+        let mut live_bytes = 0;
+        self.index.for_each(|cmd_pos| live_bytes += cmd_pos.len);
+
+        let mut generations = Vec::new();
+        for gen in gen_file_list(&*self.storage)? {
+            let size = self.storage.open(&log_name(gen))?.size()?;
+            generations.push(GenerationStats { gen, size });
+        }
+        let mut blob_files = Vec::new();
+        for id in blob_file_list(&*self.storage)? {
+            let size = self.storage.open(&blob_name(id))?.size()?;
+            blob_files.push(GenerationStats { gen: id, size });
+        }
+
+        Ok(EngineStats {
+            key_count: self.index.len() as u64,
+            live_bytes,
+            uncompacted: self.uncompacted,
+            generations,
+            compaction_count: self.compaction_count,
+            compaction_duration: self.compaction_duration,
+            last_compaction: self.last_compaction,
+            blob_files,
+            blob_garbage: self.blobs.total_garbage(),
+        })
+    }
+
+    ///under the writer lock, so compaction cannot move values while we read them
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let mut positions = Vec::with_capacity(self.index.len());
+        self.index.for_each(|cmd_pos| positions.push(cmd_pos));
+        for cmd_pos in positions {
+            let key = self.reader.read_command(cmd_pos)?.into_key();
+            f(key, self.reader.read_value(cmd_pos)?)?;
+        }
+        Ok(())
+    }
+
+    ///remove old file
+    fn compact(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let compaction_gen = self.current_gen + 1;
+        let new_gen = self.current_gen + 2;
+
+        //the new generation is live even if the compaction fails halfway,
+        //it is only written to once it is in the manifest
+        let new_writer = new_log_file(&*self.storage, new_gen)?;
+        let mut gens = self.manifest.gens.clone();
+        gens.push(new_gen);
+        let manifest = Manifest {
+            gens,
+            last_seq: self.history.read().unwrap().last_seq(),
+        };
+        manifest.store(&*self.storage)?;
+        self.writer = new_writer;
+        self.current_gen = new_gen;
+        self.manifest = manifest;
+
+        let mut compaction_writer = new_log_file(&*self.storage, compaction_gen)?;
+
+        let mut new_pos = 0;
+        let reader = &self.reader;
+        let mut copy = |cmd_pos: CommandPos| -> Result<CommandPos> {
+            compaction_writer.write_all(&reader.read_bytes(cmd_pos)?)?;
+            let len = cmd_pos.len;
+
+            let compacted_pos = (compaction_gen, new_pos..new_pos + len).into();
+            new_pos += len;
+            Ok(compacted_pos)
+        };
+
+        //readers only see the moved versions once they are on disk
+        let mut history = self.history.write().unwrap();
+        if history.is_enabled() {
+            //every retained version moves, the latest sets among them
+            history.prune_all();
+            let mut moved = HashMap::new();
+            history.update_all(|cmd_pos| {
+                let compacted_pos = copy(cmd_pos)?;
+                moved.insert(cmd_pos, compacted_pos);
+                Ok(compacted_pos)
+            })?;
+            self.index.update_all(|cmd_pos| {
+                moved.get(&cmd_pos).copied().ok_or_else(|| {
+                    KvsError::StringError(format!(
+                        "{:?} is in the index but has no version in the history",
+                        cmd_pos
+                    ))
+                })
+            })?;
+        } else {
+            self.index.update_all(copy)?;
+        }
+        compaction_writer.sync()?;
+        let last_seq = history.last_seq();
+        drop(history);
+        //from now on the store is the compacted generation and the new one
+        self.manifest = Manifest {
+            gens: vec![compaction_gen, self.current_gen],
+            last_seq,
+        };
+        self.manifest.store(&*self.storage)?;
+
+        //顺序一致性的更新safe_point
+        self.reader
+            .safe_point
+            .store(compaction_gen, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        let stale_gens = gen_file_list(&*self.storage)?
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen);
+
+        for stale_gen in stale_gens {
+            if let Err(e) = self.storage.remove(&log_name(stale_gen)) {
+                error!("{} cannot be deleted: {}", log_name(stale_gen), e);
+            }
+        }
+        self.uncompacted = 0;
+
+        self.compaction_count += 1;
+        self.compaction_duration += start.elapsed();
+        self.last_compaction = Some(SystemTime::now());
+
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    ///the last clone is gone, `close` already synced a closed store
+    fn drop(&mut self) {
+        if !self.closed.load(Ordering::SeqCst) {
+            if let Err(e) = self.sync() {
+                error!("cannot sync the store on drop: {}", e);
+            }
+        }
+    }
+}
+
+//not interface function
+
+fn load(
+    gen: u64,
+    reader: SequentialReader,
+    index: &Index,
+    resolve: KeyResolver,
+    blobs: &mut BlobIndex,
+    history: &mut History,
+) -> Result<u64> {
+    let mut pos = 0;
+    let mut stream = Deserializer::from_reader(BufReader::new(reader)).into_iter::<Command>();
+    let mut uncompacted: u64 = 0;
+
+    while let Some(cmd) = stream.next() {
+        let new_pos = stream.byte_offset() as u64;
+        let cmd = match cmd {
+            //a write torn by a crash, nothing was written after it
+            Err(ref e) if e.is_eof() => {
+                warn!("{} ends with a torn command at {}", log_name(gen), pos);
+                break;
+            }
+            cmd => cmd?,
+        };
+        let (seq, time) = cmd.stamp();
+        let cmd_pos = (gen, pos..new_pos).into();
+        let (key, removed) = match cmd {
+            Command::Set { key, .. } => {
+                blobs.replace(&key, None);
+                (key, false)
+            }
+            Command::SetBlob { key, blob, .. } => {
+                blobs.replace(&key, Some(blob));
+                (key, false)
+            }
+            Command::Remove { key, .. } => {
+                blobs.replace(&key, None);
+                (key, true)
+            }
+        };
+        let version = VersionPos {
+            seq,
+            time,
+            pos: cmd_pos,
+            removed,
+        };
+        let superseded = if removed {
+            index.remove(&key, resolve)?
+        } else {
+            index.insert(key.clone(), cmd_pos, resolve)?
+        };
+        uncompacted += history.push(&key, version, superseded);
+        pos = new_pos;
+    }
+
+    Ok(uncompacted)
+}
+
+fn new_log_file(storage: &dyn Storage, gen: u64) -> Result<BufWriterWithPos> {
+    Ok(BufWriterWithPos::new(storage.create(&log_name(gen))?)?)
+}
+fn log_name(gen: u64) -> String {
+    format!("{}.log", gen)
+}
+
+fn gen_file_list(storage: &dyn Storage) -> Result<Vec<u64>> {
+    Ok(numbered_files(storage, "log")?)
+}
+
+//Command
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+    },
+    ///a value stored in a blob file
+    SetBlob {
+        key: String,
+        blob: BlobPos,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+    },
+    ///commands written before sequence numbers read as sequence number 0
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+    },
+}
+impl Command {
+    fn set(key: String, value: String, seq: u64, time: u64) -> Command {
+        Command::Set {
+            key,
+            value,
+            seq,
+            time,
+        }
+    }
+    fn set_blob(key: String, blob: BlobPos, seq: u64, time: u64) -> Command {
+        Command::SetBlob {
+            key,
+            blob,
+            seq,
+            time,
+        }
+    }
+    fn remove(key: String, seq: u64, time: u64) -> Command {
+        Command::Remove { key, seq, time }
+    }
+    fn into_key(self) -> String {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetBlob { key, .. }
+            | Command::Remove { key, .. } => key,
+        }
+    }
+    ///(sequence number,time) of the write
+    fn stamp(&self) -> (u64, u64) {
+        match *self {
+            Command::Set { seq, time, .. }
+            | Command::SetBlob { seq, time, .. }
+            | Command::Remove { seq, time, .. } => (seq, time),
+        }
+    }
+}
+
+//CommandPos
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CommandPos {
+    gen: u64, //所在文件 file_pos
+    pos: u64, // in_file_pos
+    len: u64, // offset
+}
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
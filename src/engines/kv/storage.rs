@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Where a `KvStore` keeps its files.
+///
+/// Files have flat names like `3.log`. They are only ever appended to,
+/// and replaced as a whole through `rename`.
+pub trait Storage: Send + Sync {
+    /// Creates an empty file, truncating it if it exists.
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>>;
+    /// Opens an existing file, failing with `NotFound` otherwise.
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>>;
+    /// Names of every file.
+    fn list(&self) -> io::Result<Vec<String>>;
+    /// Deletes a file, handles opened before can still read it.
+    fn remove(&self, name: &str) -> io::Result<()>;
+    /// Atomically replaces the file `to` with the file `from`.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Makes created, removed and renamed files durable.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// An open file of a `Storage`.
+pub trait StorageFile: Send {
+    /// Writes at the end of the file.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+    /// Fills `buf` with the bytes at `offset`, failing with `UnexpectedEof` past the end.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Length of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+    /// Makes appended bytes durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Files in a directory of the file system.
+#[derive(Debug, Clone)]
+pub struct OsStorage {
+    dir: PathBuf,
+}
+
+impl OsStorage {
+    /// Keeps files in `dir`, which has to exist.
+    pub fn new(dir: impl Into<PathBuf>) -> OsStorage {
+        OsStorage { dir: dir.into() }
+    }
+}
+
+impl Storage for OsStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.dir.join(name))?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.dir.join(name))?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))
+    }
+
+    #[cfg(unix)]
+    fn sync(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct OsFile(File);
+
+impl StorageFile for OsFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::End(0))?;
+        self.0.write_all(buf)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        self.0.read_exact(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+///the shared content of an in-memory file
+type MemoryData = Arc<RwLock<Vec<u8>>>;
+
+/// Files kept in memory, nothing outlives the process.
+///
+/// Clones share the same files, so a store can be opened again on them.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, MemoryData>>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty `MemoryStorage`.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let data = Arc::new(RwLock::new(Vec::new()));
+        self.files
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), Arc::clone(&data));
+        Ok(Box::new(MemoryFile(data)))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        match self.files.lock().unwrap().get(name) {
+            Some(data) => Ok(Box::new(MemoryFile(Arc::clone(data)))),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryFile(MemoryData);
+
+impl StorageFile for MemoryFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.0.read().unwrap();
+        let start = offset as usize;
+        match data.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the file",
+            )),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", name))
+}
+
+///the whole content of a file
+pub(super) fn read_file(storage: &dyn Storage, name: &str) -> io::Result<Vec<u8>> {
+    let mut file = storage.open(name)?;
+    let mut buf = vec![0; file.size()? as usize];
+    file.read_at(0, &mut buf)?;
+    Ok(buf)
+}
+
+///numbered files with the extension, like the generations in `{gen}.log`, in order
+pub(super) fn numbered_files(storage: &dyn Storage, extension: &str) -> io::Result<Vec<u64>> {
+    let suffix = format!(".{}", extension);
+    let mut id_list: Vec<u64> = storage
+        .list()?
+        .iter()
+        .filter_map(|name| name.strip_suffix(suffix.as_str()))
+        .flat_map(str::parse::<u64>)
+        .collect();
+    id_list.sort_unstable();
+
+    Ok(id_list)
+}
+
+///Writes through a buffer to the end of a storage file,
+///keeping track of where the next write lands.
+pub(super) struct BufWriterWithPos {
+    writer: io::BufWriter<Appender>,
+    pub pos: u64,
+}
+
+impl BufWriterWithPos {
+    pub fn new(file: Box<dyn StorageFile>) -> io::Result<Self> {
+        let pos = file.size()?;
+        Ok(BufWriterWithPos {
+            writer: io::BufWriter::new(Appender(file)),
+            pos,
+        })
+    }
+
+    ///flush and wait until the data is durable
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        (self.writer.get_mut().0).sync()
+    }
+}
+
+impl Write for BufWriterWithPos {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct Appender(Box<dyn StorageFile>);
+
+impl Write for Appender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///Reads a storage file from the start, for replaying it.
+pub(super) struct SequentialReader<'a> {
+    file: &'a mut dyn StorageFile,
+    pos: u64,
+    len: u64,
+}
+
+impl<'a> SequentialReader<'a> {
+    pub fn new(file: &'a mut dyn StorageFile) -> io::Result<Self> {
+        let len = file.size()?;
+        Ok(SequentialReader { file, pos: 0, len })
+    }
+}
+
+impl<'a> Read for SequentialReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.len - self.pos) as usize);
+        self.file.read_at(self.pos, &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
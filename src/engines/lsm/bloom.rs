@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+
+///A bloom filter over the keys of a table, stored in the table itself.
+///It tells for sure when a key is not in the table, so most lookups of
+///missing keys never read a block.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    ///`key_hashes` are the `hash` of every key in the table
+    pub fn new(key_hashes: &[u64]) -> BloomFilter {
+        let len = key_hashes.len() * BITS_PER_KEY / 64 + 1;
+        let mut filter = BloomFilter {
+            bits: vec![0; len],
+            hashes: HASHES,
+        };
+        for &hash in key_hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    ///derive every probe from the two halves of one hash
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let total = (self.bits.len() * 64) as u64;
+        let low = hash & 0xffff_ffff;
+        let high = hash >> 32;
+        (0..u64::from(self.hashes))
+            .map(move |i| (low.wrapping_add(i.wrapping_mul(high)) % total) as usize)
+    }
+}
+
+///64-bit FNV-1a, unlike `DefaultHasher` it is stable across Rust releases,
+///which matters because filters are persisted
+pub(super) fn hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
@@ -0,0 +1,53 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "MANIFEST";
+
+///The tables that make up the store, newest first.
+///
+///A table that is not listed, like one a compaction replaced but could not delete,
+///is not part of the store and gets deleted on open.
+///The manifest is replaced atomically, it is either the old or the new list.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(super) struct Manifest {
+    pub tables: Vec<u64>,
+}
+
+impl Manifest {
+    ///`None` for a store written before manifests existed
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        match fs::read(manifest_path(dir)) {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///write a temporary file, sync it, then rename it over the manifest
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, manifest_path(dir))?;
+        sync_dir(dir)?;
+        Ok(())
+    }
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
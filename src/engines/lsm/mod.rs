@@ -0,0 +1,595 @@
+use self::manifest::Manifest;
+use self::sstable::{table_path, Table, TableBuilder};
+use super::{check_size, EngineStats, Event, GenerationStats, KvsEngine, Watcher};
+use crate::{KvsError, Result, StoreMeta};
+use crossbeam::channel::{self, Sender};
+use crossbeam_skiplist::SkipMap;
+use log::{error, warn};
+use serde_json::Deserializer;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+mod bloom;
+mod manifest;
+mod sstable;
+
+///(key,value) as stored in the log and in tables, `None` is a tombstone
+type Entry = (String, Option<String>);
+type MemTable = SkipMap<String, Option<String>>;
+
+/// Options for opening a `LsmKvsEngine`
+#[derive(Debug, Clone)]
+pub struct LsmConfig {
+    /// The memtable is written out as a table once its entries take this many bytes
+    pub memtable_size: u64,
+    /// All tables are merged into one once there are more than this many
+    pub max_tables: usize,
+    /// Sets with a longer key fail with `KvsError::KeyTooLarge`, `None` accepts any key
+    pub max_key_size: Option<u64>,
+    /// Sets with a longer value fail with `KvsError::ValueTooLarge`, `None` accepts any value
+    pub max_value_size: Option<u64>,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        LsmConfig {
+            memtable_size: 4 * 1024 * 1024,
+            max_tables: 4,
+            max_key_size: None,
+            max_value_size: None,
+        }
+    }
+}
+
+/// A log-structured merge tree.
+///
+/// Writes go to a write-ahead log and a memtable, full memtables are written
+/// out as immutable sorted tables, and once there are more than `max_tables`
+/// tables all of them are merged into one. A manifest lists the tables in use.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    state: Arc<RwLock<Arc<State>>>,
+    writer: Arc<Mutex<LsmWriter>>,
+}
+
+///What readers look at. The writer replaces it as a whole when
+///the memtable or the tables change, readers keep using their copy meanwhile.
+struct State {
+    mem: Arc<MemTable>,
+    ///the memtable being written out as a table
+    imm: Option<Arc<MemTable>>,
+    ///newest first
+    tables: Vec<Arc<Table>>,
+}
+
+impl LsmKvsEngine {
+    /// Opens a `LsmKvsEngine` with the given path
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_config(path, LsmConfig::default())
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and options
+    pub fn open_with_config(path: impl Into<PathBuf>, config: LsmConfig) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        StoreMeta::check(&path, "lsm")?;
+
+        //tables not finished before a crash
+        for id in file_list(&path, "tmp")? {
+            fs::remove_file(path.join(format!("{}.sst.tmp", id)))?;
+        }
+
+        //newest first, a store older than the manifest is made of every table
+        let table_ids = match Manifest::load(&path)? {
+            Some(manifest) => {
+                for id in file_list(&path, "sst")? {
+                    if !manifest.tables.contains(&id) {
+                        warn!(
+                            "{:?} is not in the manifest, deleting it",
+                            table_path(&path, id)
+                        );
+                        fs::remove_file(table_path(&path, id))?;
+                    }
+                }
+                manifest.tables
+            }
+            None => file_list(&path, "sst")?.into_iter().rev().collect(),
+        };
+        let wal_ids = file_list(&path, "wal")?;
+        let mut next_id = table_ids
+            .iter()
+            .chain(&wal_ids)
+            .max()
+            .map_or(1, |id| id + 1);
+
+        let mut tables = Vec::new();
+        for &id in &table_ids {
+            tables.push(Arc::new(Table::open(&path, id)?));
+        }
+
+        //whatever the logs hold goes into a table right away,
+        //so we always start with an empty memtable and a new log
+        let mem = MemTable::new();
+        for &id in &wal_ids {
+            replay_wal(&path, id, &mem)?;
+        }
+        if !mem.is_empty() {
+            tables.insert(0, Arc::new(write_table(&path, next_id, &mem)?));
+            next_id += 1;
+        }
+        store_manifest(&path, &tables)?;
+        for &id in &wal_ids {
+            fs::remove_file(wal_path(&path, id))?;
+        }
+
+        let wal_id = next_id;
+        let state = Arc::new(RwLock::new(Arc::new(State {
+            mem: Arc::new(MemTable::new()),
+            imm: None,
+            tables,
+        })));
+        let writer = LsmWriter {
+            wal: new_wal(&path, wal_id)?,
+            wal_id,
+            next_id: wal_id + 1,
+            mem_size: 0,
+            path,
+            config,
+            state: Arc::clone(&state),
+            watchers: Vec::new(),
+            compaction_count: 0,
+            compaction_duration: Duration::default(),
+            last_compaction: None,
+        };
+
+        Ok(LsmKvsEngine {
+            state,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_many(vec![(key, value)])
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let state = Arc::clone(&self.state.read().unwrap());
+        state.get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        //only the writer adds keys, so the key cannot come back before our tombstone
+        let state = Arc::clone(&self.state.read().unwrap());
+        if state.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        writer.write(vec![(key, None)])
+    }
+
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let state = Arc::clone(&self.state.read().unwrap());
+        for entry in state.merged() {
+            if let (key, Some(value)) = entry? {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///the tables are synced when written, only the log may be behind
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.wal.flush()?;
+        writer.wal.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    ///merges the tables, the memtable stays until it is full
+    fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.state.read().unwrap().tables.len() > 1 {
+            writer.compact()?;
+        }
+        Ok(())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let state = Arc::clone(&self.state.read().unwrap());
+        keys.iter().map(|key| state.get(key)).collect()
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        //a batch with one pair too large writes nothing
+        for (key, value) in &pairs {
+            check_size(
+                key,
+                value,
+                writer.config.max_key_size,
+                writer.config.max_value_size,
+            )?;
+        }
+        let entries = pairs
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        writer.write(entries)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut writer = self.writer.lock().unwrap();
+        let state = Arc::clone(&self.state.read().unwrap());
+        let mut removing = HashSet::new();
+        let mut tombstones = Vec::new();
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            let exists = !removing.contains(&key) && state.get(&key)?.is_some();
+            if exists {
+                removing.insert(key.clone());
+                tombstones.push((key, None));
+            }
+            found.push(exists);
+        }
+        writer.write(tombstones)?;
+        Ok(found)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let (tx, rx) = channel::unbounded();
+        self.writer.lock().unwrap().watchers.push((prefix, tx));
+        Ok(Watcher::from_channel(rx))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let writer = self.writer.lock().unwrap();
+        let state = Arc::clone(&self.state.read().unwrap());
+
+        let mut key_count = 0;
+        for entry in state.merged() {
+            if entry?.1.is_some() {
+                key_count += 1;
+            }
+        }
+        let generations: Vec<GenerationStats> = state
+            .tables
+            .iter()
+            .rev()
+            .map(|table| GenerationStats {
+                gen: table.id(),
+                size: table.size(),
+            })
+            .collect();
+        let table_bytes: u64 = generations.iter().map(|table| table.size).sum();
+        //everything but the oldest table is waiting to be merged into it
+        let uncompacted = table_bytes - generations.first().map_or(0, |table| table.size);
+
+        Ok(EngineStats {
+            key_count,
+            live_bytes: table_bytes + writer.mem_size,
+            uncompacted,
+            generations,
+            compaction_count: writer.compaction_count,
+            compaction_duration: writer.compaction_duration,
+            last_compaction: writer.last_compaction,
+            ..EngineStats::default()
+        })
+    }
+}
+
+impl State {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        for mem in Some(&self.mem).into_iter().chain(&self.imm) {
+            if let Some(entry) = mem.get(key) {
+                return Ok(entry.value().clone());
+            }
+        }
+        for table in &self.tables {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    ///every entry, newest version only, in key order
+    fn merged(&self) -> Merge<'_> {
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + '_>> = Vec::new();
+        for mem in Some(&self.mem).into_iter().chain(&self.imm) {
+            sources
+                .push(Box::new(mem.iter().map(|entry| {
+                    Ok((entry.key().clone(), entry.value().clone()))
+                })));
+        }
+        for table in &self.tables {
+            sources.push(Box::new(table.iter()));
+        }
+        Merge::new(sources)
+    }
+}
+
+struct LsmWriter {
+    path: PathBuf,
+    config: LsmConfig,
+    state: Arc<RwLock<Arc<State>>>,
+    wal: BufWriter<File>,
+    wal_id: u64,
+    ///next id of a log or table file
+    next_id: u64,
+    ///bytes of the entries in the memtable
+    mem_size: u64,
+    ///(prefix,sender) of every live subscription
+    watchers: Vec<(String, Sender<Event>)>,
+    //compaction statistics since open
+    compaction_count: u64,
+    compaction_duration: Duration,
+    last_compaction: Option<SystemTime>,
+}
+
+impl LsmWriter {
+    ///log the entries with one flush, then make them visible in the memtable in order
+    fn write(&mut self, entries: Vec<(String, Option<String>)>) -> Result<()> {
+        for (key, value) in &entries {
+            serde_json::to_writer(&mut self.wal, &(key, value))?;
+        }
+        self.wal.flush()?;
+
+        let mem = Arc::clone(&self.state.read().unwrap().mem);
+        for (key, value) in entries {
+            self.mem_size += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+            if !self.watchers.is_empty() {
+                let event = match &value {
+                    Some(value) => Event::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                    None => Event::Remove { key: key.clone() },
+                };
+                self.notify(event);
+            }
+            mem.insert(key, value);
+        }
+
+        if self.mem_size > self.config.memtable_size {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    ///send the event to every watcher whose prefix matches,
+    ///and forget those whose receiver has been dropped
+    fn notify(&mut self, event: Event) {
+        self.watchers.retain(|(prefix, tx)| {
+            !event.key().starts_with(prefix.as_str()) || tx.send(event.clone()).is_ok()
+        });
+    }
+
+    ///write the memtable out as a table and start a new log
+    fn flush_memtable(&mut self) -> Result<()> {
+        let old_wal_id = self.wal_id;
+        self.wal_id = self.next_id;
+        self.wal = new_wal(&self.path, self.wal_id)?;
+        let table_id = self.wal_id + 1;
+        self.next_id += 2;
+
+        let imm = {
+            let mut state = self.state.write().unwrap();
+            let imm = Arc::clone(&state.mem);
+            *state = Arc::new(State {
+                mem: Arc::new(MemTable::new()),
+                imm: Some(Arc::clone(&imm)),
+                tables: state.tables.clone(),
+            });
+            imm
+        };
+        self.mem_size = 0;
+
+        let table = Arc::new(write_table(&self.path, table_id, &imm)?);
+        //only the writer changes the tables, so they stay the same meanwhile
+        let mut tables = vec![table];
+        tables.extend(self.state.read().unwrap().tables.iter().cloned());
+        store_manifest(&self.path, &tables)?;
+        {
+            let mut state = self.state.write().unwrap();
+            *state = Arc::new(State {
+                mem: Arc::clone(&state.mem),
+                imm: None,
+                tables,
+            });
+        }
+        remove_file(&wal_path(&self.path, old_wal_id));
+
+        if self.state.read().unwrap().tables.len() > self.config.max_tables {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    ///merge every table into one, dropping overwritten values and tombstones
+    fn compact(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let id = self.next_id;
+        self.next_id += 1;
+
+        //only the writer changes the tables, so they stay the same meanwhile
+        let old_tables = self.state.read().unwrap().tables.clone();
+        let mut builder = TableBuilder::new(&self.path, id)?;
+        let sources = old_tables
+            .iter()
+            .map(|table| Box::new(table.iter()) as Box<dyn Iterator<Item = Result<Entry>>>)
+            .collect();
+        for entry in Merge::new(sources) {
+            if let (key, Some(value)) = entry? {
+                builder.add(&key, Some(&value))?;
+            }
+        }
+        let table = Arc::new(builder.finish()?);
+        //the old tables, tombstones included, are no longer part of the store
+        //even if deleting them fails
+        store_manifest(&self.path, &[Arc::clone(&table)])?;
+
+        {
+            let mut state = self.state.write().unwrap();
+            *state = Arc::new(State {
+                mem: Arc::clone(&state.mem),
+                imm: state.imm.clone(),
+                tables: vec![table],
+            });
+        }
+        for table in old_tables {
+            remove_file(&table_path(&self.path, table.id()));
+        }
+
+        self.compaction_count += 1;
+        self.compaction_duration += start.elapsed();
+        self.last_compaction = Some(SystemTime::now());
+        Ok(())
+    }
+}
+
+///Merges sorted sources into one sorted stream.
+///When several sources hold a key, the one listed first wins.
+struct Merge<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>) -> Merge<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+        Merge {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        if !self.started {
+            self.started = true;
+            for source in 0..self.sources.len() {
+                self.advance(source)?;
+            }
+        }
+
+        let mut min: Option<usize> = None;
+        for (source, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                let smaller = match min {
+                    Some(min) => key < &self.heads[min].as_ref().unwrap().0,
+                    None => true,
+                };
+                if smaller {
+                    min = Some(source);
+                }
+            }
+        }
+        let min = match min {
+            Some(min) => min,
+            None => return Ok(None),
+        };
+
+        let entry = self.heads[min].take().unwrap();
+        self.advance(min)?;
+        //older versions of the key
+        for source in min + 1..self.heads.len() {
+            if self.heads[source].as_ref().map(|(key, _)| key) == Some(&entry.0) {
+                self.advance(source)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        self.next_entry().transpose()
+    }
+}
+
+fn write_table(dir: &Path, id: u64, mem: &MemTable) -> Result<Table> {
+    let mut builder = TableBuilder::new(dir, id)?;
+    for entry in mem.iter() {
+        builder.add(entry.key(), entry.value().as_deref())?;
+    }
+    builder.finish()
+}
+
+fn replay_wal(dir: &Path, id: u64, mem: &MemTable) -> Result<()> {
+    let reader = BufReader::new(File::open(wal_path(dir, id))?);
+    for entry in Deserializer::from_reader(reader).into_iter::<Entry>() {
+        match entry {
+            Ok((key, value)) => {
+                mem.insert(key, value);
+            }
+            //the last record may be cut short by a crash
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn store_manifest(dir: &Path, tables: &[Arc<Table>]) -> Result<()> {
+    Manifest {
+        tables: tables.iter().map(|table| table.id()).collect(),
+    }
+    .store(dir)
+}
+
+fn new_wal(dir: &Path, id: u64) -> Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?,
+    ))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!("{:?} cannot be deleted: {}", path, e);
+    }
+}
+
+///ids of the files with the extension, ascending
+fn file_list(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut id_list: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.split('.').next())
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    id_list.sort_unstable();
+
+    Ok(id_list)
+}
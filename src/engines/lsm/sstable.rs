@@ -0,0 +1,231 @@
+use super::bloom::{self, BloomFilter};
+use super::Entry;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::vec;
+
+///a block is closed once it holds this many bytes
+const BLOCK_SIZE: usize = 4 * 1024;
+///offset and length of the meta block, both little endian u64
+const FOOTER_SIZE: u64 = 16;
+
+///An immutable sorted table.
+///
+///Layout: data blocks of JSON `[key,value]` entries sorted by key,
+///a JSON meta block with the block index and the bloom filter,
+///then the footer locating the meta block.
+///A `null` value is a tombstone.
+pub(super) struct Table {
+    id: u64,
+    size: u64,
+    file: Mutex<File>,
+    meta: TableMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableMeta {
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    ///the biggest key of the block
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+impl Table {
+    pub fn open(dir: &Path, id: u64) -> Result<Table> {
+        let mut file = File::open(table_path(dir, id))?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE {
+            return Err(KvsError::StringError(format!("table {} is truncated", id)));
+        }
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        let meta_offset = u64_from_le(&footer[..8]);
+        let meta_len = u64_from_le(&footer[8..]);
+
+        file.seek(SeekFrom::Start(meta_offset))?;
+        let meta = serde_json::from_reader((&mut file).take(meta_len))?;
+
+        Ok(Table {
+            id,
+            size,
+            file: Mutex::new(file),
+            meta,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    ///`Some(None)` means the table holds a tombstone for the key
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if !self.meta.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .meta
+            .blocks
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        if block == self.meta.blocks.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value))
+    }
+
+    ///every entry in key order
+    pub fn iter(&self) -> TableIter<'_> {
+        TableIter {
+            table: self,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.blocks[block];
+        let mut buf = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        Ok(Deserializer::from_slice(&buf)
+            .into_iter::<Entry>()
+            .collect::<serde_json::Result<_>>()?)
+    }
+}
+
+pub(super) struct TableIter<'a> {
+    table: &'a Table,
+    next_block: usize,
+    entries: vec::IntoIter<Entry>,
+}
+
+impl<'a> Iterator for TableIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block == self.table.meta.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+///Writes a table from entries added in key order.
+///The table only shows up under its name once it is complete.
+pub(super) struct TableBuilder {
+    dir: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    last_key: String,
+    blocks: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub fn new(dir: &Path, id: u64) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            dir: dir.to_owned(),
+            id,
+            writer: BufWriter::new(File::create(tmp_table_path(dir, id))?),
+            offset: 0,
+            block: Vec::new(),
+            last_key: String::new(),
+            blocks: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        serde_json::to_writer(&mut self.block, &(key, value))?;
+        self.key_hashes.push(bloom::hash(key));
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Table> {
+        if !self.block.is_empty() {
+            self.finish_block()?;
+        }
+        let meta = TableMeta {
+            blocks: self.blocks,
+            bloom: BloomFilter::new(&self.key_hashes),
+        };
+        let meta = serde_json::to_vec(&meta)?;
+        self.writer.write_all(&meta)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&(meta.len() as u64).to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        fs::rename(
+            tmp_table_path(&self.dir, self.id),
+            table_path(&self.dir, self.id),
+        )?;
+        Table::open(&self.dir, self.id)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn tmp_table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst.tmp", id))
+}
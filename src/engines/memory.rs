@@ -0,0 +1,210 @@
+use super::{check_size, EngineStats, Event, KvsEngine, Watcher};
+use crate::{KvsError, Result};
+use crossbeam::channel::{self, Sender};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A storage engine keeping everything in memory and nothing on disk.
+///
+/// It can be bounded, then the least recently used keys are evicted
+/// once keys and values take more bytes than the capacity.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine(Arc<Mutex<MemoryInner>>);
+
+#[derive(Default)]
+struct MemoryInner {
+    ///(key,(value,last use))
+    map: HashMap<String, (String, u64)>,
+    ///(last use,key), the least recently used key comes first
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    size: u64,
+    capacity: Option<u64>,
+    max_key_size: Option<u64>,
+    max_value_size: Option<u64>,
+    ///(prefix,sender) of every live subscription
+    watchers: Vec<(String, Sender<Event>)>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an unbounded `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+
+    /// Creates a `MemoryKvsEngine` holding at most `capacity` bytes of keys and values.
+    ///
+    /// A pair taking more than the whole capacity fails with `KvsError::ValueTooLarge`.
+    pub fn with_capacity(capacity: u64) -> Self {
+        MemoryKvsEngine(Arc::new(Mutex::new(MemoryInner {
+            capacity: Some(capacity),
+            ..MemoryInner::default()
+        })))
+    }
+
+    /// Makes sets with a longer key or value fail with `KvsError::KeyTooLarge`
+    /// or `KvsError::ValueTooLarge`, `None` accepts any size.
+    pub fn with_size_limits(self, max_key_size: Option<u64>, max_value_size: Option<u64>) -> Self {
+        {
+            let mut inner = self.0.lock().unwrap();
+            inner.max_key_size = max_key_size;
+            inner.max_value_size = max_value_size;
+        }
+        self
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.check_size(&key, &value)?;
+        inner.set(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(&key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.lock().unwrap().remove(key)
+    }
+
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let inner = self.0.lock().unwrap();
+        for (key, (value, _)) in &inner.map {
+            f(key.clone(), value.clone())?;
+        }
+        Ok(())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut inner = self.0.lock().unwrap();
+        Ok(keys.iter().map(|key| inner.get(key)).collect())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        for (key, value) in &pairs {
+            inner.check_size(key, value)?;
+        }
+        for (key, value) in pairs {
+            inner.set(key, value);
+        }
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut inner = self.0.lock().unwrap();
+        Ok(keys
+            .into_iter()
+            .map(|key| inner.remove(key).is_ok())
+            .collect())
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let (tx, rx) = channel::unbounded();
+        self.0.lock().unwrap().watchers.push((prefix, tx));
+        Ok(Watcher::from_channel(rx))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let inner = self.0.lock().unwrap();
+        Ok(EngineStats {
+            key_count: inner.map.len() as u64,
+            live_bytes: inner.size,
+            ..EngineStats::default()
+        })
+    }
+}
+
+impl MemoryInner {
+    ///within the size limits, and small enough not to evict itself
+    fn check_size(&self, key: &str, value: &str) -> Result<()> {
+        check_size(key, value, self.max_key_size, self.max_value_size)?;
+        match self.capacity {
+            Some(capacity) if (key.len() + value.len()) as u64 > capacity => {
+                Err(KvsError::ValueTooLarge {
+                    size: value.len() as u64,
+                    limit: capacity.saturating_sub(key.len() as u64),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) {
+        if let Some((old_value, old_use)) = self.map.remove(&key) {
+            self.lru.remove(&old_use);
+            self.size -= (key.len() + old_value.len()) as u64;
+        }
+        if !self.watchers.is_empty() {
+            self.notify(Event::Set {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+
+        let tick = self.tick();
+        self.size += (key.len() + value.len()) as u64;
+        self.lru.insert(tick, key.clone());
+        self.map.insert(key, (value, tick));
+        self.evict();
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let tick = self.tick();
+        let (value, last_use) = self.map.get_mut(key)?;
+        let key = self.lru.remove(last_use).expect("key missing from lru");
+        *last_use = tick;
+        let value = value.clone();
+        self.lru.insert(tick, key);
+        Some(value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let (value, last_use) = self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        self.lru.remove(&last_use);
+        self.size -= (key.len() + value.len()) as u64;
+        if !self.watchers.is_empty() {
+            self.notify(Event::Remove { key });
+        }
+        Ok(())
+    }
+
+    ///drop the least recently used keys until we fit, watchers see them removed
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.size > capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            let key = self.lru.remove(&oldest).unwrap();
+            let (value, _) = self.map.remove(&key).expect("key missing from map");
+            self.size -= (key.len() + value.len()) as u64;
+            if !self.watchers.is_empty() {
+                self.notify(Event::Remove { key });
+            }
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    ///send the event to every watcher whose prefix matches,
+    ///and forget those whose receiver has been dropped
+    fn notify(&mut self, event: Event) {
+        self.watchers.retain(|(prefix, tx)| {
+            !event.key().starts_with(prefix.as_str()) || tx.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -1,6 +1,12 @@
-use crate::Result;
+use crate::{KvsError, Result};
+mod async_engine;
 mod kv;
+mod lsm;
+mod memory;
 mod sled;
+mod stats;
+mod version;
+mod watch;
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
@@ -8,7 +14,112 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Gets the values of several keys, in the order of `keys`.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Sets several keys, in order, so a later pair wins over an earlier one with the same key.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Removes several keys, telling for each of them whether it was there.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in keys {
+            removed.push(match self.remove(key) {
+                Ok(()) => true,
+                Err(KvsError::KeyNotFound) => false,
+                Err(e) => return Err(e),
+            });
+        }
+        Ok(removed)
+    }
+
+    /// Subscribes to every later set or remove of a key starting with `prefix`.
+    ///
+    /// An empty prefix watches the whole store.
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+
+    /// Returns a snapshot of the engine's size and compaction state.
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Calls `f` with every live key and its value, in no particular order,
+    /// stopping at the first error.
+    ///
+    /// Engines may hold writes back meanwhile, so `f` must not call back into the engine.
+    fn for_each<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+
+    /// Waits until every write made so far is durable on disk.
+    ///
+    /// Engines keeping nothing on disk have nothing to do.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Compacts the engine's data now rather than when enough of it is stale.
+    ///
+    /// Engines compacting on their own, or keeping nothing on disk, have nothing to do.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gets the value the key held right after the write with sequence number `seq`.
+    ///
+    /// Versions older than the engine retains read as `None`.
+    fn get_at(&self, _key: String, _seq: u64) -> Result<Option<String>> {
+        Err(KvsError::StringError(
+            "this engine does not keep versions".to_owned(),
+        ))
+    }
+
+    /// Returns the versions of the key the engine retains, oldest first.
+    fn history(&self, _key: String) -> Result<Vec<Version>> {
+        Err(KvsError::StringError(
+            "this engine does not keep versions".to_owned(),
+        ))
+    }
+}
+
+///fails on a key or a value over its limit, `None` accepting any size
+fn check_size(
+    key: &str,
+    value: &str,
+    max_key_size: Option<u64>,
+    max_value_size: Option<u64>,
+) -> Result<()> {
+    match max_key_size {
+        Some(limit) if key.len() as u64 > limit => {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len() as u64,
+                limit,
+            })
+        }
+        _ => {}
+    }
+    match max_value_size {
+        Some(limit) if value.len() as u64 > limit => Err(KvsError::ValueTooLarge {
+            size: value.len() as u64,
+            limit,
+        }),
+        _ => Ok(()),
+    }
 }
 
-pub use self::kv::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::kv::{
+    KvStore, KvStoreConfig, MemoryStorage, OsStorage, Retention, Storage, StorageFile,
+};
+pub use self::lsm::{LsmConfig, LsmKvsEngine};
+pub use self::memory::MemoryKvsEngine;
+pub use self::sled::{FlushPolicy, SledConfig, SledKvsEngine, SledTransaction};
+pub use self::stats::{EngineStats, GenerationStats};
+pub use self::version::Version;
+pub use self::watch::{Event, Watcher};
@@ -1,27 +1,175 @@
-use super::KvsEngine;
-use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use super::{check_size, EngineStats, KvsEngine, Watcher};
+use crate::{KvsError, Result, StoreMeta};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{Batch, Db, Mode, Tree};
+use std::path::Path;
+use std::time::Duration;
+
+/// When a `SledKvsEngine` makes its writes durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Every write is flushed before it returns
+    EveryWrite,
+    /// sled flushes in the background this often, a crash loses the writes since
+    Periodic(Duration),
+    /// Writes are only flushed by `SledKvsEngine::flush`
+    Manual,
+}
+
+/// Options for opening a `SledKvsEngine`
+#[derive(Debug, Clone)]
+pub struct SledConfig {
+    /// When writes are flushed
+    pub flush: FlushPolicy,
+    /// Bytes of cache, sled's default when `None`
+    pub cache_capacity: Option<u64>,
+    /// zstd compression level from 1 to 22, no compression when `None`.
+    /// sled refuses to change it for an existing store.
+    pub compression: Option<i32>,
+    /// Whether sled favours disk space or write throughput
+    pub mode: Mode,
+    /// Tree the engine uses as its keyspace, the default tree when `None`
+    pub tree: Option<String>,
+    /// Sets with a longer key fail with `KvsError::KeyTooLarge`, `None` accepts any key
+    pub max_key_size: Option<u64>,
+    /// Sets with a longer value fail with `KvsError::ValueTooLarge`, `None` accepts any value
+    pub max_value_size: Option<u64>,
+}
+
+impl Default for SledConfig {
+    fn default() -> Self {
+        SledConfig {
+            flush: FlushPolicy::EveryWrite,
+            cache_capacity: None,
+            compression: None,
+            mode: Mode::LowSpace,
+            tree: None,
+            max_key_size: None,
+            max_value_size: None,
+        }
+    }
+}
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    tree: Tree,
+    flush: FlushPolicy,
+    max_key_size: Option<u64>,
+    max_value_size: Option<u64>,
+}
+
 impl SledKvsEngine {
-    /// Creates a `SledKvsEngine` from `sled::Db`.
+    /// Creates a `SledKvsEngine` on the default tree of `sled::Db`, flushing every write.
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        let tree = Tree::clone(&db);
+        SledKvsEngine {
+            db,
+            tree,
+            flush: FlushPolicy::EveryWrite,
+            max_key_size: None,
+            max_value_size: None,
+        }
+    }
+
+    /// Opens a sled database in `path` with the given options.
+    pub fn open_with_config(path: impl AsRef<Path>, config: SledConfig) -> Result<Self> {
+        let path = path.as_ref();
+        StoreMeta::check(path, "sled")?;
+        let flush_every_ms = match config.flush {
+            FlushPolicy::Periodic(every) => Some((every.as_millis() as u64).max(1)),
+            FlushPolicy::EveryWrite | FlushPolicy::Manual => None,
+        };
+        let mut sled_config = sled::Config::new()
+            .path(path)
+            .mode(config.mode)
+            .flush_every_ms(flush_every_ms);
+        if let Some(capacity) = config.cache_capacity {
+            sled_config = sled_config.cache_capacity(capacity);
+        }
+        if let Some(level) = config.compression {
+            sled_config = sled_config.use_compression(true).compression_factor(level);
+        }
+        let engine = SledKvsEngine {
+            flush: config.flush,
+            max_key_size: config.max_key_size,
+            max_value_size: config.max_value_size,
+            ..SledKvsEngine::new(sled_config.open()?)
+        };
+        match config.tree {
+            Some(name) => engine.open_tree(&name),
+            None => Ok(engine),
+        }
+    }
+
+    /// Opens the named tree of the same database as a separate keyspace.
+    ///
+    /// The tree shares the database's flush policy and size limits, and is created if missing.
+    pub fn open_tree(&self, name: &str) -> Result<Self> {
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+            ..self.clone()
+        })
+    }
+
+    /// Lists the trees of the database, the default tree included.
+    pub fn tree_names(&self) -> Result<Vec<String>> {
+        self.db
+            .tree_names()
+            .into_iter()
+            .map(|name| Ok(String::from_utf8(name.to_vec())?))
+            .collect()
+    }
+
+    /// Flushes every write made so far to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Runs `f` as one sled transaction on the engine's tree.
+    ///
+    /// sled may run `f` several times when it conflicts with other writers,
+    /// and nothing it writes is applied if it aborts.
+    pub fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&SledTransaction<'_>) -> ConflictableTransactionResult<T, KvsError>,
+    {
+        let result = self
+            .tree
+            .transaction(|tree| f(&SledTransaction { tree, engine: self }))
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        self.flushed()?;
+        Ok(result)
+    }
+
+    fn flushed(&self) -> Result<()> {
+        if self.flush == FlushPolicy::EveryWrite {
+            self.tree.flush()?;
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
+        check_size(&key, &value, self.max_key_size, self.max_value_size)?;
+        let tree = &self.tree;
         tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
+        self.flushed()?;
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
+        let tree = &self.tree;
         Ok(tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
@@ -30,9 +178,113 @@ impl KvsEngine for SledKvsEngine {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
+        let tree = &self.tree;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
+        self.flushed()?;
+        Ok(())
+    }
+
+    /// Writes do not wait for the scan, keys written meanwhile may or may not be seen.
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let tree = &self.tree;
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            f(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Applies every set in one batch and flushes at most once.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &pairs {
+            check_size(key, value, self.max_key_size, self.max_value_size)?;
+        }
+        let tree = &self.tree;
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        tree.apply_batch(batch)?;
+        self.flushed()?;
         Ok(())
     }
+
+    /// Flushes at most once, after every remove.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let tree = &self.tree;
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in keys {
+            removed.push(tree.remove(key)?.is_some());
+        }
+        self.flushed()?;
+        Ok(removed)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let tree = &self.tree;
+        Ok(Watcher::from_sled(tree.watch_prefix(prefix)))
+    }
+
+    /// sled compacts on its own and does not tell live data from garbage,
+    /// so only the key count is known.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            key_count: self.tree.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+}
+
+/// The engine's tree within a transaction, holding strings within the engine's size limits.
+pub struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+    engine: &'a SledKvsEngine,
+}
+
+impl SledTransaction<'_> {
+    /// Gets the value of a key.
+    pub fn get(&self, key: &str) -> ConflictableTransactionResult<Option<String>, KvsError> {
+        let value = self.tree.get(key)?;
+        value.map(|value| to_string(&value)).transpose()
+    }
+
+    /// Sets the value of a key, returning the one it replaced.
+    ///
+    /// A key or value over the size limits aborts the transaction.
+    pub fn insert(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> ConflictableTransactionResult<Option<String>, KvsError> {
+        check_size(
+            key,
+            value,
+            self.engine.max_key_size,
+            self.engine.max_value_size,
+        )
+        .map_err(ConflictableTransactionError::Abort)?;
+        let old = self.tree.insert(key, value)?;
+        old.map(|value| to_string(&value)).transpose()
+    }
+
+    /// Removes a key, returning its value.
+    pub fn remove(&self, key: &str) -> ConflictableTransactionResult<Option<String>, KvsError> {
+        let old = self.tree.remove(key)?;
+        old.map(|value| to_string(&value)).transpose()
+    }
+}
+
+///a value read in a transaction, which aborts if it is not UTF-8
+fn to_string(value: &[u8]) -> ConflictableTransactionResult<String, KvsError> {
+    String::from_utf8(value.to_vec()).map_err(|e| ConflictableTransactionError::Abort(e.into()))
 }
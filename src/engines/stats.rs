@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// A snapshot of the state of a storage engine
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys
+    pub key_count: u64,
+    /// Bytes taken by the live log records
+    pub live_bytes: u64,
+    /// Stale bytes waiting for compaction
+    pub uncompacted: u64,
+    /// Generation files on disk, oldest first
+    pub generations: Vec<GenerationStats>,
+    /// Number of compactions since the engine was opened
+    pub compaction_count: u64,
+    /// Total time spent compacting since the engine was opened
+    pub compaction_duration: Duration,
+    /// When the last compaction finished
+    pub last_compaction: Option<SystemTime>,
+    /// Blob files holding large values, oldest first
+    pub blob_files: Vec<GenerationStats>,
+    /// Blob bytes no key references any more
+    pub blob_garbage: u64,
+}
+
+/// Size of one generation or blob file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationStats {
+    /// Generation or blob file number
+    pub gen: u64,
+    /// File size in bytes
+    pub size: u64,
+}
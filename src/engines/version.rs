@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// One write of a key, as kept by the engine's retention
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// Sequence number of the write, growing with every write to the store
+    pub seq: u64,
+    /// When the write happened
+    pub time: SystemTime,
+    /// The value written, `None` for a removal
+    pub value: Option<String>,
+}
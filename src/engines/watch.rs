@@ -0,0 +1,82 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// A write observed by a `Watcher`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// The key has been set to the value
+    Set { key: String, value: String },
+    /// The key has been removed
+    Remove { key: String },
+}
+
+impl Event {
+    /// The key touched by this event
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } => key,
+            Event::Remove { key } => key,
+        }
+    }
+}
+
+/// An ordered stream of events for keys starting with a prefix.
+///
+/// The stream ends when the engine that created it is dropped or closed.
+pub struct Watcher(WatcherInner);
+
+enum WatcherInner {
+    Channel(Receiver<Event>),
+    Sled(sled::Subscriber),
+}
+
+impl Watcher {
+    pub(crate) fn from_channel(rx: Receiver<Event>) -> Watcher {
+        Watcher(WatcherInner::Channel(rx))
+    }
+
+    pub(crate) fn from_sled(subscriber: sled::Subscriber) -> Watcher {
+        Watcher(WatcherInner::Sled(subscriber))
+    }
+
+    /// Waits for the next event at most `timeout`.
+    pub fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Event, RecvTimeoutError> {
+        match &mut self.0 {
+            WatcherInner::Channel(rx) => rx.recv_timeout(timeout),
+            WatcherInner::Sled(subscriber) => match subscriber.next_timeout(timeout) {
+                Ok(event) => Ok(from_sled_event(event)),
+                Err(mpsc::RecvTimeoutError::Timeout) => Err(RecvTimeoutError::Timeout),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            },
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        match &mut self.0 {
+            WatcherInner::Channel(rx) => rx.recv().ok(),
+            WatcherInner::Sled(subscriber) => subscriber.next().map(from_sled_event),
+        }
+    }
+}
+
+fn from_sled_event(event: sled::Event) -> Event {
+    let to_string = |i_vec: sled::IVec| String::from_utf8_lossy(&i_vec).into_owned();
+    match event {
+        sled::Event::Insert { key, value } => Event::Set {
+            key: to_string(key),
+            value: to_string(value),
+        },
+        sled::Event::Remove { key } => Event::Remove {
+            key: to_string(key),
+        },
+    }
+}
@@ -1,3 +1,4 @@
+use crate::Permission;
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
@@ -11,6 +12,9 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "serde_json error: {}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Binary serialization or deserialization error
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -24,6 +28,92 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// The data directory belongs to another engine
+    #[fail(
+        display = "Wrong engine: the data directory belongs to {}, not {}",
+        found, expected
+    )]
+    WrongEngine {
+        /// the engine opening the directory
+        expected: String,
+        /// the engine recorded in the directory
+        found: String,
+    },
+    /// The data directory has to be upgraded with `kvs-admin upgrade` first
+    #[fail(
+        display = "data directory format version {} is older than {}, run `kvs-admin upgrade`",
+        found, expected
+    )]
+    UpgradeRequired {
+        /// the format version of the directory
+        found: u32,
+        /// the format version of this build
+        expected: u32,
+    },
+    /// The data directory was written by a newer build
+    #[fail(
+        display = "data directory format version {} is newer than {}",
+        found, expected
+    )]
+    UnsupportedFormat {
+        /// the format version of the directory
+        found: u32,
+        /// the format version of this build
+        expected: u32,
+    },
+    /// A key is longer than the store or the server accepts
+    #[fail(display = "key of {} bytes is over the limit of {} bytes", size, limit)]
+    KeyTooLarge {
+        /// the length of the key
+        size: u64,
+        /// the longest key accepted
+        limit: u64,
+    },
+    /// A value is longer than the store or the server accepts
+    #[fail(
+        display = "value of {} bytes is over the limit of {} bytes",
+        size, limit
+    )]
+    ValueTooLarge {
+        /// the length of the value
+        size: u64,
+        /// the longest value accepted
+        limit: u64,
+    },
+    /// A request is larger than the server reads, it closes the connection
+    #[fail(display = "request is over the limit of {} bytes", limit)]
+    RequestTooLarge {
+        /// the most bytes a request may take
+        limit: u64,
+    },
+    /// The store was closed, by this handle or a clone of it
+    #[fail(display = "store closed")]
+    StoreClosed,
+    /// The server requires clients to authenticate before any request
+    #[fail(display = "authentication required")]
+    Unauthenticated,
+    /// The credentials match no account of the server
+    #[fail(display = "invalid credentials")]
+    InvalidCredentials,
+    /// The ACL of the server does not grant the permission the request needs
+    #[fail(
+        display = "permission denied: {} has no {} permission on {}",
+        identity, permission, scope
+    )]
+    PermissionDenied {
+        /// what the connection is authenticated as, `anonymous` if it is not
+        identity: String,
+        /// the permission the request needs
+        permission: Permission,
+        /// the key, the keys or the store the request is on
+        scope: String,
+    },
+    /// TLS configuration or session error
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(#[cause] rustls::Error),
+    /// The peer broke the binary protocol
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -41,6 +131,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -53,5 +149,11 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(err)
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
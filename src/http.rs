@@ -0,0 +1,496 @@
+use crate::auth::Session;
+use crate::{backup, Credentials, KvsEngine, KvsError, Permission, Result, SizeLimits};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str;
+
+///most headers a request may have
+const MAX_HEADERS: usize = 64;
+///most pairs a listing returns, and how many it returns unless asked for fewer
+const MAX_LIST_LIMIT: usize = 1000;
+
+///A request of the HTTP gateway
+pub(crate) struct Request {
+    pub method: String,
+    ///the path and the query
+    pub target: String,
+    pub body: Vec<u8>,
+    ///the connection ends after the response
+    pub close: bool,
+    ///of the `Authorization` header, if it has a scheme we know
+    pub credentials: Option<Credentials>,
+}
+
+///The complete requests at the start of a connection's buffer
+pub(crate) struct Parsed {
+    pub requests: Vec<Request>,
+    ///how many bytes they take
+    pub len: usize,
+    ///what follows them breaks the protocol, the connection is answered and closed
+    pub error: Option<KvsError>,
+}
+
+pub(crate) fn parse_requests(buf: &[u8], limits: &SizeLimits) -> Parsed {
+    let mut parsed = Parsed {
+        requests: Vec::new(),
+        len: 0,
+        error: None,
+    };
+    loop {
+        match parse_request(&buf[parsed.len..], limits) {
+            Ok(Some((req, len))) => {
+                parsed.requests.push(req);
+                parsed.len += len;
+            }
+            Ok(None) => return parsed,
+            Err(e) => {
+                parsed.error = Some(e);
+                return parsed;
+            }
+        }
+    }
+}
+
+///the request at the start of `buf` and how many bytes it takes,
+///`None` until all of it arrived. Bodies need a `Content-Length`.
+fn parse_request(buf: &[u8], limits: &SizeLimits) -> Result<Option<(Request, usize)>> {
+    let too_large = || KvsError::RequestTooLarge {
+        limit: limits.max_request_size,
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let head_len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) if buf.len() as u64 > limits.max_request_size => {
+            return Err(too_large())
+        }
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(KvsError::Protocol(e.to_string())),
+    };
+    //HTTP/1.0 connections end after a response unless kept alive
+    let mut close = req.version == Some(0);
+    let mut body_len = 0;
+    let mut credentials = None;
+    for header in req.headers.iter() {
+        let value = String::from_utf8_lossy(header.value).to_ascii_lowercase();
+        if header.name.eq_ignore_ascii_case("content-length") {
+            body_len = value
+                .trim()
+                .parse()
+                .map_err(|_| KvsError::Protocol("invalid Content-Length".to_owned()))?;
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(KvsError::Protocol(
+                "bodies need a Content-Length, not a Transfer-Encoding".to_owned(),
+            ));
+        } else if header.name.eq_ignore_ascii_case("authorization") {
+            credentials = parse_authorization(&String::from_utf8_lossy(header.value));
+        } else if header.name.eq_ignore_ascii_case("connection") {
+            if value.contains("close") {
+                close = true;
+            } else if value.contains("keep-alive") {
+                close = false;
+            }
+        }
+    }
+    if (head_len as u64).saturating_add(body_len) > limits.max_request_size {
+        return Err(too_large());
+    }
+    let len = head_len + body_len as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some((
+        Request {
+            method: req.method.unwrap_or_default().to_owned(),
+            target: req.path.unwrap_or_default().to_owned(),
+            body: buf[head_len..len].to_vec(),
+            close,
+            credentials,
+        },
+        len,
+    )))
+}
+
+///`Bearer TOKEN` or `Basic` with a base64 `USER:PASSWORD`
+fn parse_authorization(value: &str) -> Option<Credentials> {
+    let (scheme, param) = value.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Token(param.trim().to_owned()))
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(STANDARD.decode(param.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    } else {
+        None
+    }
+}
+
+///the encoded answer to a request breaking the protocol, the connection is closed after it
+pub(crate) fn protocol_error(e: &KvsError) -> Vec<u8> {
+    let status = match e {
+        KvsError::RequestTooLarge { .. } => 413,
+        _ => 400,
+    };
+    Response::error(status, e).encode(true)
+}
+
+///Runs the request on the engine and encodes the response.
+///
+///`GET`, `PUT` and `DELETE` on `/keys/{key}` read, write and remove a key,
+///`GET /keys` lists keys in order, and `/admin/stats`, `/admin/compact`
+///and `/admin/backup` give the engine's statistics, compact it and back it up
+///into `backup_dir`, without which there are no backups.
+///Every request authenticates on its own when the session has accounts,
+///and `/admin` needs an account, with the `admin` permission when there is an ACL.
+pub(crate) fn respond<E: KvsEngine>(
+    engine: &E,
+    limits: &SizeLimits,
+    session: &Session,
+    backup_dir: Option<&Path>,
+    req: &Request,
+) -> Vec<u8> {
+    let mut session = session.clone();
+    session
+        .check()
+        .or_else(|e| match &req.credentials {
+            Some(credentials) => session.authenticate(credentials).map(drop),
+            None => Err(e),
+        })
+        .and_then(|()| route(engine, limits, &session, backup_dir, req))
+        .unwrap_or_else(|e| Response::from_error(&e))
+        .encode(req.close)
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Listing {
+    pairs: Vec<Pair>,
+    ///where the next page starts, `None` on the last one
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackupBody {
+    dir: String,
+}
+
+#[derive(Serialize)]
+struct BackupResult {
+    dir: String,
+    keys: u64,
+    ///as hex, a u64 does not fit in every JSON number
+    checksum: String,
+}
+
+fn route<E: KvsEngine>(
+    engine: &E,
+    limits: &SizeLimits,
+    session: &Session,
+    backup_dir: Option<&Path>,
+    req: &Request,
+) -> Result<Response> {
+    let (path, query) = match req.target.find('?') {
+        Some(pos) => (&req.target[..pos], &req.target[pos + 1..]),
+        None => (req.target.as_str(), ""),
+    };
+    let method = req.method.as_str();
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = decode(key, false)?;
+        return match method {
+            "GET" => {
+                limits.check_key(&key)?;
+                session.permit(Permission::Read, &key)?;
+                match engine.get(key.clone())? {
+                    Some(value) => Response::json(200, &Pair { key, value }),
+                    None => Err(KvsError::KeyNotFound),
+                }
+            }
+            "PUT" => {
+                let body: PutBody = parse_body(&req.body)?;
+                limits.check_pair(&key, &body.value)?;
+                session.permit(Permission::Write, &key)?;
+                engine.set(key, body.value)?;
+                Ok(Response::empty(204))
+            }
+            "DELETE" => {
+                limits.check_key(&key)?;
+                session.permit(Permission::Delete, &key)?;
+                engine.remove(key)?;
+                Ok(Response::empty(204))
+            }
+            _ => Ok(Response::not_allowed("GET, PUT, DELETE")),
+        };
+    }
+    if path.starts_with("/admin/") {
+        session.permit_account_admin()?;
+    }
+    match (path, method) {
+        ("/keys", "GET") => list(engine, session, query),
+        ("/keys", _) => Ok(Response::not_allowed("GET")),
+        ("/admin/stats", "GET") => Response::json(200, &engine.stats()?),
+        ("/admin/stats", _) => Ok(Response::not_allowed("GET")),
+        ("/admin/compact", "POST") => {
+            engine.compact()?;
+            Response::json(200, &engine.stats()?)
+        }
+        ("/admin/compact", _) => Ok(Response::not_allowed("POST")),
+        ("/admin/backup", "POST") => {
+            let root = match backup_dir {
+                Some(root) => root,
+                None => return Ok(Response::error(404, "the server takes no backups")),
+            };
+            let body: BackupBody = parse_body(&req.body)?;
+            let backup = backup(engine, &within(root, &body.dir)?)?;
+            Response::json(
+                200,
+                &BackupResult {
+                    dir: body.dir,
+                    keys: backup.keys,
+                    checksum: format!("{:016x}", backup.checksum),
+                },
+            )
+        }
+        ("/admin/backup", _) => Ok(Response::not_allowed("POST")),
+        _ => Ok(Response::error(404, format!("no endpoint at {}", path))),
+    }
+}
+
+///`dir` in `root`, a relative path without `..`, whose parent is still in `root`
+///once symbolic links are resolved
+fn within(root: &Path, dir: &str) -> Result<PathBuf> {
+    let outside = || {
+        KvsError::Protocol(format!(
+            "{} is not a directory of the backup directory",
+            dir
+        ))
+    };
+    let path = Path::new(dir);
+    let name = match path.file_name() {
+        Some(name) if path.components().all(|c| matches!(c, Component::Normal(_))) => name,
+        _ => return Err(outside()),
+    };
+    let root = root.canonicalize()?;
+    let parent = match path.parent() {
+        Some(parent) => root.join(parent).canonicalize()?,
+        None => root.clone(),
+    };
+    if !parent.starts_with(&root) {
+        return Err(outside());
+    }
+    Ok(parent.join(name))
+}
+
+///The pairs from `start` on, before `end`, whose key starts with `prefix`,
+///in the order of their keys. At most `limit` of them, `next` is the `start` of the next page.
+fn list<E: KvsEngine>(engine: &E, session: &Session, query: &str) -> Result<Response> {
+    let mut prefix = String::new();
+    let mut start = None;
+    let mut end = None;
+    let mut limit = MAX_LIST_LIMIT;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(pos) => (&param[..pos], decode(&param[pos + 1..], true)?),
+            None => (param, String::new()),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "start" => start = Some(value),
+            "end" => end = Some(value),
+            "limit" => {
+                limit = match value.parse() {
+                    Ok(limit) if limit > 0 && limit <= MAX_LIST_LIMIT => limit,
+                    _ => {
+                        return Err(KvsError::Protocol(format!(
+                            "limit must be from 1 to {}",
+                            MAX_LIST_LIMIT
+                        )))
+                    }
+                }
+            }
+            name => {
+                return Err(KvsError::Protocol(format!(
+                    "unknown query parameter {}",
+                    name
+                )))
+            }
+        }
+    }
+
+    session.permit_prefix(Permission::Read, &prefix)?;
+
+    //one more than a page tells where the next one starts
+    let mut pairs = BTreeMap::new();
+    engine.for_each(|key, value| {
+        let wanted = key.starts_with(&prefix)
+            && start.as_ref().is_none_or(|start| &key >= start)
+            && end.as_ref().is_none_or(|end| &key < end);
+        if wanted {
+            pairs.insert(key, value);
+            if pairs.len() > limit + 1 {
+                pairs.pop_last();
+            }
+        }
+        Ok(())
+    })?;
+    let next = if pairs.len() > limit {
+        pairs.pop_last().map(|(key, _)| key)
+    } else {
+        None
+    };
+    let pairs = pairs
+        .into_iter()
+        .map(|(key, value)| Pair { key, value })
+        .collect();
+    Response::json(200, &Listing { pairs, next })
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|e| KvsError::Protocol(format!("invalid body: {}", e)))
+}
+
+///undo the percent-encoding of a path segment or, with `plus_is_space`, a query value
+fn decode(s: &str, plus_is_space: bool) -> Result<String> {
+    let invalid = || KvsError::Protocol(format!("invalid percent-encoding in {}", s));
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'%' => {
+                let hex = bytes.get(pos + 1..pos + 3).ok_or_else(invalid)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(invalid());
+                }
+                let hex = str::from_utf8(hex).map_err(|_| invalid())?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                pos += 3;
+            }
+            b'+' if plus_is_space => {
+                decoded.push(b' ');
+                pos += 1;
+            }
+            b => {
+                decoded.push(b);
+                pos += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+///An HTTP response, with a JSON body unless it is a 204
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+    ///the methods the path takes, for a 405
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Result<Response> {
+        Ok(Response {
+            status,
+            body: serde_json::to_vec(body)?,
+            allow: None,
+        })
+    }
+
+    fn empty(status: u16) -> Response {
+        Response {
+            status,
+            body: Vec::new(),
+            allow: None,
+        }
+    }
+
+    fn error<M: ToString>(status: u16, msg: M) -> Response {
+        Response {
+            status,
+            body: serde_json::json!({ "error": msg.to_string() })
+                .to_string()
+                .into_bytes(),
+            allow: None,
+        }
+    }
+
+    fn not_allowed(allow: &'static str) -> Response {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "method not allowed")
+        }
+    }
+
+    fn from_error(e: &KvsError) -> Response {
+        let status = match e {
+            KvsError::KeyNotFound => 404,
+            KvsError::Unauthenticated | KvsError::InvalidCredentials => 401,
+            KvsError::PermissionDenied { .. } => 403,
+            KvsError::Protocol(_) => 400,
+            KvsError::Io(e) if e.kind() == io::ErrorKind::AlreadyExists => 409,
+            KvsError::KeyTooLarge { .. }
+            | KvsError::ValueTooLarge { .. }
+            | KvsError::RequestTooLarge { .. } => 413,
+            KvsError::StoreClosed => 503,
+            _ => 500,
+        };
+        Response::error(status, e)
+    }
+
+    fn encode(&self, close: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if self.status != 204 {
+            head += "Content-Type: application/json\r\n";
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+        }
+        if let Some(allow) = self.allow {
+            head += &format!("Allow: {}\r\n", allow);
+        }
+        if self.status == 401 {
+            head += "WWW-Authenticate: Bearer realm=\"kvs\"\r\n";
+            head += "WWW-Authenticate: Basic realm=\"kvs\"\r\n";
+        }
+        if close {
+            head += "Connection: close\r\n";
+        }
+        head += "\r\n";
+        let mut encoded = head.into_bytes();
+        encoded.extend_from_slice(&self.body);
+        encoded
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
@@ -1,11 +1,33 @@
+mod acl;
+mod async_server;
+mod auth;
 mod client;
 mod common;
 mod engines;
 mod error;
+mod http;
+mod meta;
+mod migrate;
+mod protocol;
+mod resp;
 mod server;
+mod shutdown;
 pub mod thread_pool;
+mod tls;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use acl::{Acl, Permission};
+pub use async_server::AsyncKvsServer;
+pub use auth::{hash_password, hash_token, Accounts, Credentials};
+pub use client::{KvsClient, Pending, Pipeline};
+pub use engines::{
+    AsyncKvsEngine, EngineStats, Event, FlushPolicy, GenerationStats, KvStore, KvStoreConfig,
+    KvsEngine, LsmConfig, LsmKvsEngine, MemoryKvsEngine, MemoryStorage, OsStorage, Retention,
+    SledConfig, SledKvsEngine, SledTransaction, Storage, StorageFile, Version, Watcher,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use meta::{format_version, StoreMeta};
+pub use migrate::{backup, migrate, Backup, Migration};
+pub use protocol::Protocol;
+pub use server::{KvsServer, SizeLimits};
+pub use shutdown::ShutdownHandle;
+pub use tls::{ClientTls, ServerTls};
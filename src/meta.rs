@@ -0,0 +1,199 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///the file `kvs-server` used to record the engine in before `META`
+const LEGACY_ENGINE_FILE: &str = "engine";
+
+/// Metadata of a data directory, kept as JSON in its `META` file.
+///
+/// Every engine storing data on disk writes it the first time it opens a directory
+/// and refuses to open a directory written by another engine or in another format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoreMeta {
+    /// Name of the engine owning the directory: `kvs`, `sled` or `lsm`
+    pub engine: String,
+    /// Version of the on-disk format of the engine
+    pub format_version: u32,
+    /// Creation time, in seconds since the unix epoch
+    pub created: u64,
+    /// Random id telling stores apart
+    pub store_id: String,
+}
+
+impl StoreMeta {
+    /// Reads the metadata of a directory, `None` if it has none.
+    pub fn load(dir: &Path) -> Result<Option<StoreMeta>> {
+        let path = meta_path(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    /// Checks that the directory can be opened by the engine,
+    /// writing fresh metadata if the directory holds no store yet.
+    pub fn check(dir: &Path, engine: &str) -> Result<StoreMeta> {
+        let version = format_version(engine)?;
+        let meta = match StoreMeta::load(dir)? {
+            Some(meta) => meta,
+            None => match legacy_engine(dir)? {
+                //written before metadata existed, the old layout is version 0
+                Some(found) if found != engine => {
+                    return Err(KvsError::WrongEngine {
+                        expected: engine.to_owned(),
+                        found,
+                    })
+                }
+                Some(_) => {
+                    return Err(KvsError::UpgradeRequired {
+                        found: 0,
+                        expected: version,
+                    })
+                }
+                None => {
+                    let meta = StoreMeta::new(engine, version);
+                    meta.store(dir)?;
+                    return Ok(meta);
+                }
+            },
+        };
+
+        if meta.engine != engine {
+            return Err(KvsError::WrongEngine {
+                expected: engine.to_owned(),
+                found: meta.engine,
+            });
+        }
+        if meta.format_version < version {
+            return Err(KvsError::UpgradeRequired {
+                found: meta.format_version,
+                expected: version,
+            });
+        }
+        if meta.format_version > version {
+            return Err(KvsError::UnsupportedFormat {
+                found: meta.format_version,
+                expected: version,
+            });
+        }
+        Ok(meta)
+    }
+
+    /// Brings a data directory to the format this build reads, one version at a time.
+    ///
+    /// Returns the version the directory was in and its new metadata.
+    pub fn upgrade(dir: &Path) -> Result<(u32, StoreMeta)> {
+        let mut meta = match StoreMeta::load(dir)? {
+            Some(meta) => meta,
+            None => {
+                let engine = legacy_engine(dir)?.ok_or_else(|| {
+                    KvsError::StringError(format!("{:?} does not hold a store", dir))
+                })?;
+                StoreMeta::new(&engine, 0)
+            }
+        };
+        let from = meta.format_version;
+        let version = format_version(&meta.engine)?;
+        if from > version {
+            return Err(KvsError::UnsupportedFormat {
+                found: from,
+                expected: version,
+            });
+        }
+
+        while meta.format_version < version {
+            upgrade_step(dir, &meta.engine, meta.format_version)?;
+            meta.format_version += 1;
+            meta.store(dir)?;
+        }
+        let legacy = dir.join(LEGACY_ENGINE_FILE);
+        if legacy.exists() {
+            fs::remove_file(legacy)?;
+        }
+        Ok((from, meta))
+    }
+
+    fn new(engine: &str, format_version: u32) -> StoreMeta {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(created.as_nanos());
+        hasher.write_u32(std::process::id());
+        StoreMeta {
+            engine: engine.to_owned(),
+            format_version,
+            created: created.as_secs(),
+            store_id: format!("{:016x}", hasher.finish()),
+        }
+    }
+
+    ///write a temporary file, sync it, then rename it over the metadata
+    fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join("META.tmp");
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, meta_path(dir))?;
+        Ok(())
+    }
+}
+
+/// The on-disk format version this build of the engine reads and writes.
+pub fn format_version(engine: &str) -> Result<u32> {
+    match engine {
+        //1: the first versioned format, logs listed in a manifest
+        "kvs" => Ok(1),
+        "sled" | "lsm" => Ok(1),
+        _ => Err(KvsError::StringError(format!("unknown engine {}", engine))),
+    }
+}
+
+///rewrite the files of `engine` from format `from` to `from + 1`
+fn upgrade_step(_dir: &Path, engine: &str, from: u32) -> Result<()> {
+    match (engine, from) {
+        //the files were laid out the same before `META`,
+        //a kvs store without a manifest is still read from every log
+        (_, 0) => Ok(()),
+        _ => Err(KvsError::StringError(format!(
+            "no upgrade from version {} of the {} engine",
+            from, engine
+        ))),
+    }
+}
+
+///the engine of a directory written before `META`, if it holds anything
+fn legacy_engine(dir: &Path) -> Result<Option<String>> {
+    let legacy = dir.join(LEGACY_ENGINE_FILE);
+    if legacy.exists() {
+        return Ok(Some(fs::read_to_string(legacy)?.trim().to_lowercase()));
+    }
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(OsStr::to_str) {
+            Some("log") | Some("blob") => return Ok(Some("kvs".to_owned())),
+            Some("sst") | Some("wal") => return Ok(Some("lsm".to_owned())),
+            _ => {}
+        }
+        if path.file_name() == Some("conf".as_ref()) {
+            return Ok(Some("sled".to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn meta_path(dir: &Path) -> PathBuf {
+    dir.join("META")
+}
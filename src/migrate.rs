@@ -0,0 +1,211 @@
+use crate::{
+    KvStore, KvsEngine, KvsError, LsmKvsEngine, Result, SledConfig, SledKvsEngine, StoreMeta,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+///pairs copied per `set_many`
+const BATCH_SIZE: usize = 1000;
+
+/// What `migrate` moved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    /// Number of live keys
+    pub keys: u64,
+    /// Checksum of every key and value, independent of their order
+    pub checksum: u64,
+    /// Where the data directory in the old engine was moved to
+    pub backup: PathBuf,
+}
+
+/// Moves the store in `dir` from engine `from` to engine `to`.
+///
+/// Every live key is copied into a new directory next to `dir`, which is checked
+/// to hold the same keys and values before it takes the place of `dir`.
+/// The old directory is kept as `<dir>.<from>-backup`. No server may use the store meanwhile.
+///
+/// The swap is two renames. If it is cut short in between, `dir` is missing
+/// and running the migration again finishes it.
+pub fn migrate(dir: &Path, from: &str, to: &str) -> Result<Migration> {
+    //`.` and the like have no name to put the new directories next to
+    let dir = &dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| KvsError::StringError(format!("{:?} is not a directory name", dir)))?;
+    let staged = dir.with_file_name(format!("{}.{}-migrating", name, to));
+    let backup = dir.with_file_name(format!("{}.{}-backup", name, from));
+
+    if !dir.exists() && staged.exists() && backup.exists() {
+        fs::rename(&staged, dir)?;
+        let (keys, checksum) = open(to, dir)?.summary()?;
+        return Ok(Migration {
+            keys,
+            checksum,
+            backup,
+        });
+    }
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "the store already uses the {} engine",
+            to
+        )));
+    }
+    if backup.exists() {
+        return Err(KvsError::StringError(format!(
+            "{:?} exists, remove it before migrating",
+            backup
+        )));
+    }
+    StoreMeta::check(dir, from)?;
+
+    //left behind by a migration that failed before the swap
+    if staged.exists() {
+        fs::remove_dir_all(&staged)?;
+    }
+    fs::create_dir(&staged)?;
+    let copied = match copy(from, dir, to, &staged) {
+        Ok(copied) => copied,
+        Err(e) => {
+            fs::remove_dir_all(&staged)?;
+            return Err(e);
+        }
+    };
+
+    fs::rename(dir, &backup)?;
+    fs::rename(&staged, dir)?;
+    let (keys, checksum) = copied;
+    Ok(Migration {
+        keys,
+        checksum,
+        backup,
+    })
+}
+
+/// What `backup` copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    /// Number of live keys
+    pub keys: u64,
+    /// Checksum of every key and value, independent of their order
+    pub checksum: u64,
+}
+
+/// Copies every live key of `engine` into a new kvs store in `dir`.
+///
+/// `dir` must not exist yet. The copy is checked to hold the same keys and values,
+/// like a migration, and can be served by `kvs-server --engine kvs`.
+/// Engines hold writes back while their keys are listed, so the copy is a snapshot.
+pub fn backup<E: KvsEngine>(engine: &E, dir: &Path) -> Result<Backup> {
+    fs::create_dir(dir)?;
+    match open("kvs", dir).and_then(|dst| copy_into(|f| engine.for_each(f), &dst)) {
+        Ok((keys, checksum)) => Ok(Backup { keys, checksum }),
+        Err(e) => {
+            fs::remove_dir_all(dir)?;
+            Err(e)
+        }
+    }
+}
+
+fn copy(from: &str, src_dir: &Path, to: &str, dst_dir: &Path) -> Result<(u64, u64)> {
+    let src = open(from, src_dir)?;
+    copy_into(|f| src.for_each(f), &open(to, dst_dir)?)
+}
+
+///copy every key `for_each` lists, then check the copy holds the same data
+fn copy_into<S>(for_each: S, dst: &Engine) -> Result<(u64, u64)>
+where
+    S: FnOnce(&mut dyn FnMut(String, String) -> Result<()>) -> Result<()>,
+{
+    let mut summary = Summary::default();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for_each(&mut |key, value| {
+        summary.add(&key, &value);
+        batch.push((key, value));
+        if batch.len() == BATCH_SIZE {
+            dst.set_many(batch.split_off(0))?;
+        }
+        Ok(())
+    })?;
+    dst.set_many(batch)?;
+
+    let copied = dst.summary()?;
+    if copied != (summary.keys, summary.checksum) {
+        return Err(KvsError::StringError(format!(
+            "the copy does not match: {} keys with checksum {:016x} instead of {} with {:016x}",
+            copied.0, copied.1, summary.keys, summary.checksum
+        )));
+    }
+    Ok(copied)
+}
+
+///an engine chosen by name
+enum Engine {
+    Kvs(KvStore),
+    Sled(SledKvsEngine),
+    Lsm(LsmKvsEngine),
+}
+
+fn open(engine: &str, dir: &Path) -> Result<Engine> {
+    match engine {
+        "kvs" => Ok(Engine::Kvs(KvStore::open(dir)?)),
+        "sled" => Ok(Engine::Sled(SledKvsEngine::open_with_config(
+            dir,
+            SledConfig::default(),
+        )?)),
+        "lsm" => Ok(Engine::Lsm(LsmKvsEngine::open(dir)?)),
+        _ => Err(KvsError::StringError(format!(
+            "cannot migrate the {} engine",
+            engine
+        ))),
+    }
+}
+
+impl Engine {
+    fn for_each<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        match self {
+            Engine::Kvs(engine) => engine.for_each(f),
+            Engine::Sled(engine) => engine.for_each(f),
+            Engine::Lsm(engine) => engine.for_each(f),
+        }
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self {
+            Engine::Kvs(engine) => engine.set_many(pairs),
+            Engine::Sled(engine) => engine.set_many(pairs),
+            Engine::Lsm(engine) => engine.set_many(pairs),
+        }
+    }
+
+    ///(number of keys,checksum)
+    fn summary(&self) -> Result<(u64, u64)> {
+        let mut summary = Summary::default();
+        self.for_each(|key, value| {
+            summary.add(&key, &value);
+            Ok(())
+        })?;
+        Ok((summary.keys, summary.checksum))
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    keys: u64,
+    checksum: u64,
+}
+
+impl Summary {
+    ///engines list their keys in different orders, so the hashes are summed
+    fn add(&mut self, key: &str, value: &str) {
+        let mut hasher = DefaultHasher::new();
+        (key, value).hash(&mut hasher);
+        self.keys += 1;
+        self.checksum = self.checksum.wrapping_add(hasher.finish());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::common::Request;
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read};
+
+///what a client opens a binary connection with, followed by its version
+const MAGIC: &[u8; 4] = b"KVSB";
+const VERSION: u8 = 1;
+const HELLO_LEN: usize = 5;
+///the length prefix, the opcode and the request id
+const HEADER_LEN: usize = 13;
+
+const GET: u8 = 1;
+const SET: u8 = 2;
+const REMOVE: u8 = 3;
+const WATCH: u8 = 4;
+const STATS: u8 = 5;
+const GET_AT: u8 = 6;
+const HISTORY: u8 = 7;
+const GET_MANY: u8 = 8;
+const SET_MANY: u8 = 9;
+const REMOVE_MANY: u8 = 10;
+const AUTH: u8 = 11;
+
+/// The wire format of a connection, chosen by the client when it connects.
+///
+/// A server speaks both on the same port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Requests and responses as a bare stream of JSON values, handy for debugging
+    /// with `nc`. A connection speaks JSON unless it opens with the binary greeting.
+    Json,
+    /// Length-prefixed frames. The client opens with `KVSB` and a version byte,
+    /// the server answers the same. Each frame is a 4 byte big-endian length of
+    /// the rest of the frame, a 1 byte opcode, an 8 byte big-endian request id
+    /// and the bincode payload. A response carries the opcode and id of its request,
+    /// a malformed request gets an error response and the connection goes on.
+    ///
+    /// A client may send requests without waiting for their responses. The server
+    /// may run them concurrently and answer them in any order.
+    Binary,
+}
+
+///the greeting opening a binary connection, the server answers with the same
+pub(crate) fn hello() -> [u8; HELLO_LEN] {
+    let mut hello = [0; HELLO_LEN];
+    hello[..4].copy_from_slice(MAGIC);
+    hello[4] = VERSION;
+    hello
+}
+
+fn check_hello(hello: &[u8]) -> Result<()> {
+    if &hello[..4] != MAGIC {
+        return Err(KvsError::Protocol("unknown greeting".to_owned()));
+    }
+    if hello[4] != VERSION {
+        return Err(KvsError::Protocol(format!(
+            "unsupported protocol version {}",
+            hello[4]
+        )));
+    }
+    Ok(())
+}
+
+///read the server's answer to the greeting
+pub(crate) fn read_hello<R: Read>(reader: &mut R) -> Result<()> {
+    let mut hello = [0; HELLO_LEN];
+    reader.read_exact(&mut hello)?;
+    check_hello(&hello)
+}
+
+///the protocol of a new connection, consuming the greeting of a binary one
+pub(crate) fn accept<R: BufRead>(reader: &mut R) -> Result<Protocol> {
+    if reader.fill_buf()?.first() != Some(&MAGIC[0]) {
+        return Ok(Protocol::Json);
+    }
+    let mut hello = [0; HELLO_LEN];
+    reader.read_exact(&mut hello)?;
+    check_hello(&hello)?;
+    Ok(Protocol::Binary)
+}
+
+///the protocol of a new connection from the bytes received so far and
+///how many of them the greeting takes, `None` until there are enough
+pub(crate) fn detect(buf: &[u8]) -> Result<Option<(Protocol, usize)>> {
+    match buf.first() {
+        None => Ok(None),
+        Some(&first) if first != MAGIC[0] => Ok(Some((Protocol::Json, 0))),
+        Some(_) if buf.len() < HELLO_LEN => Ok(None),
+        Some(_) => {
+            check_hello(&buf[..HELLO_LEN])?;
+            Ok(Some((Protocol::Binary, HELLO_LEN)))
+        }
+    }
+}
+
+///The start of a binary frame
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    payload_len: usize,
+    pub opcode: u8,
+    pub id: u64,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Result<Header> {
+        let mut len = [0; 4];
+        len.copy_from_slice(&buf[..4]);
+        let mut id = [0; 8];
+        id.copy_from_slice(&buf[5..HEADER_LEN]);
+        let len = u32::from_be_bytes(len) as usize;
+        //the opcode and the id are part of the length
+        if len < HEADER_LEN - 4 {
+            return Err(KvsError::Protocol(format!("frame of {} bytes", len)));
+        }
+        Ok(Header {
+            payload_len: len - (HEADER_LEN - 4),
+            opcode: buf[4],
+            id: u64::from_be_bytes(id),
+        })
+    }
+
+    ///the bytes of the whole frame
+    pub fn frame_len(&self) -> u64 {
+        (HEADER_LEN + self.payload_len) as u64
+    }
+
+    ///how a response to this frame is written
+    pub fn reply(&self) -> Reply {
+        Reply::Binary {
+            opcode: self.opcode,
+            id: self.id,
+        }
+    }
+}
+
+///the header of the next frame, `None` at the end of the stream
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<Option<Header>> {
+    let mut buf = [0; HEADER_LEN];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Header::parse(&buf).map(Some),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+///the payload following `header`
+pub(crate) fn read_payload<R: Read>(reader: &mut R, header: &Header) -> Result<Vec<u8>> {
+    let mut payload = vec![0; header.payload_len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+///the header of the frame at the start of `buf`, `None` until all of it arrived
+pub(crate) fn parse_header(buf: &[u8]) -> Result<Option<Header>> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    Header::parse(buf).map(Some)
+}
+
+///whether `buf` starts with a whole frame
+pub(crate) fn has_frame(buf: &[u8]) -> bool {
+    match parse_header(buf) {
+        Ok(Some(header)) => buf.len() as u64 >= header.frame_len(),
+        //a malformed header is reported once it is read
+        Ok(None) => false,
+        Err(_) => true,
+    }
+}
+
+///the payload of the frame at the start of `buf`, `None` until all of it arrived
+pub(crate) fn parse_payload<'a>(buf: &'a [u8], header: &Header) -> Option<&'a [u8]> {
+    buf.get(HEADER_LEN..HEADER_LEN + header.payload_len)
+}
+
+///fails when the length does not fit the 4 byte prefix
+fn frame(opcode: u8, id: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    let len =
+        u32::try_from(HEADER_LEN - 4 + payload.len()).map_err(|_| KvsError::RequestTooLarge {
+            limit: u64::from(u32::MAX) + 4,
+        })?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.push(opcode);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+pub(crate) fn encode_request(req: &Request, id: u64) -> Result<Vec<u8>> {
+    let (opcode, payload) = match req {
+        Request::Get { key } => (GET, bincode::serialize(key)?),
+        Request::Set { key, value } => (SET, bincode::serialize(&(key, value))?),
+        Request::Remove { key } => (REMOVE, bincode::serialize(key)?),
+        Request::Watch { prefix } => (WATCH, bincode::serialize(prefix)?),
+        Request::Stats => (STATS, Vec::new()),
+        Request::GetAt { key, seq } => (GET_AT, bincode::serialize(&(key, seq))?),
+        Request::History { key } => (HISTORY, bincode::serialize(key)?),
+        Request::GetMany { keys } => (GET_MANY, bincode::serialize(keys)?),
+        Request::SetMany { pairs } => (SET_MANY, bincode::serialize(pairs)?),
+        Request::RemoveMany { keys } => (REMOVE_MANY, bincode::serialize(keys)?),
+        Request::Auth { credentials } => (AUTH, bincode::serialize(credentials)?),
+    };
+    frame(opcode, id, &payload)
+}
+
+pub(crate) fn decode_request(opcode: u8, payload: &[u8]) -> Result<Request> {
+    Ok(match opcode {
+        GET => Request::Get {
+            key: bincode::deserialize(payload)?,
+        },
+        SET => {
+            let (key, value) = bincode::deserialize(payload)?;
+            Request::Set { key, value }
+        }
+        REMOVE => Request::Remove {
+            key: bincode::deserialize(payload)?,
+        },
+        WATCH => Request::Watch {
+            prefix: bincode::deserialize(payload)?,
+        },
+        STATS => Request::Stats,
+        GET_AT => {
+            let (key, seq) = bincode::deserialize(payload)?;
+            Request::GetAt { key, seq }
+        }
+        HISTORY => Request::History {
+            key: bincode::deserialize(payload)?,
+        },
+        GET_MANY => Request::GetMany {
+            keys: bincode::deserialize(payload)?,
+        },
+        SET_MANY => Request::SetMany {
+            pairs: bincode::deserialize(payload)?,
+        },
+        REMOVE_MANY => Request::RemoveMany {
+            keys: bincode::deserialize(payload)?,
+        },
+        AUTH => Request::Auth {
+            credentials: bincode::deserialize(payload)?,
+        },
+        opcode => return Err(KvsError::Protocol(format!("unknown opcode {}", opcode))),
+    })
+}
+
+///the id and payload of the next response, `None` at the end of the stream
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(u64, Vec<u8>)>> {
+    match read_header(reader)? {
+        Some(header) => Ok(Some((header.id, read_payload(reader, &header)?))),
+        None => Ok(None),
+    }
+}
+
+///the response to request `id`, `None` at the end of the stream
+pub(crate) fn read_response<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    id: u64,
+) -> Result<Option<T>> {
+    let (resp_id, payload) = match read_frame(reader)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    if resp_id != id {
+        return Err(KvsError::Protocol(format!(
+            "response to request {} while waiting for {}",
+            resp_id, id
+        )));
+    }
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+///How a response is written, it depends on the request it answers
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Reply {
+    Json,
+    Binary { opcode: u8, id: u64 },
+}
+
+impl Reply {
+    pub fn encode<T: Serialize>(self, resp: &T) -> Result<Vec<u8>> {
+        match self {
+            Reply::Json => Ok(serde_json::to_vec(resp)?),
+            Reply::Binary { opcode, id } => frame(opcode, id, &bincode::serialize(resp)?),
+        }
+    }
+}
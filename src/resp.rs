@@ -0,0 +1,725 @@
+use crate::auth::Session;
+use crate::{Credentials, KvsEngine, KvsError, Permission, Result, ShutdownHandle, SizeLimits};
+use log::error;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+///how often keys past their deadline are removed when no RESP command comes
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+///longest inline command, like in Redis
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+///most arguments of a command, like in Redis
+const MAX_ARGS: usize = 1024 * 1024;
+const SCAN_COUNT: usize = 10;
+///most scans whose next page is kept, the oldest is dropped first
+const MAX_SCANS: usize = 64;
+
+///State the RESP connections of a server share.
+///Writes take its lock, so `SET NX`, `SET XX` and `INCR` are atomic
+///among RESP clients, but not against clients of the kvs protocols.
+///Deadlines of keys set with `EX` or `PX` live in memory only,
+///a restart forgets them. A deadline removes only the value written with it,
+///a key a client of the kvs protocols set to another value meanwhile is kept.
+#[derive(Clone, Default)]
+pub(crate) struct RespState {
+    expiry: Arc<Mutex<Expiry>>,
+    scans: Arc<Mutex<HashMap<u64, Scan>>>,
+}
+
+#[derive(Default)]
+struct Expiry {
+    ///the deadline of each key and the value it was set to then
+    deadlines: HashMap<String, (Instant, String)>,
+    by_deadline: BTreeSet<(Instant, String)>,
+}
+
+impl Expiry {
+    ///a key without a deadline lives until removed
+    fn set(&mut self, key: &str, deadline: Option<(Instant, String)>) {
+        if let Some((old, _)) = self.deadlines.remove(key) {
+            self.by_deadline.remove(&(old, key.to_owned()));
+        }
+        if let Some((deadline, value)) = deadline {
+            self.deadlines.insert(key.to_owned(), (deadline, value));
+            self.by_deadline.insert((deadline, key.to_owned()));
+        }
+    }
+
+    ///a new value for a key that keeps its deadline
+    fn rewrite(&mut self, key: &str, value: String) {
+        if let Some((_, written)) = self.deadlines.get_mut(key) {
+            *written = value;
+        }
+    }
+
+    ///the keys past their deadline and the values they were set to
+    fn overdue(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut keys = Vec::new();
+        while let Some((deadline, key)) = self.by_deadline.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
+            self.by_deadline.remove(&(deadline, key.clone()));
+            if let Some((_, value)) = self.deadlines.remove(&key) {
+                keys.push((key, value));
+            }
+        }
+        keys
+    }
+}
+
+impl RespState {
+    ///remove the keys past their deadline that still hold the value written with it,
+    ///writes keep the lock for their command
+    fn expire<E: KvsEngine>(&self, engine: &E) -> Result<MutexGuard<'_, Expiry>> {
+        let mut expiry = self.expiry.lock().unwrap();
+        let (keys, written): (Vec<_>, Vec<_>) = expiry.overdue(Instant::now()).into_iter().unzip();
+        if !keys.is_empty() {
+            let values = engine.get_many(keys.clone())?;
+            let expired: Vec<_> = keys
+                .into_iter()
+                .zip(written.into_iter().zip(values))
+                .filter(|(_, (written, value))| value.as_ref() == Some(written))
+                .map(|(key, _)| key)
+                .collect();
+            if !expired.is_empty() {
+                engine.remove_many(expired)?;
+            }
+        }
+        Ok(expiry)
+    }
+
+    ///remove keys past their deadline until the server shuts down,
+    ///so that clients of the kvs protocols do not see them either
+    pub fn spawn_sweeper<E: KvsEngine>(
+        &self,
+        engine: E,
+        shutdown: ShutdownHandle,
+    ) -> Result<JoinHandle<()>> {
+        let state = self.clone();
+        Ok(thread::Builder::new().spawn(move || {
+            while !shutdown.is_requested() {
+                thread::sleep(SWEEP_INTERVAL);
+                if let Err(e) = state.expire(&engine) {
+                    error!("Error on expiring keys:{}", e);
+                }
+            }
+        })?)
+    }
+}
+
+///The complete commands at the start of a connection's buffer
+pub(crate) struct Parsed {
+    pub commands: Vec<Vec<Vec<u8>>>,
+    ///how many bytes they take
+    pub len: usize,
+    ///what follows them breaks the protocol, the connection is answered and closed
+    pub error: Option<KvsError>,
+}
+
+pub(crate) fn parse_commands(buf: &[u8], limits: &SizeLimits) -> Parsed {
+    let mut parsed = Parsed {
+        commands: Vec::new(),
+        len: 0,
+        error: None,
+    };
+    loop {
+        match parse_command(&buf[parsed.len..], limits) {
+            Ok(Some((args, len))) => {
+                parsed.commands.push(args);
+                parsed.len += len;
+            }
+            Ok(None) => return parsed,
+            Err(e) => {
+                parsed.error = Some(e);
+                return parsed;
+            }
+        }
+    }
+}
+
+///the command at the start of `buf` and how many bytes it takes,
+///`None` until all of it arrived. Commands come as arrays of bulk strings
+///or inline, as a line of words.
+fn parse_command(buf: &[u8], limits: &SizeLimits) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let parsed = parse_multibulk(buf, limits)?;
+    //what is left is the start of a single command
+    if parsed.is_none() && buf.len() as u64 > limits.max_request_size {
+        return Err(KvsError::Protocol("request too large".to_owned()));
+    }
+    Ok(parsed)
+}
+
+fn parse_multibulk(buf: &[u8], limits: &SizeLimits) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    if buf.first() != Some(&b'*') {
+        return parse_inline(buf);
+    }
+    let (count, mut pos) = match parse_line(buf)? {
+        Some((line, len)) => (parse_int(&line[1..])?, len),
+        None => return Ok(None),
+    };
+    if count > MAX_ARGS as i64 {
+        return Err(KvsError::Protocol("invalid multibulk length".to_owned()));
+    }
+    let mut args = Vec::new();
+    for _ in 0..count.max(0) {
+        let (line, len) = match parse_line(&buf[pos..])? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err(KvsError::Protocol(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..1.min(line.len())])
+            )));
+        }
+        let arg_len = parse_int(&line[1..])?;
+        if arg_len < 0 || arg_len as u64 > limits.max_request_size {
+            return Err(KvsError::Protocol("invalid bulk length".to_owned()));
+        }
+        let start = pos + len;
+        let end = start + arg_len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(KvsError::Protocol("bulk string without CRLF".to_owned()));
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+fn parse_inline(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_INLINE_SIZE => {
+            return Err(KvsError::Protocol("too big inline request".to_owned()))
+        }
+        None => return Ok(None),
+    };
+    let args = buf[..end]
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_vec())
+        .collect();
+    Ok(Some((args, end + 1)))
+}
+
+///a line without its CRLF and the bytes it takes with it
+fn parse_line(buf: &[u8]) -> Result<Option<(&[u8], usize)>> {
+    match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => Ok(Some((&buf[..end], end + 2))),
+        None if buf.len() > MAX_INLINE_SIZE => {
+            Err(KvsError::Protocol("too big header line".to_owned()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_int(digits: &[u8]) -> Result<i64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("invalid length".to_owned()))
+}
+
+///whether the connection ends after answering
+pub(crate) fn is_quit(args: &[Vec<u8>]) -> bool {
+    args.len() == 1 && args[0].eq_ignore_ascii_case(b"QUIT")
+}
+
+///the encoded answer to a protocol error, the connection is closed after it
+pub(crate) fn protocol_error(e: &KvsError) -> Vec<u8> {
+    let msg = match e {
+        KvsError::Protocol(msg) => msg.clone(),
+        e => e.to_string(),
+    };
+    Value::Error(format!("ERR Protocol error: {}", msg)).encode()
+}
+
+///A RESP reply
+enum Value {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            //a line break would end the error early
+            Value::Error(e) => {
+                buf.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            Value::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Value::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Value::Bulk(Some(s)) => {
+                buf.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Array(values) => {
+                buf.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode_into(buf);
+                }
+            }
+        }
+    }
+}
+
+///Run a command on the engine and encode the reply.
+///Errors are replies too, the connection goes on.
+///Until the session is authenticated, only `AUTH` and `QUIT` are run,
+///and then only the commands the ACL grants.
+pub(crate) fn execute<E: KvsEngine>(
+    engine: &E,
+    state: &RespState,
+    session: &mut Session,
+    limits: &SizeLimits,
+    args: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    let args = match args
+        .into_iter()
+        .map(String::from_utf8)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return Value::Error("ERR arguments must be UTF-8".to_owned()).encode(),
+    };
+    match args.split_first() {
+        Some((name, args)) if name.eq_ignore_ascii_case("AUTH") => auth(session, args),
+        Some((name, _)) if session.check().is_err() && !name.eq_ignore_ascii_case("QUIT") => {
+            Value::Error("NOAUTH Authentication required.".to_owned())
+        }
+        Some((name, args)) => match authorize(session, name, args) {
+            Ok(()) => match run(engine, state, limits, name, args) {
+                Ok(value) => value,
+                Err(e) => Value::Error(format!("ERR {}", e)),
+            },
+            Err(e) => Value::Error(format!("NOPERM {}", e)),
+        },
+        //an empty line
+        None => return Vec::new(),
+    }
+    .encode()
+}
+
+fn run<E: KvsEngine>(
+    engine: &E,
+    state: &RespState,
+    limits: &SizeLimits,
+    name: &str,
+    args: &[String],
+) -> Result<Value> {
+    let name = name.to_uppercase();
+    match name.as_str() {
+        "SET" | "DEL" | "INCR" => write(engine, &mut *state.expire(engine)?, limits, &name, args),
+        _ => {
+            let expires = state.expire(engine)?.deadlines.len();
+            read(engine, state, expires, limits, &name, args)
+        }
+    }
+}
+
+///fails unless the ACL grants the command every permission it needs,
+///commands listing keys need theirs on every key
+fn authorize(session: &Session, name: &str, args: &[String]) -> Result<()> {
+    let each = |permission, keys: &[String]| {
+        keys.iter()
+            .try_for_each(|key| session.permit(permission, key))
+    };
+    match name.to_uppercase().as_str() {
+        "GET" | "EXISTS" => each(Permission::Read, args),
+        "SET" => each(Permission::Write, &args[..args.len().min(1)]),
+        "INCR" => each(Permission::Read, args).and_then(|()| each(Permission::Write, args)),
+        "DEL" => each(Permission::Delete, args),
+        "KEYS" | "SCAN" => session.permit_prefix(Permission::Read, ""),
+        "DBSIZE" | "INFO" => session.permit_admin(),
+        _ => Ok(()),
+    }
+}
+
+///`AUTH token` or `AUTH user password`
+fn auth(session: &mut Session, args: &[String]) -> Value {
+    let credentials = match args {
+        [token] => Credentials::Token(token.clone()),
+        [user, password] => Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        },
+        _ => return wrong_args("AUTH"),
+    };
+    match session.authenticate(&credentials) {
+        Ok(_) => Value::Simple("OK"),
+        Err(KvsError::InvalidCredentials) => {
+            Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned())
+        }
+        Err(e) => Value::Error(format!("ERR {}", e)),
+    }
+}
+
+fn wrong_args(name: &str) -> Value {
+    Value::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+///commands writing keys, run under the lock of the RESP state
+fn write<E: KvsEngine>(
+    engine: &E,
+    expiry: &mut Expiry,
+    limits: &SizeLimits,
+    name: &str,
+    args: &[String],
+) -> Result<Value> {
+    Ok(match (name, args) {
+        ("SET", [key, value, options @ ..]) => {
+            limits.check_pair(key, value)?;
+            let options = match SetOptions::parse(options) {
+                Ok(options) => options,
+                Err(e) => return Ok(e),
+            };
+            if options.nx || options.xx {
+                let exists = engine.get(key.clone())?.is_some();
+                if exists == options.nx {
+                    return Ok(Value::Bulk(None));
+                }
+            }
+            engine.set(key.clone(), value.clone())?;
+            expiry.set(
+                key,
+                options.deadline.map(|deadline| (deadline, value.clone())),
+            );
+            Value::Simple("OK")
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            let removed = engine.remove_many(keys.to_vec())?;
+            for key in keys {
+                expiry.set(key, None);
+            }
+            Value::Integer(removed.into_iter().filter(|&removed| removed).count() as i64)
+        }
+        ("INCR", [key]) => {
+            limits.check_key(key)?;
+            let n = match engine.get(key.clone())? {
+                Some(value) => match value.parse::<i64>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        return Ok(Value::Error(
+                            "ERR value is not an integer or out of range".to_owned(),
+                        ))
+                    }
+                },
+                None => 0,
+            };
+            let n = match n.checked_add(1) {
+                Some(n) => n,
+                None => {
+                    return Ok(Value::Error(
+                        "ERR increment or decrement would overflow".to_owned(),
+                    ))
+                }
+            };
+            engine.set(key.clone(), n.to_string())?;
+            expiry.rewrite(key, n.to_string());
+            Value::Integer(n)
+        }
+        _ => wrong_args(name),
+    })
+}
+
+fn read<E: KvsEngine>(
+    engine: &E,
+    state: &RespState,
+    expires: usize,
+    limits: &SizeLimits,
+    name: &str,
+    args: &[String],
+) -> Result<Value> {
+    Ok(match (name, args) {
+        ("PING", []) => Value::Simple("PONG"),
+        ("PING", [msg]) | ("ECHO", [msg]) => Value::Bulk(Some(msg.clone())),
+        ("QUIT", []) => Value::Simple("OK"),
+        ("SELECT", [db]) if db == "0" => Value::Simple("OK"),
+        ("SELECT", [_]) => Value::Error("ERR DB index is out of range".to_owned()),
+        ("GET", [key]) => {
+            limits.check_key(key)?;
+            Value::Bulk(engine.get(key.clone())?)
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let values = engine.get_many(keys.to_vec())?;
+            Value::Integer(values.into_iter().filter(Option::is_some).count() as i64)
+        }
+        ("KEYS", [pattern]) => {
+            let mut keys = Vec::new();
+            engine.for_each(|key, _| {
+                if glob(pattern.as_bytes(), key.as_bytes()) {
+                    keys.push(Value::Bulk(Some(key)));
+                }
+                Ok(())
+            })?;
+            Value::Array(keys)
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(engine, state, cursor, options)?,
+        ("DBSIZE", []) => Value::Integer(engine.stats()?.key_count as i64),
+        ("INFO", sections) if sections.len() <= 1 => Value::Bulk(Some(info(
+            engine,
+            expires,
+            sections.first().map(String::as_str),
+        )?)),
+        (
+            "PING" | "ECHO" | "QUIT" | "SELECT" | "GET" | "EXISTS" | "KEYS" | "SCAN" | "DBSIZE"
+            | "INFO",
+            _,
+        ) => wrong_args(name),
+        _ => Value::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+    })
+}
+
+struct SetOptions {
+    deadline: Option<Instant>,
+    nx: bool,
+    xx: bool,
+}
+
+impl SetOptions {
+    fn parse(options: &[String]) -> std::result::Result<SetOptions, Value> {
+        let syntax_error = || Value::Error("ERR syntax error".to_owned());
+        let mut parsed = SetOptions {
+            deadline: None,
+            nx: false,
+            xx: false,
+        };
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_uppercase().as_str() {
+                "NX" if !parsed.xx => parsed.nx = true,
+                "XX" if !parsed.nx => parsed.xx = true,
+                unit @ "EX" | unit @ "PX" if parsed.deadline.is_none() => {
+                    let ttl = options
+                        .next()
+                        .ok_or_else(syntax_error)?
+                        .parse::<i64>()
+                        .map_err(|_| {
+                            Value::Error("ERR value is not an integer or out of range".to_owned())
+                        })?;
+                    if ttl <= 0 {
+                        return Err(Value::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ));
+                    }
+                    let ttl = if unit == "EX" {
+                        Duration::from_secs(ttl as u64)
+                    } else {
+                        Duration::from_millis(ttl as u64)
+                    };
+                    parsed.deadline = Some(Instant::now() + ttl);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+///The keys of a scan in the order of their hash, from the first of the next page
+struct Scan {
+    taken: Instant,
+    keys: Arc<Vec<(u64, String)>>,
+    pos: usize,
+}
+
+impl Scan {
+    ///the keys whose hash is at least `cursor`
+    fn take<E: KvsEngine>(engine: &E, cursor: u64) -> Result<Scan> {
+        let mut keys = Vec::new();
+        engine.for_each(|key, _| {
+            let hash = key_hash(&key);
+            if hash >= cursor {
+                keys.push((hash, key));
+            }
+            Ok(())
+        })?;
+        keys.sort();
+        Ok(Scan {
+            taken: Instant::now(),
+            keys: Arc::new(keys),
+            pos: 0,
+        })
+    }
+}
+
+///Keys come in the order of their hash and the cursor is the hash to go on from,
+///so a key present during the whole scan is returned whatever is written meanwhile.
+///The keys are listed once per scan, the next page is kept under its cursor
+///and taken again only if it was dropped.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    state: &RespState,
+    cursor: &str,
+    options: &[String],
+) -> Result<Value> {
+    let cursor: u64 = match cursor.parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_uppercase().as_str(), options.next()) {
+            ("MATCH", Some(p)) => pattern = Some(p.clone()),
+            ("COUNT", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+            },
+            _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    let kept = match cursor {
+        0 => None,
+        cursor => state.scans.lock().unwrap().remove(&cursor),
+    };
+    let mut scan = match kept {
+        Some(scan) => scan,
+        None => Scan::take(engine, cursor)?,
+    };
+    let keys = &scan.keys[scan.pos..];
+    //keys with the same hash go in the same batch, the cursor cannot split them
+    let mut end = count.min(keys.len());
+    while end > 0 && end < keys.len() && keys[end].0 == keys[end - 1].0 {
+        end += 1;
+    }
+    let next = keys.get(end).map_or(0, |&(hash, _)| hash);
+    let page = keys[..end]
+        .iter()
+        .map(|(_, key)| key)
+        .filter(|key| {
+            pattern
+                .as_ref()
+                .is_none_or(|p| glob(p.as_bytes(), key.as_bytes()))
+        })
+        .map(|key| Value::Bulk(Some(key.clone())))
+        .collect();
+    if next != 0 {
+        scan.pos += end;
+        keep_scan(&mut state.scans.lock().unwrap(), next, scan);
+    }
+    Ok(Value::Array(vec![
+        Value::Bulk(Some(next.to_string())),
+        Value::Array(page),
+    ]))
+}
+
+///Two scans can reach the same cursor, the keys listed last serve both,
+///as they were listed while both scans were going on.
+fn keep_scan(scans: &mut HashMap<u64, Scan>, cursor: u64, scan: Scan) {
+    if scans
+        .get(&cursor)
+        .is_some_and(|kept| kept.taken > scan.taken)
+    {
+        return;
+    }
+    scans.insert(cursor, scan);
+    if scans.len() > MAX_SCANS {
+        let oldest = scans
+            .iter()
+            .min_by_key(|(_, scan)| scan.taken)
+            .map(|(&cursor, _)| cursor);
+        if let Some(oldest) = oldest {
+            scans.remove(&oldest);
+        }
+    }
+}
+
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn info<E: KvsEngine>(engine: &E, expires: usize, section: Option<&str>) -> Result<String> {
+    let section = section.map(str::to_lowercase);
+    let wanted = |name: &str| match section.as_deref() {
+        None | Some("all") | Some("default") | Some("everything") => true,
+        Some(section) => section == name,
+    };
+    let stats = engine.stats()?;
+    let mut info = String::new();
+    if wanted("server") {
+        info += "# Server\r\n";
+        info += &format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+        info += "redis_mode:standalone\r\n";
+        info += "\r\n";
+    }
+    if wanted("stats") {
+        info += "# Stats\r\n";
+        info += &format!("live_bytes:{}\r\n", stats.live_bytes);
+        info += &format!("uncompacted_bytes:{}\r\n", stats.uncompacted);
+        info += &format!("compactions:{}\r\n", stats.compaction_count);
+        info += "\r\n";
+    }
+    if wanted("keyspace") {
+        info += "# Keyspace\r\n";
+        info += &format!("db0:keys={},expires={}\r\n", stats.key_count, expires);
+    }
+    Ok(info)
+}
+
+///Whether `text` matches the glob-style `pattern`, as in Redis:
+///`*` and `?` match any bytes and any byte, `[abc]`, `[^abc]` and `[a-z]`
+///match a byte of the set, `\` escapes the next byte.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => glob(rest, text),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((b'[', rest)) => match (text.first(), rest.iter().position(|&b| b == b']')) {
+            (Some(&c), Some(end)) => {
+                let (negated, set) = match rest[..end].split_first() {
+                    Some((b'^', set)) => (true, set),
+                    _ => (false, &rest[..end]),
+                };
+                let mut matched = false;
+                let mut i = 0;
+                while i < set.len() {
+                    if i + 2 < set.len() && set[i + 1] == b'-' {
+                        let (low, high) = (set[i].min(set[i + 2]), set[i].max(set[i + 2]));
+                        matched |= low <= c && c <= high;
+                        i += 3;
+                    } else {
+                        matched |= set[i] == c;
+                        i += 1;
+                    }
+                }
+                matched != negated && glob(&rest[end + 1..], &text[1..])
+            }
+            //an unclosed `[` is taken literally
+            (Some(&c), None) => c == b'[' && glob(rest, &text[1..]),
+            (None, _) => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob(&rest[1..], &text[1..])
+        }
+        Some((&p, rest)) => text.first() == Some(&p) && glob(rest, &text[1..]),
+    }
+}
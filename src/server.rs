@@ -1,69 +1,738 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::auth::Session;
+use crate::common::{
+    AuthResponse, GetManyResponse, GetResponse, HistoryResponse, RemoteError, RemoveManyResponse,
+    RemoveResponse, Request, SetResponse, StatsResponse, WatchResponse,
+};
+use crate::http;
+use crate::protocol::{self, Protocol, Reply};
+use crate::resp::{self, RespState};
+use crate::shutdown::{Connection, Connections};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
-use log::{debug, error};
+use crate::tls::{ServerTls, Stream};
+use crate::{Accounts, Acl, KvsEngine, KvsError, Permission, Result, ShutdownHandle};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::{debug, error, info, warn};
 use serde_json::Deserializer;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
 };
 
+///bytes read from a connection at once
+pub(crate) const READ_BUF_SIZE: usize = 4096;
+///how often a watching connection checks whether the client has gone away
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+///how long a shutdown waits for the requests being served
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+///how long the rest of a refused request is read and thrown away
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The largest keys, values and requests a server accepts.
+///
+/// A request over `max_request_size` is not read any further, the server answers
+/// with `KvsError::RequestTooLarge` and closes the connection. Keys and values
+/// over their limit fail with `KvsError::KeyTooLarge` and `KvsError::ValueTooLarge`
+/// without reaching the engine. A request takes more bytes than its keys and values,
+/// in JSON escaped characters take up to six.
+///
+/// Engines enforce key and value limits of their own, for use without a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Longest key, in bytes
+    pub max_key_size: u64,
+    /// Longest value, in bytes
+    pub max_value_size: u64,
+    /// Most bytes a request may take on the wire, batches included
+    pub max_request_size: u64,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits {
+            max_key_size: 64 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+            max_request_size: 128 * 1024 * 1024,
+        }
+    }
+}
+
+impl SizeLimits {
+    ///every key and value of the request within the limits
+    pub(crate) fn check(&self, req: &Request) -> Result<()> {
+        match req {
+            Request::Get { key }
+            | Request::Remove { key }
+            | Request::GetAt { key, .. }
+            | Request::History { key } => self.check_key(key),
+            Request::Set { key, value } => self.check_pair(key, value),
+            Request::GetMany { keys } | Request::RemoveMany { keys } => {
+                keys.iter().try_for_each(|key| self.check_key(key))
+            }
+            Request::SetMany { pairs } => pairs
+                .iter()
+                .try_for_each(|(key, value)| self.check_pair(key, value)),
+            Request::Watch { .. } | Request::Stats | Request::Auth { .. } => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() as u64 > self.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len() as u64,
+                limit: self.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_pair(&self, key: &str, value: &str) -> Result<()> {
+        self.check_key(key)?;
+        if value.len() as u64 > self.max_value_size {
+            return Err(KvsError::ValueTooLarge {
+                size: value.len() as u64,
+                limit: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+}
+
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    limits: SizeLimits,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    resp_addr: Option<SocketAddr>,
+    resp: RespState,
+    http_addr: Option<SocketAddr>,
+    backup_dir: Option<Arc<PathBuf>>,
+    tls: Option<ServerTls>,
+    accounts: Option<Arc<Accounts>>,
+    acl: Option<Acl>,
+}
+
+///What a listener serves
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Service {
+    Kvs,
+    Resp,
+    Http,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool,
+            limits: SizeLimits::default(),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            resp_addr: None,
+            resp: RespState::default(),
+            http_addr: None,
+            backup_dir: None,
+            tls: None,
+            accounts: None,
+            acl: None,
+        }
+    }
+
+    /// Sets the largest keys, values and requests the server accepts.
+    pub fn with_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how long a shutdown waits for the requests being served, 5 seconds by default.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle stopping the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Also serves Redis clients on `addr`, speaking RESP.
+    ///
+    /// `GET`, `SET` with `EX`, `PX`, `NX` and `XX`, `DEL`, `EXISTS`, `KEYS`, `SCAN`,
+    /// `INCR`, `PING`, `ECHO`, `INFO`, `DBSIZE`, `SELECT 0` and `QUIT` are supported.
+    /// Expirations are kept in memory and forgotten on restart.
+    /// `SET NX`, `SET XX` and `INCR` are atomic among RESP clients only.
+    pub fn with_resp(mut self, addr: SocketAddr) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// Also serves HTTP clients on `addr`, with JSON bodies.
+    ///
+    /// `GET`, `PUT` and `DELETE` on `/keys/{key}` read, write and remove a key,
+    /// `PUT` taking `{"value": ...}`. `GET /keys` lists pairs in the order of their keys,
+    /// filtered by the `prefix`, `start` and `end` query parameters, `limit` of them
+    /// at a time. `GET /admin/stats` gives the engine's statistics, `POST /admin/compact`
+    /// compacts it and `POST /admin/backup` with `{"dir": ...}` copies the store
+    /// into a new directory of the backup directory, see `with_backup_dir`.
+    /// The `/admin` endpoints need clients authenticated with `with_accounts`,
+    /// a server without accounts refuses them.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
     }
 
+    /// Lets HTTP clients back the store up into new directories of `dir`.
+    ///
+    /// Clients name them relative to `dir` and cannot leave it,
+    /// without a backup directory `POST /admin/backup` is not served.
+    pub fn with_backup_dir<D: Into<PathBuf>>(mut self, dir: D) -> Self {
+        self.backup_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// Serves every listener over TLS only.
+    ///
+    /// Clients not speaking TLS, or not presenting a certificate
+    /// when `tls` verifies them, are disconnected.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Requires clients to authenticate with the credentials of `accounts`.
+    ///
+    /// Until a connection authenticates, its requests fail with
+    /// `KvsError::Unauthenticated`. RESP clients authenticate with `AUTH`,
+    /// HTTP requests carry a `Bearer` token or `Basic` password in `Authorization`.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = Some(Arc::new(accounts));
+        self
+    }
+
+    /// Only serves the requests `acl` grants to the identity of the connection.
+    ///
+    /// Other requests fail with `KvsError::PermissionDenied`, RESP commands
+    /// with a `NOPERM` error and HTTP requests with `403 Forbidden`.
+    /// Keep a clone of `acl` to reload its rules while the server runs.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Serves clients until the shutdown handle is used.
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
+        let tls = self.tls.as_ref().map(ServerTls::config).transpose()?;
+        let mut listeners = vec![(Service::Kvs, TcpListener::bind(addr)?)];
+        if let Some(addr) = self.resp_addr {
+            listeners.push((Service::Resp, TcpListener::bind(addr)?));
+        }
+        if let Some(addr) = self.http_addr {
+            listeners.push((Service::Http, TcpListener::bind(addr)?));
+        }
+        let (accepted, incoming) = channel::unbounded();
+        let mut acceptors = Vec::new();
+        for (service, listener) in listeners {
+            self.shutdown.listening_on(listener.local_addr()?);
+            acceptors.push(spawn_acceptor(
+                service,
+                listener,
+                accepted.clone(),
+                self.shutdown.clone(),
+            )?);
+        }
+        drop(accepted);
+        let sweeper = match self.resp_addr {
+            Some(_) => Some(
+                self.resp
+                    .spawn_sweeper(self.engine.clone(), self.shutdown.clone())?,
+            ),
+            None => None,
+        };
+
+        //ends once every acceptor has stopped
+        for (service, stream) in incoming {
             let engine = self.engine.clone();
+            let limits = self.limits;
+            let resp = self.resp.clone();
+            let backup_dir = self.backup_dir.clone();
+            let connections = self.shutdown.connections().clone();
+            let tls = tls.clone();
+            let session = Session::new(self.accounts.clone(), self.acl.clone());
             self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
+                Ok(tcp) => {
+                    let served =
+                        Stream::accept(tcp, tls.as_ref()).and_then(|stream| match service {
+                            Service::Kvs => serve(engine, stream, session, limits, &connections),
+                            Service::Resp => {
+                                serve_resp(engine, stream, session, resp, limits, &connections)
+                            }
+                            Service::Http => serve_http(
+                                engine,
+                                stream,
+                                session,
+                                limits,
+                                backup_dir.as_deref().map(PathBuf::as_path),
+                                &connections,
+                            ),
+                        });
+                    if let Err(e) = served {
                         error!("Error on serving client:{}", e);
                     }
                 }
                 Err(e) => error!("Connection failed:{}", e),
             })
         }
-        Ok(())
+        for acceptor in acceptors.into_iter().chain(sweeper) {
+            acceptor
+                .join()
+                .map_err(|_| KvsError::StringError("listener thread panicked".to_owned()))?;
+        }
+
+        self.shutdown.drain(self.shutdown_timeout);
+        self.engine.sync()
+    }
+}
+
+///accept connections on their own thread until a shutdown, the listener is closed then
+fn spawn_acceptor(
+    service: Service,
+    listener: TcpListener,
+    accepted: Sender<(Service, io::Result<TcpStream>)>,
+    shutdown: ShutdownHandle,
+) -> Result<JoinHandle<()>> {
+    Ok(thread::Builder::new().spawn(move || {
+        while !shutdown.is_requested() {
+            let stream = listener.accept().map(|(stream, _)| stream);
+            if shutdown.is_requested() || accepted.send((service, stream)).is_err() {
+                break;
+            }
+        }
+    })?)
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    session: Session,
+    limits: SizeLimits,
+    connections: &Connections,
+) -> Result<()> {
+    let connection = connections.add(stream.tcp())?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    match protocol::accept(&mut reader)? {
+        Protocol::Json => serve_json(engine, &stream, session, reader, writer, limits, connection),
+        Protocol::Binary => {
+            writer.write_all(&protocol::hello())?;
+            writer.flush()?;
+            serve_binary(engine, &stream, session, reader, writer, limits, connection)
+        }
     }
 }
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
+
+fn serve_json<E: KvsEngine>(
+    engine: E,
+    stream: &Stream,
+    mut session: Session,
+    reader: BufReader<&Stream>,
+    mut writer: BufWriter<&Stream>,
+    limits: SizeLimits,
+    connection: Connection,
+) -> Result<()> {
+    let peer_addr = stream.tcp().peer_addr()?;
+    let budget = Rc::new(Cell::new(limits.max_request_size));
+    let reader = RequestLimiter {
+        inner: reader,
+        budget: Rc::clone(&budget),
+    };
     let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-    macro_rules! send_resp {
+    for req in req_reader {
+        let req = match req {
+            Ok(req) => req,
+            Err(_) if budget.get() == 0 => {
+                writer.flush()?;
+                return refuse_request(stream, limits, Reply::Json);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        budget.set(limits.max_request_size);
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        if let Some(resp) = guard(&mut session, &req, peer_addr, Reply::Json)? {
+            writer.write_all(&resp)?;
+            writer.flush()?;
+            continue;
+        }
+        if let Request::Watch { prefix } = req {
+            //a subscription lives as long as the client does,
+            //so it gets its own thread instead of holding a pool thread
+            spawn_watch(engine, prefix, stream.clone(), connection, Reply::Json)?;
+            return Ok(());
+        }
+        writer.write_all(&respond(&engine, req, &limits, peer_addr, Reply::Json)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+///frames are read whole, so a malformed request is answered and skipped.
+///Responses to pipelined requests are flushed together.
+fn serve_binary<E: KvsEngine>(
+    engine: E,
+    stream: &Stream,
+    mut session: Session,
+    mut reader: BufReader<&Stream>,
+    mut writer: BufWriter<&Stream>,
+    limits: SizeLimits,
+    connection: Connection,
+) -> Result<()> {
+    let peer_addr = stream.tcp().peer_addr()?;
+    while let Some(header) = protocol::read_header(&mut reader)? {
+        let reply = header.reply();
+        if header.frame_len() > limits.max_request_size {
+            writer.flush()?;
+            return refuse_request(stream, limits, reply);
+        }
+        let payload = protocol::read_payload(&mut reader, &header)?;
+        match protocol::decode_request(header.opcode, &payload) {
+            Ok(req) => {
+                debug!(
+                    "Receive request {} from {}: {:?}",
+                    header.id, peer_addr, req
+                );
+                if let Some(resp) = guard(&mut session, &req, peer_addr, reply)? {
+                    writer.write_all(&resp)?;
+                } else if let Request::Watch { prefix } = req {
+                    writer.flush()?;
+                    spawn_watch(engine, prefix, stream.clone(), connection, reply)?;
+                    return Ok(());
+                } else {
+                    writer.write_all(&respond(&engine, req, &limits, peer_addr, reply)?)?;
+                }
+            }
+            Err(e) => {
+                warn!("Malformed request {} from {}: {}", header.id, peer_addr, e);
+                writer.write_all(&reply.encode(&SetResponse::Err(e.into()))?)?;
+            }
+        }
+        //the client waits for the responses once it stops sending
+        if !protocol::has_frame(reader.buffer()) {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn serve_resp<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    mut session: Session,
+    state: RespState,
+    limits: SizeLimits,
+    connections: &Connections,
+) -> Result<()> {
+    let _connection = connections.add(stream.tcp())?;
+    let peer_addr = stream.tcp().peer_addr()?;
+    let mut stream = &stream;
+    //bytes received but not parsed yet, a command may span several reads
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUF_SIZE];
+    loop {
+        let parsed = resp::parse_commands(&buf, &limits);
+        buf.drain(..parsed.len);
+        //pipelined commands are answered together
+        let mut out = Vec::new();
+        for args in parsed.commands {
+            debug!(
+                "Receive RESP command from {}: {:?}",
+                peer_addr,
+                args.first().map(|name| String::from_utf8_lossy(name))
+            );
+            let quit = resp::is_quit(&args);
+            out.extend(resp::execute(&engine, &state, &mut session, &limits, args));
+            if quit {
+                stream.write_all(&out)?;
+                return Ok(());
+            }
+        }
+        if let Some(e) = parsed.error {
+            out.extend(resp::protocol_error(&e));
+            stream.write_all(&out)?;
+            return Err(e);
+        }
+        stream.write_all(&out)?;
+
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            debug!("Client {} disconnected", peer_addr);
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+fn serve_http<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    session: Session,
+    limits: SizeLimits,
+    backup_dir: Option<&Path>,
+    connections: &Connections,
+) -> Result<()> {
+    let _connection = connections.add(stream.tcp())?;
+    let peer_addr = stream.tcp().peer_addr()?;
+    let mut stream = &stream;
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_BUF_SIZE];
+    loop {
+        let parsed = http::parse_requests(&buf, &limits);
+        buf.drain(..parsed.len);
+        let mut out = Vec::new();
+        for req in parsed.requests {
+            debug!(
+                "Receive HTTP request from {}: {} {}",
+                peer_addr, req.method, req.target
+            );
+            out.extend(http::respond(&engine, &limits, &session, backup_dir, &req));
+            if req.close {
+                stream.write_all(&out)?;
+                return Ok(());
+            }
+        }
+        if let Some(e) = parsed.error {
+            out.extend(http::protocol_error(&e));
+            stream.write_all(&out)?;
+            return Err(e);
+        }
+        stream.write_all(&out)?;
+
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            debug!("Client {} disconnected", peer_addr);
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+///Reads the requests of a connection, failing once the current one
+///takes more than its budget so it is never buffered whole.
+///The connection resets the budget after every request.
+struct RequestLimiter<R> {
+    inner: R,
+    budget: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for RequestLimiter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let budget = self.budget.get();
+        if budget == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let len = (buf.len() as u64).min(budget) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        self.budget.set(budget - read as u64);
+        Ok(read)
+    }
+}
+
+///Answer a request over the size limit and drop the connection.
+///What the client still sends is read and thrown away for a while,
+///so it gets to finish writing and read the answer.
+pub(crate) fn refuse_request(mut stream: &Stream, limits: SizeLimits, reply: Reply) -> Result<()> {
+    warn!(
+        "Request from {} is over {} bytes, closing the connection",
+        stream.tcp().peer_addr()?,
+        limits.max_request_size
+    );
+    //every response type has the same `Err`
+    let resp = SetResponse::Err(RemoteError::RequestTooLarge {
+        limit: limits.max_request_size,
+    });
+    stream.write_all(&reply.encode(&resp)?)?;
+    stream.shutdown_write()?;
+    stream.tcp().set_read_timeout(Some(DRAIN_TIMEOUT))?;
+    //the client may have gone away, there is nobody left to tell
+    let _ = io::copy(&mut stream.take(limits.max_request_size), &mut io::sink());
+    Ok(())
+}
+
+///The answer to a request the connection's session decides on: authentication,
+///and what the connection may not do. `None` for requests to run on the engine.
+pub(crate) fn guard(
+    session: &mut Session,
+    req: &Request,
+    peer_addr: SocketAddr,
+    reply: Reply,
+) -> Result<Option<Vec<u8>>> {
+    match req {
+        Request::Auth { credentials } => {
+            let resp = match session.authenticate(credentials) {
+                Ok(identity) => {
+                    info!("{} authenticated as {}", peer_addr, identity);
+                    AuthResponse::Ok(identity)
+                }
+                Err(e) => {
+                    warn!("Authentication of {} failed: {}", peer_addr, e);
+                    AuthResponse::Err(e.into())
+                }
+            };
+            Ok(Some(reply.encode(&resp)?))
+        }
+        //every response type has the same `Err`
+        _ => match session.check().and_then(|()| authorize(session, req)) {
+            Ok(()) => Ok(None),
+            Err(e) => {
+                if let KvsError::PermissionDenied { .. } = e {
+                    warn!("Request of {} refused: {}", peer_addr, e);
+                }
+                Ok(Some(reply.encode(&SetResponse::Err(e.into()))?))
+            }
+        },
+    }
+}
+
+///fails unless the ACL grants the request every permission it needs
+fn authorize(session: &Session, req: &Request) -> Result<()> {
+    match req {
+        Request::Get { key } | Request::GetAt { key, .. } | Request::History { key } => {
+            session.permit(Permission::Read, key)
+        }
+        Request::GetMany { keys } => keys
+            .iter()
+            .try_for_each(|key| session.permit(Permission::Read, key)),
+        Request::Watch { prefix } => session.permit_prefix(Permission::Read, prefix),
+        Request::Set { key, .. } => session.permit(Permission::Write, key),
+        Request::SetMany { pairs } => pairs
+            .iter()
+            .try_for_each(|(key, _)| session.permit(Permission::Write, key)),
+        Request::Remove { key } => session.permit(Permission::Delete, key),
+        Request::RemoveMany { keys } => keys
+            .iter()
+            .try_for_each(|key| session.permit(Permission::Delete, key)),
+        Request::Stats => session.permit_admin(),
+        Request::Auth { .. } => Ok(()),
+    }
+}
+
+///Run the request on the engine and encode the response.
+///Watch requests keep the connection, they are served by `spawn_watch`.
+pub(crate) fn respond<E: KvsEngine>(
+    engine: &E,
+    req: Request,
+    limits: &SizeLimits,
+    peer_addr: SocketAddr,
+    reply: Reply,
+) -> Result<Vec<u8>> {
+    macro_rules! resp {
         ($resp:expr) => {{
             let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
+            debug!("Response to {}: {:?}", peer_addr, resp);
+            reply.encode(&resp)?
         }};
     }
-    for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
+    //too large keys and values never reach the engine
+    let checked = limits.check(&req);
+    Ok(match req {
+        Request::Get { key } => resp!(match checked.and_then(|()| engine.get(key)) {
+            Ok(value) => GetResponse::Ok(value),
+            Err(e) => GetResponse::Err(e.into()),
+        }),
+        Request::Set { key, value } => resp!(match checked.and_then(|()| engine.set(key, value)) {
+            Ok(_) => SetResponse::Ok(()),
+            Err(e) => SetResponse::Err(e.into()),
+        }),
+        Request::Remove { key } => resp!(match checked.and_then(|()| engine.remove(key)) {
+            Ok(_) => RemoveResponse::Ok(()),
+            Err(e) => RemoveResponse::Err(e.into()),
+        }),
+        Request::Stats => resp!(match checked.and_then(|()| engine.stats()) {
+            Ok(stats) => StatsResponse::Ok(stats),
+            Err(e) => StatsResponse::Err(e.into()),
+        }),
+        Request::GetAt { key, seq } => {
+            resp!(match checked.and_then(|()| engine.get_at(key, seq)) {
                 Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
-            }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
-            }),
+                Err(e) => GetResponse::Err(e.into()),
+            })
         }
-    }
+        Request::History { key } => resp!(match checked.and_then(|()| engine.history(key)) {
+            Ok(versions) => HistoryResponse::Ok(versions),
+            Err(e) => HistoryResponse::Err(e.into()),
+        }),
+        Request::GetMany { keys } => resp!(match checked.and_then(|()| engine.get_many(keys)) {
+            Ok(values) => GetManyResponse::Ok(values),
+            Err(e) => GetManyResponse::Err(e.into()),
+        }),
+        Request::SetMany { pairs } => resp!(match checked.and_then(|()| engine.set_many(pairs)) {
+            Ok(_) => SetResponse::Ok(()),
+            Err(e) => SetResponse::Err(e.into()),
+        }),
+        Request::RemoveMany { keys } => {
+            resp!(match checked.and_then(|()| engine.remove_many(keys)) {
+                Ok(removed) => RemoveManyResponse::Ok(removed),
+                Err(e) => RemoveManyResponse::Err(e.into()),
+            })
+        }
+        Request::Watch { .. } => unreachable!("watch requests are served by spawn_watch"),
+        Request::Auth { .. } => unreachable!("auth requests are answered by guard"),
+    })
+}
+
+///serve a subscription on its own thread, it stays registered until it ends
+pub(crate) fn spawn_watch<E: KvsEngine>(
+    engine: E,
+    prefix: String,
+    stream: Stream,
+    connection: Connection,
+    reply: Reply,
+) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        let _connection = connection;
+        if let Err(e) = serve_watch(engine, prefix, stream, reply) {
+            error!("Error on serving watcher:{}", e);
+        }
+    })?;
     Ok(())
 }
+
+///stream events to the client until it goes away
+fn serve_watch<E: KvsEngine>(
+    engine: E,
+    prefix: String,
+    stream: Stream,
+    reply: Reply,
+) -> Result<()> {
+    let peer_addr = stream.tcp().peer_addr()?;
+    let mut writer = BufWriter::new(&stream);
+    let mut send_resp = |resp: WatchResponse| -> Result<()> {
+        writer.write_all(&reply.encode(&resp)?)?;
+        writer.flush()?;
+        debug!("Event sent to {}: {:?}", peer_addr, resp);
+        Ok(())
+    };
+    let mut watcher = match engine.watch(prefix) {
+        Ok(watcher) => watcher,
+        Err(e) => return send_resp(WatchResponse::Err(e.into())),
+    };
+    loop {
+        match watcher.next_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => send_resp(WatchResponse::Event(event))?,
+            Err(RecvTimeoutError::Timeout) => {
+                if stream.is_closed()? {
+                    debug!("Watcher {} disconnected", peer_addr);
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::Result;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Stops a running `KvsServer` or `AsyncKvsServer` from another thread.
+///
+/// The server stops accepting connections, stops reading new requests,
+/// gives the requests it is serving until its shutdown timeout to finish,
+/// syncs the engine and returns from `run`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    ///where the server listens, connecting wakes up the accept loops
+    addrs: Mutex<Vec<SocketAddr>>,
+    connections: Connections,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down, `run` returns once it has.
+    /// Asking before the server runs makes it return right after binding.
+    pub fn shutdown(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        for addr in self.0.addrs.lock().unwrap().iter() {
+            //the accept loops check the flag after every connection
+            if let Err(e) = TcpStream::connect(addr) {
+                error!("cannot wake up the server at {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Tells whether a shutdown was asked for.
+    pub fn is_requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    ///remember where the server listens, once per listener,
+    ///it must check `is_requested` afterwards
+    pub(crate) fn listening_on(&self, addr: SocketAddr) {
+        let addr = match addr {
+            SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+            }
+            SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+            }
+            addr => addr,
+        };
+        self.0.addrs.lock().unwrap().push(addr);
+    }
+
+    pub(crate) fn connections(&self) -> &Connections {
+        &self.0.connections
+    }
+
+    ///stop reading from every connection and wait at most `timeout` for them to finish
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let connections = self.connections();
+        info!(
+            "Shutting down, waiting for {} connections",
+            connections.len()
+        );
+        connections.close_reads();
+        let left = connections.wait_idle(timeout);
+        if left > 0 {
+            warn!("{} connections still busy after {:?}", left, timeout);
+        }
+    }
+}
+
+///The connections a server is serving, so a shutdown can stop reading from them
+///and wait for them. Each one stays registered until its `Connection` is dropped.
+#[derive(Clone, Default)]
+pub(crate) struct Connections(Arc<(Mutex<ConnectionList>, Condvar)>);
+
+#[derive(Default)]
+struct ConnectionList {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+    closing: bool,
+}
+
+impl Connections {
+    pub fn add(&self, tcp: &TcpStream) -> Result<Connection> {
+        let tcp = tcp.try_clone()?;
+        let mut list = (self.0).0.lock().unwrap();
+        //accepted before the shutdown but registered after it
+        if list.closing {
+            let _ = tcp.shutdown(Shutdown::Read);
+        }
+        let id = list.next_id;
+        list.next_id += 1;
+        list.streams.insert(id, tcp);
+        Ok(Connection {
+            connections: self.clone(),
+            id,
+        })
+    }
+
+    fn len(&self) -> usize {
+        (self.0).0.lock().unwrap().streams.len()
+    }
+
+    ///blocked reads return end of file, responses can still be written
+    fn close_reads(&self) {
+        let mut list = (self.0).0.lock().unwrap();
+        list.closing = true;
+        for tcp in list.streams.values() {
+            //the peer may be gone already
+            let _ = tcp.shutdown(Shutdown::Read);
+        }
+    }
+
+    ///how many connections are left after at most `timeout`
+    fn wait_idle(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let (list, idle) = &*self.0;
+        let mut list = list.lock().unwrap();
+        while !list.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            list = idle.wait_timeout(list, deadline - now).unwrap().0;
+        }
+        list.streams.len()
+    }
+}
+
+///A registered connection, it leaves the registry when dropped.
+pub(crate) struct Connection {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let (list, idle) = &*self.connections.0;
+        list.lock().unwrap().streams.remove(&self.id);
+        idle.notify_all();
+    }
+}
@@ -0,0 +1,356 @@
+use crate::{KvsError, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// TLS settings of a server: its certificate chain and private key, and optionally
+/// the CAs that must have signed the certificates clients present.
+pub struct ServerTls {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<RootCertStore>,
+}
+
+impl ServerTls {
+    /// Loads the server's certificate chain and private key from PEM files.
+    pub fn from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, key: Q) -> Result<Self> {
+        Ok(ServerTls {
+            certs: load_certs(cert.as_ref())?,
+            key: load_key(key.as_ref())?,
+            client_roots: None,
+        })
+    }
+
+    /// Only accepts clients presenting a certificate signed by a CA of the PEM bundle `ca`.
+    pub fn with_client_ca<P: AsRef<Path>>(mut self, ca: P) -> Result<Self> {
+        self.client_roots = Some(load_roots(ca.as_ref())?);
+        Ok(self)
+    }
+
+    pub(crate) fn config(&self) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_roots {
+            Some(roots) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots.clone()),
+                    provider(),
+                )
+                .build()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(builder.with_single_cert(
+            self.certs.clone(),
+            self.key.clone_key(),
+        )?))
+    }
+}
+
+/// TLS settings of a client: the CAs that may sign the server's certificate,
+/// the name the certificate must be for, and optionally a certificate
+/// to present to servers verifying their clients.
+pub struct ClientTls {
+    roots: RootCertStore,
+    server_name: ServerName<'static>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl ClientTls {
+    /// Trusts the server certificates for `server_name` signed by a CA of the PEM bundle `ca`.
+    ///
+    /// `server_name` is a DNS name or an IP address.
+    pub fn from_ca_file<P: AsRef<Path>>(ca: P, server_name: &str) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| rustls::Error::General(format!("invalid server name {}", server_name)))?;
+        Ok(ClientTls {
+            roots: load_roots(ca.as_ref())?,
+            server_name,
+            identity: None,
+        })
+    }
+
+    /// Presents the certificate chain and private key of these PEM files to the server.
+    pub fn with_client_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        mut self,
+        cert: P,
+        key: Q,
+    ) -> Result<Self> {
+        self.identity = Some((load_certs(cert.as_ref())?, load_key(key.as_ref())?));
+        Ok(self)
+    }
+
+    fn config(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots.clone());
+        let config = match &self.identity {
+            Some((certs, key)) => builder.with_client_auth_cert(certs.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::StringError(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| KvsError::StringError(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+///a TLS session over a socket, of either side
+trait TlsSession: Read + Write + Send {
+    fn send_close_notify(&mut self);
+}
+
+impl TlsSession for StreamOwned<ServerConnection, TcpStream> {
+    fn send_close_notify(&mut self) {
+        self.conn.send_close_notify();
+    }
+}
+
+impl TlsSession for StreamOwned<ClientConnection, TcpStream> {
+    fn send_close_notify(&mut self) {
+        self.conn.send_close_notify();
+    }
+}
+
+///A blocking connection, encrypted or not. Clones share it, so that
+///a reader and a writer, or a thread streaming events, can each hold one.
+#[derive(Clone)]
+pub(crate) struct Stream {
+    tcp: Arc<TcpStream>,
+    tls: Option<Arc<Mutex<dyn TlsSession>>>,
+}
+
+impl Stream {
+    pub fn plain(tcp: TcpStream) -> Stream {
+        Stream {
+            tcp: Arc::new(tcp),
+            tls: None,
+        }
+    }
+
+    ///the server side of a connection, encrypted with `config` if there is one.
+    ///The handshake happens on the first read or write.
+    pub fn accept(tcp: TcpStream, config: Option<&Arc<ServerConfig>>) -> Result<Stream> {
+        match config {
+            Some(config) => Stream::server(ServerConnection::new(Arc::clone(config))?, tcp),
+            None => Ok(Stream::plain(tcp)),
+        }
+    }
+
+    ///the client side of a connection, after the handshake, so that a server
+    ///that fails verification is refused here rather than on the first request
+    pub fn connect(mut tcp: TcpStream, tls: &ClientTls) -> Result<Stream> {
+        let mut conn = ClientConnection::new(tls.config()?, tls.server_name.clone())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        Ok(Stream {
+            tcp: Arc::new(tcp.try_clone()?),
+            tls: Some(Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))),
+        })
+    }
+
+    fn server(conn: ServerConnection, tcp: TcpStream) -> Result<Stream> {
+        Ok(Stream {
+            tcp: Arc::new(tcp.try_clone()?),
+            tls: Some(Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))),
+        })
+    }
+
+    ///the socket under the session
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    ///ends the writing side, telling a TLS peer first
+    pub fn shutdown_write(&self) -> Result<()> {
+        if let Some(tls) = &self.tls {
+            let mut tls = tls.lock().unwrap();
+            tls.send_close_notify();
+            tls.flush()?;
+        }
+        self.tcp.shutdown(std::net::Shutdown::Write)?;
+        Ok(())
+    }
+
+    ///check without blocking whether the peer has shut down its side of the connection
+    pub fn is_closed(&self) -> Result<bool> {
+        self.tcp.set_nonblocking(true)?;
+        let closed = match &self.tls {
+            //what the client sends meanwhile is dropped, it waits for events only
+            Some(_) => (&*self).read(&mut [0; 64]),
+            None => self.tcp.peek(&mut [0; 1]),
+        };
+        self.tcp.set_nonblocking(false)?;
+        match closed {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.tls {
+            //our protocols delimit their messages, so a peer closing
+            //without a close_notify is an ordinary end of the stream
+            Some(tls) => match tls.lock().unwrap().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                res => res,
+            },
+            None => (&*self.tcp).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.tls {
+            Some(tls) => tls.lock().unwrap().write(buf),
+            None => (&*self.tcp).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.tls {
+            Some(tls) => tls.lock().unwrap().flush(),
+            None => (&*self.tcp).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+///A connection of the async server, encrypted or not
+pub(crate) enum AsyncStream {
+    Plain(tokio::net::TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>),
+}
+
+impl AsyncStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            AsyncStream::Plain(tcp) => tcp.peer_addr(),
+            AsyncStream::Tls(tls) => tls.get_ref().0.peer_addr(),
+        }
+    }
+
+    ///the same connection for blocking code, along with what the session buffered
+    pub fn into_std(self) -> Result<Stream> {
+        match self {
+            AsyncStream::Plain(tcp) => {
+                let tcp = tcp.into_std()?;
+                tcp.set_nonblocking(false)?;
+                Ok(Stream::plain(tcp))
+            }
+            AsyncStream::Tls(tls) => {
+                let (tcp, conn) = tls.into_inner();
+                let tcp = tcp.into_std()?;
+                tcp.set_nonblocking(false)?;
+                Stream::server(conn, tcp)
+            }
+        }
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            AsyncStream::Tls(tls) => match Pin::new(tls).poll_read(cx, buf) {
+                //like `Stream`, a missing close_notify is not an error
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Poll::Ready(Ok(()))
+                }
+                poll => poll,
+            },
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            AsyncStream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            AsyncStream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            AsyncStream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
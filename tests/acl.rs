@@ -0,0 +1,311 @@
+use assert_cmd::prelude::*;
+use common::{exchange, http};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Accounts, Acl, AsyncKvsServer, Credentials, KvStore, KvsClient, KvsError, KvsServer,
+    Permission, Result, ShutdownHandle,
+};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+const RULES: &str = "# team-a owns its keys, team-b may not remove its own
+team-a read,write,delete team-a/*
+team-b read,write team-b/*
+*      read public/*
+ops    admin,read *
+";
+
+fn write_accounts(path: &Path) -> Result<()> {
+    let tokens: Vec<_> = ["team-a", "team-b", "ops"]
+        .iter()
+        .map(|&name| (name, token(name)))
+        .collect();
+    let accounts: Vec<_> = tokens
+        .iter()
+        .map(|(name, token)| (*name, "token", token.as_str()))
+        .collect();
+    common::write_accounts(path, &accounts)
+}
+
+fn token(name: &str) -> String {
+    format!("{}-0123456789abcdef", name)
+}
+
+fn connect(addr: &str, name: &str) -> Result<KvsClient> {
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate(Credentials::Token(token(name)))?;
+    Ok(client)
+}
+
+fn denied<T>(result: Result<T>, identity: &str, permission: Permission, scope: &str) -> bool {
+    match result {
+        Err(KvsError::PermissionDenied {
+            identity: i,
+            permission: p,
+            scope: s,
+        }) => i == identity && p == permission && s == scope,
+        _ => false,
+    }
+}
+
+fn http_status(addr: &str, method: &str, target: &str, name: &str) -> String {
+    let authorization = format!("Bearer {}", token(name));
+    let body = r#"{"value":"v"}"#;
+    let (status, _) = http(addr, method, target, Some(&authorization), body);
+    status
+}
+
+fn acl_clients<F>(addrs: [&'static str; 3], start: F) -> Result<()>
+where
+    F: FnOnce(KvStore, Accounts, Acl) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let [addr, resp_addr, http_addr] = addrs;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let accounts_path = temp_dir.path().join("accounts");
+    write_accounts(&accounts_path)?;
+    let acl_path = temp_dir.path().join("acl");
+    fs::write(&acl_path, RULES)?;
+    let acl = Acl::from_file(&acl_path)?;
+    let store = KvStore::open(temp_dir.path().join("data"))?;
+    let (handle, server) = start(store, Accounts::from_file(&accounts_path)?, acl.clone());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut a = connect(addr, "team-a")?;
+    a.set("team-a/x".to_owned(), "1".to_owned())?;
+    assert_eq!(a.get("team-a/x".to_owned())?, Some("1".to_owned()));
+    assert_eq!(a.get("public/x".to_owned())?, None);
+    assert!(denied(
+        a.set("team-b/x".to_owned(), "1".to_owned()),
+        "team-a",
+        Permission::Write,
+        "\"team-b/x\""
+    ));
+    assert!(denied(
+        a.get("team-b/x".to_owned()),
+        "team-a",
+        Permission::Read,
+        "\"team-b/x\""
+    ));
+    assert!(denied(
+        a.set("public/x".to_owned(), "1".to_owned()),
+        "team-a",
+        Permission::Write,
+        "\"public/x\""
+    ));
+    assert!(denied(a.stats(), "team-a", Permission::Admin, "the store"));
+    // a request on several keys needs its permission on all of them
+    assert!(a
+        .set_many(vec![
+            ("team-a/y".to_owned(), "1".to_owned()),
+            ("team-b/y".to_owned(), "1".to_owned()),
+        ])
+        .is_err());
+    assert_eq!(a.get("team-a/y".to_owned())?, None);
+    // and a watch on every key of its prefix
+    let events = connect(addr, "team-a")?.watch("team-".to_owned())?;
+    assert!(matches!(
+        events.take(1).collect::<Vec<_>>()[..],
+        [Err(KvsError::PermissionDenied { .. })]
+    ));
+    a.remove("team-a/x".to_owned())?;
+
+    let mut b = connect(addr, "team-b")?;
+    b.set("team-b/x".to_owned(), "1".to_owned())?;
+    assert!(denied(
+        b.remove("team-b/x".to_owned()),
+        "team-b",
+        Permission::Delete,
+        "\"team-b/x\""
+    ));
+    let mut ops = connect(addr, "ops")?;
+    assert_eq!(ops.get("team-b/x".to_owned())?, Some("1".to_owned()));
+    assert_eq!(ops.stats()?.key_count, 1);
+    assert!(ops.set("team-b/x".to_owned(), "2".to_owned()).is_err());
+
+    // new rules apply to the connections already open
+    fs::write(
+        &acl_path,
+        RULES.replace("team-b read,write ", "team-b read,write,delete "),
+    )?;
+    acl.reload()?;
+    b.remove("team-b/x".to_owned())?;
+    // and invalid ones are not applied
+    fs::write(&acl_path, "team-b read,write,delete,drop *\n")?;
+    assert!(acl.reload().is_err());
+    assert!(b.set("team-a/x".to_owned(), "1".to_owned()).is_err());
+    assert!(b.get("public/x".to_owned()).is_ok());
+
+    // RESP commands are refused with NOPERM
+    let auth = |name: &str| format!("AUTH {}\r\n", token(name));
+    assert_eq!(
+        exchange(
+            resp_addr,
+            &(auth("team-a") + "SET team-b/x v\r\nSET team-a/x v\r\nDBSIZE\r\n"),
+            4
+        ),
+        [
+            "+OK",
+            "-NOPERM permission denied: team-a has no write permission on \"team-b/x\"",
+            "+OK",
+            "-NOPERM permission denied: team-a has no admin permission on the store",
+        ]
+    );
+    assert_eq!(
+        exchange(resp_addr, &(auth("team-a") + "KEYS *\r\n"), 2),
+        [
+            "+OK",
+            "-NOPERM permission denied: team-a has no read permission on keys starting with \"\"",
+        ]
+    );
+    assert_eq!(
+        exchange(resp_addr, &(auth("ops") + "DBSIZE\r\n"), 2),
+        ["+OK", ":1"]
+    );
+
+    // HTTP requests with 403 Forbidden
+    let forbidden = "HTTP/1.1 403 Forbidden";
+    assert_eq!(
+        http_status(http_addr, "PUT", "/keys/team-b%2Fz", "team-a"),
+        forbidden
+    );
+    assert_eq!(
+        http_status(http_addr, "PUT", "/keys/team-a%2Fz", "team-a"),
+        "HTTP/1.1 204 No Content"
+    );
+    assert_eq!(
+        http_status(http_addr, "GET", "/keys?prefix=team-a/", "team-a"),
+        "HTTP/1.1 200 OK"
+    );
+    assert_eq!(http_status(http_addr, "GET", "/keys", "team-a"), forbidden);
+    assert_eq!(
+        http_status(http_addr, "GET", "/admin/stats", "team-a"),
+        forbidden
+    );
+    assert_eq!(
+        http_status(http_addr, "GET", "/admin/stats", "ops"),
+        "HTTP/1.1 200 OK"
+    );
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn kvs_server_acl() -> Result<()> {
+    let addrs = ["127.0.0.1:4041", "127.0.0.1:4042", "127.0.0.1:4043"];
+    acl_clients(addrs, |store, accounts, acl| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .with_resp(addrs[1].parse().unwrap())
+            .with_http(addrs[2].parse().unwrap())
+            .with_accounts(accounts)
+            .with_acl(acl);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run(addrs[0])))
+    })
+}
+
+#[test]
+fn async_server_acl() -> Result<()> {
+    let addrs = ["127.0.0.1:4044", "127.0.0.1:4045", "127.0.0.1:4046"];
+    acl_clients(addrs, |store, accounts, acl| {
+        let server = AsyncKvsServer::new(store, 2)
+            .with_resp(addrs[1].parse().unwrap())
+            .with_http(addrs[2].parse().unwrap())
+            .with_accounts(accounts)
+            .with_acl(acl);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run(addrs[0])))
+    })
+}
+
+#[test]
+fn invalid_acl_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("acl");
+    for rules in &[
+        "team-a read team-a/\n",
+        "team-a read,drop team-a/*\n",
+        "team-a read\n",
+        "team-a read, team-a/*\n",
+    ] {
+        fs::write(&path, rules)?;
+        match Acl::from_file(&path) {
+            Err(KvsError::StringError(e)) => assert!(
+                e.ends_with(":1: expected `IDENTITY PERMISSIONS PREFIX*`"),
+                "{}",
+                e
+            ),
+            _ => panic!("{:?} was accepted", rules),
+        }
+    }
+    assert!(Acl::from_file(temp_dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+// `kvs-server --acl` should refuse what the rules do not grant, and reload them on SIGHUP
+#[test]
+fn cli_acl() {
+    let addr = "127.0.0.1:4047";
+    let temp_dir = TempDir::new().unwrap();
+    let accounts_path = temp_dir.path().join("accounts");
+    write_accounts(&accounts_path).unwrap();
+    let acl_path = temp_dir.path().join("acl");
+    fs::write(&acl_path, RULES).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .arg("--acl")
+        .arg(&acl_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--accounts"));
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .arg("--accounts")
+        .arg(&accounts_path)
+        .arg("--acl")
+        .arg(&acl_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--token", &token("team-b")]);
+        cmd
+    };
+    client(&["set", "team-b/x", "1"]).assert().success();
+    client(&["rm", "team-b/x"])
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+
+    fs::write(
+        &acl_path,
+        RULES.replace("team-b read,write ", "team-b read,write,delete "),
+    )
+    .unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    client(&["rm", "team-b/x"]).assert().success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
@@ -0,0 +1,257 @@
+use assert_cmd::prelude::*;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::{exchange, http, write_accounts};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Accounts, AsyncKvsServer, Credentials, KvStore, KvsClient, KvsError, KvsServer, Protocol,
+    Result, ShutdownHandle,
+};
+use predicates::str::contains;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+fn token() -> Credentials {
+    Credentials::Token(TOKEN.to_owned())
+}
+
+fn password(user: &str, password: &str) -> Credentials {
+    Credentials::Password {
+        user: user.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+fn http_get(addr: &str, authorization: Option<&str>) -> (String, Vec<String>) {
+    http(addr, "GET", "/keys/key1", authorization, "")
+}
+
+fn authenticated_clients<F>(addrs: [&'static str; 3], start: F) -> Result<()>
+where
+    F: FnOnce(KvStore, Accounts) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let [addr, resp_addr, http_addr] = addrs;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let accounts_path = temp_dir.path().join("accounts");
+    write_accounts(
+        &accounts_path,
+        &[("alice", "password", "secret"), ("ci", "token", TOKEN)],
+    )?;
+    let store = KvStore::open(temp_dir.path().join("data"))?;
+    let (handle, server) = start(store, Accounts::from_file(&accounts_path)?);
+    thread::sleep(Duration::from_secs(1));
+
+    // nothing is served before the client authenticates
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthenticated)
+    ));
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::Unauthenticated)
+    ));
+    assert!(matches!(
+        client.authenticate(Credentials::Token("wrong".to_owned())),
+        Err(KvsError::InvalidCredentials)
+    ));
+    assert!(matches!(
+        client.authenticate(password("alice", "wrong")),
+        Err(KvsError::InvalidCredentials)
+    ));
+    assert!(matches!(
+        client.authenticate(password("ci", TOKEN)),
+        Err(KvsError::InvalidCredentials)
+    ));
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::Unauthenticated)
+    ));
+    let events = KvsClient::connect(addr)?.watch(String::new())?;
+    assert!(matches!(
+        events.take(1).collect::<Vec<_>>()[..],
+        [Err(KvsError::Unauthenticated)]
+    ));
+
+    assert_eq!(client.authenticate(token())?, "ci");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    // a failed attempt keeps the identity
+    assert!(client.authenticate(password("alice", "wrong")).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut client = KvsClient::connect_with(addr, Protocol::Binary)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthenticated)
+    ));
+    assert_eq!(client.authenticate(password("alice", "secret"))?, "alice");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // RESP clients use AUTH
+    assert_eq!(
+        exchange(resp_addr, "GET key1\r\n", 1),
+        ["-NOAUTH Authentication required."]
+    );
+    assert_eq!(
+        exchange(resp_addr, "AUTH wrong\r\nGET key1\r\n", 2),
+        [
+            "-WRONGPASS invalid username-password pair or user is disabled.",
+            "-NOAUTH Authentication required."
+        ]
+    );
+    assert_eq!(
+        exchange(resp_addr, &format!("AUTH {}\r\nGET key1\r\n", TOKEN), 3),
+        ["+OK", "$6", "value1"]
+    );
+    assert_eq!(
+        exchange(resp_addr, "AUTH alice secret\r\nGET key1\r\n", 3),
+        ["+OK", "$6", "value1"]
+    );
+
+    // HTTP requests carry their credentials
+    let (status, headers) = http_get(http_addr, None);
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    assert!(headers.contains(&"WWW-Authenticate: Bearer realm=\"kvs\"".to_owned()));
+    let (status, _) = http_get(http_addr, Some("Bearer wrong"));
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let (status, headers) = http_get(http_addr, Some(&format!("Bearer {}", TOKEN)));
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(headers.last().unwrap().contains("value1"));
+    let basic = format!("Basic {}", STANDARD.encode("alice:secret"));
+    let (status, _) = http_get(http_addr, Some(&basic));
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    // a server without a backup directory takes no backups
+    let bearer = format!("Bearer {}", TOKEN);
+    let body = r#"{"dir":"backup"}"#;
+    let (status, _) = http(http_addr, "POST", "/admin/backup", Some(&bearer), body);
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn kvs_server_auth() -> Result<()> {
+    let addrs = ["127.0.0.1:4034", "127.0.0.1:4035", "127.0.0.1:4036"];
+    authenticated_clients(addrs, |store, accounts| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .with_resp(addrs[1].parse().unwrap())
+            .with_http(addrs[2].parse().unwrap())
+            .with_accounts(accounts);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run(addrs[0])))
+    })
+}
+
+#[test]
+fn async_server_auth() -> Result<()> {
+    let addrs = ["127.0.0.1:4037", "127.0.0.1:4038", "127.0.0.1:4039"];
+    authenticated_clients(addrs, |store, accounts| {
+        let server = AsyncKvsServer::new(store, 2)
+            .with_resp(addrs[1].parse().unwrap())
+            .with_http(addrs[2].parse().unwrap())
+            .with_accounts(accounts);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run(addrs[0])))
+    })
+}
+
+#[test]
+fn invalid_accounts_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("accounts");
+    for accounts in &[
+        "alice password secret\n",
+        "alice token sha256$00\n",
+        "alice key sha256$00\n",
+        "alice\n",
+    ] {
+        fs::write(&path, accounts)?;
+        match Accounts::from_file(&path) {
+            Err(KvsError::StringError(e)) => assert!(
+                e.ends_with(":1: expected `NAME password HASH` or `NAME token HASH`"),
+                "{}",
+                e
+            ),
+            _ => panic!("{:?} was accepted", accounts),
+        }
+    }
+    Ok(())
+}
+
+// `kvs-admin credential` should print lines `kvs-server --accounts` reads,
+// and `kvs-client` authenticate with --token or --user and --password
+#[test]
+fn cli_auth() {
+    let addr = "127.0.0.1:4040";
+    let temp_dir = TempDir::new().unwrap();
+    let credential = |name: &str, kind: &str, secret: &str| {
+        let mut child = Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(["credential", name, kind])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(child.stdin.take().unwrap(), "{}", secret).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let accounts = credential("alice", "password", "secret") + &credential("ci", "token", TOKEN);
+    assert!(accounts.starts_with("alice password pbkdf2-sha256$"));
+    let accounts_path = temp_dir.path().join("accounts");
+    fs::write(&accounts_path, accounts).unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .arg("--accounts")
+        .arg(&accounts_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr])
+            .env_remove("KVS_TOKEN");
+        cmd
+    };
+    client(&["set", "key1", "value1"])
+        .assert()
+        .failure()
+        .stderr(contains("Unauthenticated"));
+    client(&["set", "key1", "value1", "--token", "wrong"])
+        .assert()
+        .failure()
+        .stderr(contains("InvalidCredentials"));
+    client(&["set", "key1", "value1", "--token", TOKEN])
+        .assert()
+        .success();
+    client(&["get", "key1", "--user", "alice"])
+        .env("KVS_PASSWORD", "secret")
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key1"])
+        .env("KVS_TOKEN", TOKEN)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, StoreMeta};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +215,393 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-admin upgrade` should move a directory from the old `engine` file to `META`
+#[test]
+fn cli_admin_upgrade() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "Sled").unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("upgraded sled store from format version 0"));
+    assert!(!temp_dir.path().join("engine").exists());
+    assert!(temp_dir.path().join("META").exists());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["upgrade", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("already"));
+}
+
+// `kvs-admin migrate` should move every live key to the other engine and keep the old data
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    let big = "v".repeat(64 * 1024);
+    {
+        let store = KvStore::open(&dir).unwrap();
+        for i in 0..50 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        store.set("big".to_owned(), big.clone()).unwrap();
+        for i in 0..10 {
+            store.remove(format!("key{}", i)).unwrap();
+        }
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "kvs",
+            "--to",
+            "sled",
+            dir.to_str().unwrap(),
+        ])
+        .assert()
+        .success()
+        .stdout(contains("migrated 41 keys from kvs to sled"));
+    assert_eq!(StoreMeta::load(&dir).unwrap().unwrap().engine, "sled");
+    assert!(temp_dir
+        .path()
+        .join("data.kvs-backup")
+        .join("META")
+        .exists());
+
+    // the store is no longer a kvs one
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "kvs",
+            "--to",
+            "lsm",
+            dir.to_str().unwrap(),
+        ])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&dir)
+        .assert()
+        .success()
+        .stdout(contains("migrated 41 keys from sled to kvs"));
+    assert_eq!(StoreMeta::load(&dir).unwrap().unwrap().engine, "kvs");
+    assert!(temp_dir
+        .path()
+        .join("data.sled-backup")
+        .join("META")
+        .exists());
+
+    let store = KvStore::open(&dir).unwrap();
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), None);
+    }
+    for i in 10..50 {
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(store.get("big".to_owned()).unwrap(), Some(big));
+}
+
+// `kvs-client history` should print the versions the server keeps
+#[test]
+fn cli_history() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--retain-versions", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(&["--addr", addr]);
+        cmd
+    };
+    for value in &["value1", "value2", "value3"] {
+        client(&["set", "key1", value]).assert().success();
+    }
+    client(&["rm", "key1"]).assert().success();
+
+    let output = client(&["history", "key1"]).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout.lines().map(|l| l.split(' ').collect()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0][0], "3");
+    assert_eq!(&lines[0][2..], &["set", "value3"]);
+    assert_eq!(lines[1][0], "4");
+    assert_eq!(&lines[1][2..], &["rm"]);
+
+    client(&["get", "key1", "--seq", "3"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-server` should pass the --sled-* options to the sled engine and refuse them otherwise
+#[test]
+fn cli_sled_options() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--sled-flush", "manual"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let addr = "127.0.0.1:4012";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "sled",
+            "--addr",
+            addr,
+            "--sled-flush",
+            "100",
+            "--sled-cache-bytes",
+            "1048576",
+            "--sled-mode",
+            "high-throughput",
+            "--sled-tree",
+            "keyspace",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// The server should refuse keys, values and requests over its limits with typed errors
+fn cli_size_limits(runtime: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--runtime",
+            runtime,
+            "--max-key-size",
+            "16",
+            "--max-value-size",
+            "1024",
+            "--max-request-size",
+            "4096",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "v".repeat(1024)).unwrap();
+    assert!(matches!(
+        client.set("k".repeat(17), "value".to_owned()),
+        Err(KvsError::KeyTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    assert!(matches!(
+        client.get("k".repeat(17)),
+        Err(KvsError::KeyTooLarge { .. })
+    ));
+    assert!(matches!(
+        client.set_many(vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "v".repeat(1025)),
+        ]),
+        Err(KvsError::ValueTooLarge {
+            size: 1025,
+            limit: 1024
+        })
+    ));
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+
+    // the server stops reading and closes the connection
+    assert!(matches!(
+        client.set("key4".to_owned(), "v".repeat(1024 * 1024)),
+        Err(KvsError::RequestTooLarge { limit: 4096 })
+    ));
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("v".repeat(1024))
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_size_limits_threads() {
+    cli_size_limits("threads", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_size_limits_async() {
+    cli_size_limits("async", "127.0.0.1:4014");
+}
+
+// `kvs-server` should shut down cleanly on SIGTERM
+#[test]
+fn cli_sigterm() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(child.wait().unwrap()).unwrap());
+    let status = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the server did not stop");
+    assert!(status.success());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `KvsClient` batches should reach the server in one request each
+#[test]
+fn client_batches() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let pairs = (0..50)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs).unwrap();
+    let keys = vec!["key3".to_owned(), "key50".to_owned(), "key49".to_owned()];
+    assert_eq!(
+        client.get_many(keys).unwrap(),
+        vec![Some("value3".to_owned()), None, Some("value49".to_owned())]
+    );
+    let keys = vec!["key3".to_owned(), "key50".to_owned()];
+    assert_eq!(client.remove_many(keys).unwrap(), vec![true, false]);
+    assert_eq!(client.get("key3".to_owned()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// With `--runtime async` idle connections should not hold up other clients
+#[test]
+fn cli_async_runtime() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--runtime", "async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // far more idle connections than a thread pool has threads
+    let idle: Vec<_> = (0..200)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+
+    // a request arriving in pieces
+    let mut tcp = TcpStream::connect(addr).unwrap();
+    tcp.write_all(br#"{"Set":{"key":"key1","#).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    tcp.write_all(br#""value":"value1"}}"#).unwrap();
+    let mut resp = [0; 11];
+    tcp.read_exact(&mut resp).unwrap();
+    assert_eq!(&resp, br#"{"Ok":null}"#);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set_many(vec![("key2".to_owned(), "value2".to_owned())])
+        .unwrap();
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    drop(idle);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -253,7 +643,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(&["get", "key1", "--addr", addr, "--protocol", "binary"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +725,51 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_access_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .args(&["--memory-capacity", "1024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", &"v".repeat(1024), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ValueTooLarge"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    // nothing was stored, so the directory is still free for any engine
+    assert!(!temp_dir.path().join("META").exists());
+}
@@ -0,0 +1,64 @@
+// helpers the integration tests share, each test crate uses some of them
+#![allow(dead_code)]
+
+use kvs::{hash_password, hash_token, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+// an accounts file with a line per `(name, kind, secret)`, `kind` being `password` or `token`
+pub fn write_accounts(path: &Path, accounts: &[(&str, &str, &str)]) -> Result<()> {
+    let mut file = "# kvs accounts\n\n".to_owned();
+    for &(name, kind, secret) in accounts {
+        let hash = match kind {
+            "password" => hash_password(secret)?,
+            _ => hash_token(secret),
+        };
+        file += &format!("{} {} {}\n", name, kind, hash);
+    }
+    fs::write(path, file)?;
+    Ok(())
+}
+
+// one request and its whole answer on a new connection, for the text protocols
+pub fn exchange(addr: &str, request: &str, lines: usize) -> Vec<String> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream);
+    (0..lines)
+        .map(|_| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.trim_end().to_owned()
+        })
+        .collect()
+}
+
+// one HTTP request on a new connection, the status line and the lines after it
+pub fn http(
+    addr: &str,
+    method: &str,
+    target: &str,
+    authorization: Option<&str>,
+    body: &str,
+) -> (String, Vec<String>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        target,
+        body.len()
+    );
+    if let Some(authorization) = authorization {
+        req += &format!("Authorization: {}\r\n", authorization);
+    }
+    stream
+        .write_all(format!("{}\r\n{}", req, body).as_bytes())
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let mut lines = resp.split("\r\n").map(str::to_owned);
+    let status = lines.next().unwrap();
+    (status, lines.collect())
+}
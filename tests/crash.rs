@@ -0,0 +1,416 @@
+use kvs::{
+    EngineStats, KvStore, KvStoreConfig, KvsEngine, MemoryStorage, Result, Storage, StorageFile,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+// Storage that makes a chosen write operation fail, like a process dying
+// or a disk filling up at that point. Once a fault fired every later write
+// fails too, nothing more reaches the files of a dead process.
+#[derive(Clone)]
+struct FaultyStorage {
+    inner: MemoryStorage,
+    faults: Arc<Mutex<Faults>>,
+}
+
+struct Faults {
+    rng: StdRng,
+    // write operations done so far
+    ops: u64,
+    // write operations left before the fault, `None` when disarmed
+    countdown: Option<u64>,
+    // a failing append writes some of its bytes first
+    torn: bool,
+    // bytes that may still be appended, `None` for no limit
+    space: Option<u64>,
+    crashed: bool,
+}
+
+impl Faults {
+    // Ok if a write of `len` bytes goes through,
+    // otherwise how many of its bytes still get written before it fails
+    fn write(&mut self, len: usize) -> std::result::Result<(), usize> {
+        if self.crashed {
+            return Err(0);
+        }
+        self.ops += 1;
+        if let Some(countdown) = self.countdown.as_mut() {
+            if *countdown == 0 {
+                self.crashed = true;
+                let written = if self.torn && len > 0 {
+                    self.rng.gen_range(0, len)
+                } else {
+                    0
+                };
+                return Err(written);
+            }
+            *countdown -= 1;
+        }
+        if let Some(space) = self.space.as_mut() {
+            if len as u64 > *space {
+                let written = *space as usize;
+                *space = 0;
+                self.crashed = true;
+                return Err(written);
+            }
+            *space -= len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl FaultyStorage {
+    fn new(seed: u64) -> FaultyStorage {
+        FaultyStorage {
+            inner: MemoryStorage::new(),
+            faults: Arc::new(Mutex::new(Faults {
+                rng: StdRng::seed_from_u64(seed),
+                ops: 0,
+                countdown: None,
+                torn: false,
+                space: None,
+                crashed: false,
+            })),
+        }
+    }
+
+    fn arm(&self, f: impl FnOnce(&mut Faults)) {
+        f(&mut self.faults.lock().unwrap());
+    }
+
+    // "restart the process", later writes go through
+    fn disarm(&self) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.countdown = None;
+        faults.torn = false;
+        faults.space = None;
+        faults.crashed = false;
+    }
+
+    fn ops(&self) -> u64 {
+        self.faults.lock().unwrap().ops
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match self.faults.lock().unwrap().write(0) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(injected()),
+        }
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        self.check()?;
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.create(name)?,
+            faults: Arc::clone(&self.faults),
+        }))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.open(name)?,
+            faults: Arc::clone(&self.faults),
+        }))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.check()?;
+        self.inner.remove(name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check()?;
+        self.inner.rename(from, to)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.check()?;
+        self.inner.sync()
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn StorageFile>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl StorageFile for FaultyFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let res = self.faults.lock().unwrap().write(buf.len());
+        match res {
+            Ok(()) => self.inner.append(buf),
+            Err(written) => {
+                self.inner.append(&buf[..written])?;
+                Err(injected())
+            }
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let res = self.faults.lock().unwrap().write(0);
+        match res {
+            Ok(()) => self.inner.sync(),
+            Err(_) => Err(injected()),
+        }
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+}
+
+type Model = HashMap<String, String>;
+
+const KEYS: u64 = 50;
+
+fn config() -> KvStoreConfig {
+    KvStoreConfig {
+        blob_threshold: Some(4 * 1024),
+        blob_gc_threshold: 64 * 1024,
+        ..KvStoreConfig::default()
+    }
+}
+
+fn apply(store: &KvStore, op: &Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => store.set(key.clone(), value.clone()),
+        Op::Remove(key) => store.remove(key.clone()),
+    }
+}
+
+fn apply_to_model(model: &mut Model, op: Op) {
+    match op {
+        Op::Set(key, value) => {
+            model.insert(key, value);
+        }
+        Op::Remove(key) => {
+            model.remove(&key);
+        }
+    }
+}
+
+fn recovered(store: &KvStore) -> Result<Model> {
+    let mut state = Model::new();
+    for key_id in 0..KEYS {
+        let key = format!("key{}", key_id);
+        if let Some(value) = store.get(key.clone())? {
+            state.insert(key, value);
+        }
+    }
+    Ok(state)
+}
+
+// Reopen the store after a crash and check it holds every operation that succeeded,
+// and the failed one either completely or not at all. Returns the reopened store and model.
+fn recover(
+    storage: &FaultyStorage,
+    mut model: Model,
+    failed: Option<Op>,
+) -> Result<(KvStore, Model)> {
+    storage.disarm();
+    let store = KvStore::open_with_storage(storage.clone(), config())?;
+    let state = recovered(&store)?;
+    if let Some(op) = failed {
+        let mut after = model.clone();
+        apply_to_model(&mut after, op);
+        if state == after {
+            model = after;
+        }
+    }
+    assert_eq!(state, model);
+    Ok((store, model))
+}
+
+fn random_op(rng: &mut StdRng, model: &Model, serial: u64) -> Op {
+    if !model.is_empty() && rng.gen_bool(0.2) {
+        // sorted, so a seed always picks the same keys
+        let mut keys: Vec<_> = model.keys().collect();
+        keys.sort();
+        return Op::Remove(keys[rng.gen_range(0, keys.len())].clone());
+    }
+    let len = if rng.gen_bool(0.1) {
+        rng.gen_range(5 * 1024, 10 * 1024)
+    } else {
+        rng.gen_range(100, 2000)
+    };
+    let key = format!("key{}", rng.gen_range(0, KEYS));
+    Op::Set(key, format!("{:08}{}", serial, "x".repeat(len)))
+}
+
+// Run random operations, crash whenever `arm` makes a write fail,
+// and check the recovered store against the model every time.
+fn random_crashes(seed: u64, arm: impl Fn(&mut Faults)) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let storage = FaultyStorage::new(seed);
+    let mut model = Model::new();
+    let mut store = KvStore::open_with_storage(storage.clone(), config())?;
+    let mut serial = 0;
+
+    for _ in 0..25 {
+        storage.arm(&arm);
+        let mut failed = None;
+        for _ in 0..200 {
+            serial += 1;
+            let op = random_op(&mut rng, &model, serial);
+            match apply(&store, &op) {
+                Ok(()) => apply_to_model(&mut model, op),
+                Err(_) => {
+                    failed = Some(op);
+                    break;
+                }
+            }
+        }
+        drop(store);
+        let (reopened, recovered_model) = recover(&storage, model, failed)?;
+        store = reopened;
+        model = recovered_model;
+    }
+    Ok(())
+}
+
+// Writes failing at random points should never lose or corrupt acknowledged data
+#[test]
+fn random_write_failures() -> Result<()> {
+    for seed in 0..4 {
+        random_crashes(seed, |faults| {
+            faults.countdown = Some(faults.rng.gen_range(0, 300));
+        })?;
+    }
+    Ok(())
+}
+
+// A crash in the middle of an append leaves part of a command at the end of the log
+#[test]
+fn torn_writes() -> Result<()> {
+    for seed in 0..4 {
+        random_crashes(seed, |faults| {
+            faults.countdown = Some(faults.rng.gen_range(0, 300));
+            faults.torn = true;
+        })?;
+    }
+    Ok(())
+}
+
+// Running out of space writes whatever still fits, then fails
+#[test]
+fn disk_full() -> Result<()> {
+    for seed in 0..4 {
+        random_crashes(seed, |faults| {
+            faults.space = Some(faults.rng.gen_range(0, 512 * 1024));
+        })?;
+    }
+    Ok(())
+}
+
+// Crash at every write of the operation `trigger` picks out of a deterministic workload,
+// like the set that starts a compaction. `trigger` sees the stats before and after the operation.
+fn crash_at_every_point(
+    workload: impl Fn(u64) -> Op,
+    trigger: impl Fn(&Op, &EngineStats, &EngineStats) -> bool,
+) -> Result<()> {
+    // a clean run finds the operation and the writes it does
+    let storage = FaultyStorage::new(0);
+    let store = KvStore::open_with_storage(storage.clone(), config())?;
+    let mut serial = 0;
+    let (first_op, last_op) = loop {
+        let op = workload(serial);
+        let stats = store.stats()?;
+        let before = storage.ops();
+        apply(&store, &op)?;
+        if trigger(&op, &stats, &store.stats()?) {
+            break (before, storage.ops());
+        }
+        serial += 1;
+    };
+    drop(store);
+
+    // failing to delete stale files is only logged, so not every crash fails the operation
+    for crash_op in first_op..last_op {
+        let storage = FaultyStorage::new(crash_op);
+        let store = KvStore::open_with_storage(storage.clone(), config())?;
+        let mut model = Model::new();
+        let countdown = crash_op - storage.ops();
+        storage.arm(|faults| faults.countdown = Some(countdown));
+
+        let mut failed = None;
+        for i in 0..=serial {
+            let op = workload(i);
+            match apply(&store, &op) {
+                Ok(()) => apply_to_model(&mut model, op),
+                Err(_) => {
+                    failed = Some(op);
+                    break;
+                }
+            }
+        }
+        drop(store);
+        recover(&storage, model, failed)?;
+    }
+    Ok(())
+}
+
+// A crash anywhere inside a set that compacts the log should recover cleanly
+#[test]
+fn crash_points_in_compaction() -> Result<()> {
+    crash_at_every_point(
+        |i| {
+            let key = format!("key{}", i % KEYS);
+            Op::Set(key, format!("{:08}{}", i, "x".repeat(1000)))
+        },
+        |_, before, after| after.compaction_count > before.compaction_count,
+    )
+}
+
+// A crash anywhere inside a remove that compacts the log should recover cleanly
+#[test]
+fn crash_points_in_remove() -> Result<()> {
+    crash_at_every_point(
+        |i| {
+            let key = format!("key{}", (i / 2) % KEYS);
+            if i % 2 == 0 {
+                Op::Set(key, format!("{:08}{}", i, "x".repeat(1000)))
+            } else {
+                Op::Remove(key)
+            }
+        },
+        |op, before, after| match op {
+            Op::Remove(_) => after.compaction_count > before.compaction_count,
+            Op::Set(..) => false,
+        },
+    )
+}
+
+// A crash anywhere inside a set that collects blob files should recover cleanly
+#[test]
+fn crash_points_in_blob_gc() -> Result<()> {
+    crash_at_every_point(
+        |i| {
+            let key = format!("key{}", i % 10);
+            Op::Set(key, format!("{:08}{}", i, "x".repeat(5000)))
+        },
+        |_, before, after| after.blob_garbage < before.blob_garbage,
+    )
+}
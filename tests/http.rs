@@ -0,0 +1,350 @@
+use common::write_accounts;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Accounts, AsyncKvsServer, KvStore, KvsClient, KvsEngine, KvsServer, Result, ShutdownHandle,
+    SizeLimits,
+};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    authorization: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Conn {
+    fn connect(addr: &str) -> Conn {
+        let writer = TcpStream::connect(addr).unwrap();
+        Conn {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+            authorization: None,
+        }
+    }
+
+    fn send(&mut self, method: &str, target: &str, body: Option<&Value>) {
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let authorization = match &self.authorization {
+            Some(authorization) => format!("Authorization: {}\r\n", authorization),
+            None => String::new(),
+        };
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            authorization,
+            body.len(),
+            body
+        );
+        self.writer.write_all(req.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, method: &str, target: &str, body: Option<&Value>) -> Response {
+        self.send(method, target, body);
+        self.read()
+    }
+
+    fn read(&mut self) -> Response {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_at(line.find(':').unwrap());
+            headers.push((name.to_owned(), value[1..].trim().to_owned()));
+        }
+        let mut resp = Response {
+            status,
+            headers,
+            body: None,
+        };
+        if let Some(len) = resp.header("Content-Length") {
+            let mut body = vec![0; len.parse().unwrap()];
+            self.reader.read_exact(&mut body).unwrap();
+            resp.body = Some(serde_json::from_slice(&body).unwrap());
+        }
+        resp
+    }
+}
+
+fn put(conn: &mut Conn, key: &str, value: &str) {
+    let resp = conn.call(
+        "PUT",
+        &format!("/keys/{}", key),
+        Some(&json!({ "value": value })),
+    );
+    assert_eq!(resp.status, 204);
+    assert!(resp.body.is_none());
+}
+
+fn http_gateway<F>(addr: &'static str, http_addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?);
+    thread::sleep(Duration::from_secs(1));
+    let mut conn = Conn::connect(http_addr);
+
+    // every request goes on the same connection
+    put(&mut conn, "key1", "value1");
+    let resp = conn.call("GET", "/keys/key1", None);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    assert_eq!(resp.body, Some(json!({"key": "key1", "value": "value1"})));
+    let resp = conn.call("GET", "/keys/missing", None);
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.body, Some(json!({"error": "Key not found"})));
+    put(&mut conn, "a%2Fb%20c", "slash");
+    assert_eq!(
+        conn.call("GET", "/keys/a%2Fb%20c", None).body,
+        Some(json!({"key": "a/b c", "value": "slash"}))
+    );
+
+    // the kvs protocol sees the same store
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("a/b c".to_owned())?, Some("slash".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        conn.call("GET", "/keys/key2", None).body,
+        Some(json!({"key": "key2", "value": "value2"}))
+    );
+    assert_eq!(conn.call("DELETE", "/keys/key2", None).status, 204);
+    assert_eq!(conn.call("DELETE", "/keys/key2", None).status, 404);
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    // listing in pages
+    for i in 0..25 {
+        put(&mut conn, &format!("list{:02}", i), &i.to_string());
+    }
+    let mut listed = Vec::new();
+    let mut target = "/keys?prefix=list&limit=10".to_owned();
+    loop {
+        let body = conn.call("GET", &target, None).body.unwrap();
+        for pair in body["pairs"].as_array().unwrap() {
+            listed.push(pair["key"].as_str().unwrap().to_owned());
+        }
+        match body["next"].as_str() {
+            Some(next) => target = format!("/keys?prefix=list&limit=10&start={}", next),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = (0..25).map(|i| format!("list{:02}", i)).collect();
+    assert_eq!(listed, expected);
+    let body = conn
+        .call("GET", "/keys?start=list03&end=list06", None)
+        .body
+        .unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "pairs": [
+                {"key": "list03", "value": "3"},
+                {"key": "list04", "value": "4"},
+                {"key": "list05", "value": "5"},
+            ],
+            "next": null,
+        })
+    );
+    assert_eq!(conn.call("GET", "/keys?limit=0", None).status, 400);
+    assert_eq!(conn.call("GET", "/keys?colour=red", None).status, 400);
+
+    // a server without accounts has no administrators
+    assert_eq!(conn.call("GET", "/admin/stats", None).status, 403);
+    assert_eq!(conn.call("POST", "/admin/compact", None).status, 403);
+    let body = json!({ "dir": "backup" });
+    assert_eq!(conn.call("POST", "/admin/backup", Some(&body)).status, 403);
+
+    // errors
+    let resp = conn.call("POST", "/keys/key1", None);
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("GET, PUT, DELETE"));
+    assert_eq!(conn.call("GET", "/nowhere", None).status, 404);
+    assert_eq!(
+        conn.call("PUT", "/keys/key1", Some(&json!({"val": "x"})))
+            .status,
+        400
+    );
+    let long_key = "k".repeat(2000);
+    let resp = conn.call(
+        "PUT",
+        &format!("/keys/{}", long_key),
+        Some(&json!({"value": "v"})),
+    );
+    assert_eq!(resp.status, 413);
+    assert_eq!(conn.call("GET", "/keys/key1", None).status, 200);
+
+    // pipelined requests are answered in order
+    conn.send("PUT", "/keys/p1", Some(&json!({"value": "a"})));
+    conn.send("GET", "/keys/p1", None);
+    conn.send("DELETE", "/keys/p1", None);
+    assert_eq!(conn.read().status, 204);
+    assert_eq!(conn.read().body.unwrap()["value"], json!("a"));
+    assert_eq!(conn.read().status, 204);
+
+    // the connection ends when the client asks
+    conn.writer
+        .write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let resp = conn.read();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Connection"), Some("close"));
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // a request breaking the protocol is answered before the connection is closed
+    let mut conn = Conn::connect(http_addr);
+    conn.writer.write_all(b"NOT HTTP\r\n\r\n").unwrap();
+    assert_eq!(conn.read().status, 400);
+    let mut conn = Conn::connect(http_addr);
+    conn.writer
+        .write_all(b"PUT /keys/big HTTP/1.1\r\nContent-Length: 100000\r\n\r\n")
+        .unwrap();
+    assert_eq!(conn.read().status, 413);
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(TcpStream::connect(http_addr).is_err());
+    Ok(())
+}
+
+fn http_admin<F>(http_addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore, Accounts, PathBuf) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let accounts_path = temp_dir.path().join("accounts");
+    write_accounts(&accounts_path, &[("ops", "token", TOKEN)])?;
+    let backups = temp_dir.path().join("backups");
+    fs::create_dir(&backups)?;
+    let store = KvStore::open(temp_dir.path().join("data"))?;
+    let (handle, server) = start(store, Accounts::from_file(&accounts_path)?, backups.clone());
+    thread::sleep(Duration::from_secs(1));
+    let mut conn = Conn::connect(http_addr);
+    conn.authorization = Some(format!("Bearer {}", TOKEN));
+
+    put(&mut conn, "key1", "value1");
+    let stats = conn.call("GET", "/admin/stats", None).body.unwrap();
+    assert_eq!(stats["key_count"], json!(1));
+    let resp = conn.call("POST", "/admin/compact", None);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.unwrap()["key_count"], json!(1));
+    assert_eq!(conn.call("GET", "/admin/compact", None).status, 405);
+
+    // backups go to new directories of the backup directory
+    let body = json!({ "dir": "daily" });
+    let resp = conn.call("POST", "/admin/backup", Some(&body));
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.unwrap()["keys"], json!(1));
+    assert_eq!(conn.call("POST", "/admin/backup", Some(&body)).status, 409);
+    let backup = KvStore::open(backups.join("daily"))?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // and nowhere else
+    let outside = temp_dir.path().join("outside");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(temp_dir.path(), backups.join("link"))?;
+    for dir in &[
+        outside.to_str().unwrap(),
+        "../outside",
+        "daily/../../outside",
+        "link/outside",
+        "",
+    ] {
+        let body = json!({ "dir": dir });
+        let resp = conn.call("POST", "/admin/backup", Some(&body));
+        assert_eq!(resp.status, 400, "{:?}", dir);
+    }
+    assert!(!outside.exists());
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    Ok(())
+}
+
+fn limits() -> SizeLimits {
+    SizeLimits {
+        max_key_size: 1024,
+        max_value_size: 1024,
+        max_request_size: 64 * 1024,
+    }
+}
+
+#[test]
+fn kvs_server_http() -> Result<()> {
+    http_gateway("127.0.0.1:4027", "127.0.0.1:4028", |store| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .with_limits(limits())
+            .with_http("127.0.0.1:4028".parse().unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4027")))
+    })
+}
+
+#[test]
+fn async_server_http() -> Result<()> {
+    http_gateway("127.0.0.1:4029", "127.0.0.1:4030", |store| {
+        let server = AsyncKvsServer::new(store, 2)
+            .with_limits(limits())
+            .with_http("127.0.0.1:4030".parse().unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4029")))
+    })
+}
+
+#[test]
+fn kvs_server_http_admin() -> Result<()> {
+    http_admin("127.0.0.1:4049", |store, accounts, backups| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .with_http("127.0.0.1:4049".parse().unwrap())
+            .with_accounts(accounts)
+            .with_backup_dir(backups);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4048")))
+    })
+}
+
+#[test]
+fn async_server_http_admin() -> Result<()> {
+    http_admin("127.0.0.1:4051", |store, accounts, backups| {
+        let server = AsyncKvsServer::new(store, 2)
+            .with_http("127.0.0.1:4051".parse().unwrap())
+            .with_accounts(accounts)
+            .with_backup_dir(backups);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4050")))
+    })
+}
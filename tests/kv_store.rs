@@ -1,14 +1,20 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    format_version, AsyncKvsEngine, Event, FlushPolicy, KvStore, KvStoreConfig, KvsEngine,
+    KvsError, LsmConfig, LsmKvsEngine, MemoryKvsEngine, MemoryStorage, Result, Retention,
+    SledConfig, SledKvsEngine, Storage, StoreMeta,
+};
+use sled::transaction::abort;
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -18,18 +24,33 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
     Ok(())
 }
 
-// Should overwrite existent value
 #[test]
-fn overwrite_value() -> Result<()> {
+fn get_stored_value() -> Result<()> {
+    get_stored_value_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_stored_value() -> Result<()> {
+    get_stored_value_with(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn memory_storage_get_stored_value() -> Result<()> {
+    let storage = MemoryStorage::new();
+    get_stored_value_with(|_| KvStore::open_with_storage(storage.clone(), KvStoreConfig::default()))
+}
+
+// Should overwrite existent value
+fn overwrite_value_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +59,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -46,41 +67,379 @@ fn overwrite_value() -> Result<()> {
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn overwrite_value() -> Result<()> {
+    overwrite_value_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_overwrite_value() -> Result<()> {
+    overwrite_value_with(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn memory_storage_overwrite_value() -> Result<()> {
+    let storage = MemoryStorage::new();
+    overwrite_value_with(|_| KvStore::open_with_storage(storage.clone(), KvStoreConfig::default()))
+}
+
+// Should get `None` when getting a non-existent key
+fn get_non_existent_value_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn get_non_existent_value() -> Result<()> {
+    get_non_existent_value_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_non_existent_value() -> Result<()> {
+    get_non_existent_value_with(|path| LsmKvsEngine::open(path))
+}
+
+fn remove_non_existent_key_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
-fn remove_key() -> Result<()> {
+fn remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key_with(|path| LsmKvsEngine::open(path))
+}
+
+fn remove_key_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+#[test]
+fn remove_key() -> Result<()> {
+    remove_key_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_key() -> Result<()> {
+    remove_key_with(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn memory_storage_remove_key() -> Result<()> {
+    let storage = MemoryStorage::new();
+    remove_key_with(|_| KvStore::open_with_storage(storage.clone(), KvStoreConfig::default()))
+}
+
+// Batches should apply in order and report every key
+fn batch_ops_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i % 60), format!("value{}", i)))
+        .collect();
+    store.set_many(pairs)?;
+
+    let keys = vec!["key0".to_owned(), "key59".to_owned(), "key60".to_owned()];
+    assert_eq!(
+        store.get_many(keys)?,
+        vec![Some("value60".to_owned()), Some("value59".to_owned()), None]
+    );
+
+    let keys = vec![
+        "key1".to_owned(),
+        "key60".to_owned(),
+        "key1".to_owned(),
+        "key2".to_owned(),
+    ];
+    assert_eq!(store.remove_many(keys)?, vec![true, false, false, true]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value63".to_owned()));
+    assert_eq!(store.get_many(Vec::new())?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+#[test]
+fn batch_ops() -> Result<()> {
+    batch_ops_with(|path| KvStore::open(path))?;
+
+    // a batch is replayed like single writes
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    store.remove_many(vec!["key1".to_owned()])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_many(vec!["key1".to_owned(), "key2".to_owned()])?,
+        vec![None, Some("value2".to_owned())]
+    );
+    Ok(())
+}
+
+// `close` should keep the data and make every clone fail afterwards
+#[test]
+fn sync_and_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    let watcher = store.watch("".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.close()?;
+    assert_eq!(watcher.count(), 2);
+    for store in &[&store, &clone] {
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(KvsError::StoreClosed)
+        ));
+        assert!(matches!(
+            store.set("key3".to_owned(), "value3".to_owned()),
+            Err(KvsError::StoreClosed)
+        ));
+        assert!(matches!(store.sync(), Err(KvsError::StoreClosed)));
+        store.close()?;
+    }
+    drop(store);
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Sets over the configured sizes should fail with typed errors and write nothing
+fn size_limits_with<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "v".repeat(16))?;
+
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLarge { size: 9, limit: 8 })
+    ));
+    assert!(matches!(
+        store.set_many(vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "v".repeat(17)),
+        ]),
+        Err(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    Ok(())
+}
+
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_key_size: Some(8),
+        max_value_size: Some(16),
+        ..KvStoreConfig::default()
+    };
+    size_limits_with(KvStore::open_with_config(temp_dir.path(), config)?)
+}
+
+#[test]
+fn lsm_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = LsmConfig {
+        max_key_size: Some(8),
+        max_value_size: Some(16),
+        ..LsmConfig::default()
+    };
+    size_limits_with(LsmKvsEngine::open_with_config(temp_dir.path(), config)?)
+}
+
+#[test]
+fn sled_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = SledConfig {
+        max_key_size: Some(8),
+        max_value_size: Some(16),
+        ..SledConfig::default()
+    };
+    size_limits_with(SledKvsEngine::open_with_config(temp_dir.path(), config)?)
+}
+
+#[test]
+fn memory_size_limits() -> Result<()> {
+    size_limits_with(MemoryKvsEngine::new().with_size_limits(Some(8), Some(16)))
+}
+
+#[test]
+fn lsm_batch_ops() -> Result<()> {
+    batch_ops_with(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn sled_batch_ops() -> Result<()> {
+    batch_ops_with(|path| Ok(SledKvsEngine::new(sled::open(path)?)))
+}
+
+// Named trees should be separate keyspaces, and transactions all-or-nothing
+#[test]
+fn sled_trees_and_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = SledConfig {
+        flush: FlushPolicy::Manual,
+        compression: Some(3),
+        tree: Some("accounts".to_owned()),
+        max_value_size: Some(16),
+        ..SledConfig::default()
+    };
+    let accounts = SledKvsEngine::open_with_config(temp_dir.path(), config)?;
+    let other = accounts.open_tree("other")?;
+    accounts.set("alice".to_owned(), "10".to_owned())?;
+    accounts.set("bob".to_owned(), "0".to_owned())?;
+    other.set("alice".to_owned(), "elsewhere".to_owned())?;
+    assert_eq!(accounts.get("alice".to_owned())?, Some("10".to_owned()));
+    assert_eq!(other.get("alice".to_owned())?, Some("elsewhere".to_owned()));
+    assert_eq!(other.get("bob".to_owned())?, None);
+    let names = accounts.tree_names()?;
+    assert!(names.contains(&"accounts".to_owned()) && names.contains(&"other".to_owned()));
+
+    accounts.transaction(|tree| {
+        let balance = tree.get("alice")?.unwrap();
+        assert_eq!(tree.insert("alice", "4")?, Some(balance));
+        tree.insert("bob", "6")?;
+        Ok(())
+    })?;
+    // the size limits hold within transactions too
+    let too_large: Result<()> = accounts.transaction(|tree| {
+        tree.insert("alice", "0")?;
+        tree.insert("bob", &"9".repeat(17))?;
+        Ok(())
+    });
+    assert!(matches!(
+        too_large,
+        Err(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    let aborted: Result<()> = accounts.transaction(|tree| {
+        tree.insert("alice", "0")?;
+        abort(KvsError::StringError("insufficient funds".to_owned()))
+    });
+    match aborted {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "insufficient funds"),
+        _ => panic!("the transaction should abort"),
+    }
+    assert_eq!(accounts.get("alice".to_owned())?, Some("4".to_owned()));
+    assert_eq!(accounts.get("bob".to_owned())?, Some("6".to_owned()));
+    accounts.flush()?;
+    Ok(())
+}
+
+#[test]
+fn memory_engine_batch_ops() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    batch_ops_with(|_| Ok(store.clone()))
+}
+
+// The async facade should serve concurrent tasks from a blocking engine
+#[test]
+fn async_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .build()?;
+    runtime.block_on(async {
+        let tasks: Vec<_> = (0..100)
+            .map(|i| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    let key = format!("key{}", i);
+                    engine.set(key.clone(), format!("value{}", i)).await?;
+                    engine.get(key).await
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            let value = task.await.expect("task panicked")?;
+            assert_eq!(value, Some(format!("value{}", i)));
+        }
+
+        engine.remove("key0".to_owned()).await?;
+        assert_eq!(engine.get("key0".to_owned()).await?, None);
+        assert_eq!(engine.stats().await?.key_count, 99);
+        Ok(())
+    })
+}
+
+// Should only deliver events of keys with the watched prefix, in write order
+fn watch_prefix_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let watcher = store.watch("user:".to_owned())?;
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.set("user:1".to_owned(), "bob".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    drop(store);
+
+    let events: Vec<Event> = watcher.collect();
+    assert_eq!(
+        events,
+        vec![
+            Event::Set {
+                key: "user:1".to_owned(),
+                value: "alice".to_owned()
+            },
+            Event::Set {
+                key: "user:1".to_owned(),
+                value: "bob".to_owned()
+            },
+            Event::Remove {
+                key: "user:1".to_owned()
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    watch_prefix_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_watch_prefix() -> Result<()> {
+    watch_prefix_with(|path| LsmKvsEngine::open(path))
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,10 +486,301 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// `stats` should count live keys, stale bytes and compactions
 #[test]
-fn concurrent_set() -> Result<()> {
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 0);
+    assert_eq!(stats.compaction_count, 0);
+    assert!(stats.last_compaction.is_none());
+
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 99);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.uncompacted > 0);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 99);
+    assert_eq!(stats.uncompacted, 0);
+    assert_eq!(stats.compaction_count, 1);
+    assert!(stats.last_compaction.is_some());
+    Ok(())
+}
+
+// `compact` should drop stale data right away, and `backup` copy the live keys
+fn compact_and_backup_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for iter in 0..20 {
+        let pairs = (0..100)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", iter)))
+            .collect();
+        store.set_many(pairs)?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 99);
+    assert_eq!(stats.compaction_count, 1);
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = backup_dir.path().join("backup");
+    let backup = kvs::backup(&store, &dir)?;
+    assert_eq!(backup.keys, 99);
+    assert!(kvs::backup(&store, &dir).is_err());
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    let copy = KvStore::open(&dir)?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(copy.get(key.clone())?, store.get(key)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn compact_and_backup() -> Result<()> {
+    compact_and_backup_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_compact_and_backup() -> Result<()> {
+    compact_and_backup_with(|path| {
+        let config = LsmConfig {
+            memtable_size: 1024,
+            max_tables: 1000,
+            ..LsmConfig::default()
+        };
+        LsmKvsEngine::open_with_config(path, config)
+    })
+}
+
+// A store on in-memory storage should compact and reopen like one on disk
+#[test]
+fn memory_storage_compaction() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreConfig::default())?;
+    for iter in 0..40 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.compaction_count > 0);
+    assert!(storage.list()?.len() < 10);
+
+    drop(store);
+    let store = KvStore::open_with_storage(storage, KvStoreConfig::default())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("39".to_owned()));
+    }
+    Ok(())
+}
+
+// Large values should live in blob files that get collected once overwritten
+#[test]
+fn blob_values() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        blob_threshold: Some(1024),
+        blob_gc_threshold: 64 * 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            let value = format!("{}", iter).repeat(1024);
+            store.set(format!("key{}", key_id), value)?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let stats = store.stats()?;
+    assert!(!stats.blob_files.is_empty());
+    let blob_size: u64 = stats.blob_files.iter().map(|blob| blob.size).sum();
+    assert!(blob_size < 20 * 10 * 2048, "blob garbage was not collected");
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("19".repeat(1024))
+        );
+    }
+    Ok(())
+}
+
+// A store whose index outgrows its memory budget should keep serving every key
+#[test]
+fn memory_bounded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        index_memory_budget: Some(16 * 1024),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+    for iter in 0..60 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert_eq!(store.stats()?.key_count, 900);
+    assert!(store.stats()?.compaction_count > 0);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, None);
+        }
+        for key_id in 100..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("59".to_owned()));
+        }
+        assert_eq!(store.get("key1000".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    check(&store)
+}
+
+// Old versions are kept by key, a memory budget for the index cannot go with them
+#[test]
+fn memory_bounded_index_refuses_retention() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        index_memory_budget: Some(16 * 1024),
+        retention: Retention::Versions(3),
+        ..KvStoreConfig::default()
+    };
+    assert!(matches!(
+        KvStore::open_with_config(temp_dir.path(), config),
+        Err(KvsError::StringError(_))
+    ));
+    assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+}
+
+// A compaction cut short by a crash should not bring back stale values
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    // a half-written compaction file from an older state of the store
+    let partial = temp_dir.path().join("100.log");
+    fs::write(
+        &partial,
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value3"}}{"Set":{"ke"#,
+    )?;
+
     let store = KvStore::open(temp_dir.path())?;
+    assert!(!partial.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// The data directory should record its engine and format and refuse other engines
+#[test]
+fn data_dir_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let meta = StoreMeta::load(temp_dir.path())?.expect("no metadata written");
+    assert_eq!(meta.engine, "kvs");
+    assert_eq!(meta.format_version, format_version("kvs")?);
+    match LsmKvsEngine::open(temp_dir.path()) {
+        Err(KvsError::WrongEngine { .. }) => {}
+        _ => panic!("a kvs data directory was opened by the lsm engine"),
+    }
+
+    // a store written before the metadata existed has to be upgraded
+    fs::remove_file(temp_dir.path().join("META"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UpgradeRequired { found: 0, .. }) => {}
+        _ => panic!("a store without metadata was opened"),
+    }
+    let (from, meta) = StoreMeta::upgrade(temp_dir.path())?;
+    assert_eq!(from, 0);
+    assert_eq!(meta.engine, "kvs");
+    assert_eq!(meta.format_version, format_version("kvs")?);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Versions kept by the retention should survive compaction and reopening
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        retention: Retention::Versions(3),
+        ..KvStoreConfig::default()
+    };
+    // long enough values for the log to be compacted
+    let value = |iter: u32| format!("value{}{}", iter, "x".repeat(2000));
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), value(iter))?;
+    }
+    store.remove("key".to_owned())?;
+    assert!(store.stats()?.compaction_count > 0);
+
+    let check = |store: &KvStore| -> Result<()> {
+        let versions = store.history("key".to_owned())?;
+        let values: Vec<_> = versions.iter().map(|v| v.value.clone()).collect();
+        assert_eq!(values, vec![Some(value(998)), Some(value(999)), None]);
+        assert!(versions.windows(2).all(|w| w[0].seq < w[1].seq));
+        let seq = versions[1].seq;
+        assert_eq!(store.get_at("key".to_owned(), seq)?, Some(value(999)));
+        assert_eq!(store.get_at("key".to_owned(), seq + 1)?, None);
+        assert_eq!(store.get("key".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    let last = store.history("key".to_owned())?[2].seq;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    check(&store)?;
+    // sequence numbers keep growing after a reopen
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(store.history("other".to_owned())?[0].seq > last);
+    Ok(())
+}
+
+fn concurrent_set_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
         let store = store.clone();
@@ -150,7 +800,7 @@ fn concurrent_set() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -159,9 +809,18 @@ fn concurrent_set() -> Result<()> {
 }
 
 #[test]
-fn concurrent_get() -> Result<()> {
+fn concurrent_set() -> Result<()> {
+    concurrent_set_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_concurrent_set() -> Result<()> {
+    concurrent_set_with(|path| LsmKvsEngine::open(path))
+}
+
+fn concurrent_get_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -188,7 +847,7 @@ fn concurrent_get() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     let mut handles = Vec::new();
     for thread_id in 0..100 {
         let store = store.clone();
@@ -209,3 +868,148 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    concurrent_get_with(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_concurrent_get() -> Result<()> {
+    concurrent_get_with(|path| LsmKvsEngine::open(path))
+}
+
+// Tables should be merged once there are too many, keeping the newest values
+#[test]
+fn lsm_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = LsmConfig {
+        memtable_size: 16 * 1024,
+        max_tables: 2,
+        ..LsmConfig::default()
+    };
+    let store = LsmKvsEngine::open_with_config(temp_dir.path(), config.clone())?;
+
+    for iter in 0..20 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.compaction_count > 0);
+    assert!(stats.generations.len() <= 2);
+    assert_eq!(stats.key_count, 900);
+
+    drop(store);
+    let store = LsmKvsEngine::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 100..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    Ok(())
+}
+
+// Tables a compaction replaced should stay out of the store even if they could not be deleted
+#[test]
+fn lsm_compaction_leftovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = LsmConfig {
+        memtable_size: 1024,
+        max_tables: 1000,
+        ..LsmConfig::default()
+    };
+    let store = LsmKvsEngine::open_with_config(temp_dir.path(), config.clone())?;
+    let fill = |from: usize| -> Result<()> {
+        for key_id in from..from + 100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        Ok(())
+    };
+    store.set("removed".to_owned(), "value".to_owned())?;
+    fill(0)?;
+    store.remove("removed".to_owned())?;
+    fill(100)?;
+
+    let tables = temp_dir.path().join("tables");
+    fs::create_dir(&tables)?;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("sst".as_ref()) {
+            fs::copy(&path, tables.join(path.file_name().unwrap()))?;
+        }
+    }
+    store.compact()?;
+    drop(store);
+    for entry in fs::read_dir(&tables)? {
+        let path = entry?.path();
+        fs::copy(&path, temp_dir.path().join(path.file_name().unwrap()))?;
+    }
+
+    let store = LsmKvsEngine::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    let table_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count();
+    assert_eq!(table_files, store.stats()?.generations.len());
+    Ok(())
+}
+
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    let clone = store.clone();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clone.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    assert!(store.remove("key2".to_owned()).is_err());
+    clone.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats()?.key_count, 0);
+    Ok(())
+}
+
+// A bounded memory engine should evict the least recently used keys first
+#[test]
+fn memory_engine_eviction() -> Result<()> {
+    // room for three keys of 4 bytes with values of 6 bytes
+    let store = MemoryKvsEngine::with_capacity(30);
+    let watcher = store.watch("".to_owned())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.stats()?.live_bytes, 30);
+    // a pair over the whole capacity is refused rather than evicting everything
+    assert!(matches!(
+        store.set("key5".to_owned(), "v".repeat(27)),
+        Err(KvsError::ValueTooLarge {
+            size: 27,
+            limit: 26
+        })
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    assert!(watcher.into_iter().any(|event| event
+        == Event::Remove {
+            key: "key2".to_owned()
+        }));
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsServer, Result, ShutdownHandle};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+use Reply::*;
+
+fn bulk(s: &str) -> Reply {
+    Bulk(Some(s.to_owned()))
+}
+
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn connect(addr: &str) -> Conn {
+        let writer = TcpStream::connect(addr).unwrap();
+        Conn {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(buf.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    fn read(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Simple(rest.to_owned()),
+            "-" => Error(rest.to_owned()),
+            ":" => Integer(rest.parse().unwrap()),
+            "$" if rest == "-1" => Bulk(None),
+            "$" => {
+                let mut buf = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                buf.truncate(buf.len() - 2);
+                Bulk(Some(String::from_utf8(buf).unwrap()))
+            }
+            "*" => Array((0..rest.parse().unwrap()).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn keys(reply: Reply) -> HashSet<String> {
+    match reply {
+        Array(keys) => keys
+            .into_iter()
+            .map(|key| match key {
+                Bulk(Some(key)) => key,
+                key => panic!("unexpected key {:?}", key),
+            })
+            .collect(),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+fn resp_commands<F>(addr: &'static str, resp_addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?);
+    thread::sleep(Duration::from_secs(1));
+    let mut conn = Conn::connect(resp_addr);
+
+    // inline and array commands
+    conn.writer.write_all(b"PING\r\n").unwrap();
+    assert_eq!(conn.read(), Simple("PONG".to_owned()));
+    assert_eq!(conn.call(&["ping", "hi"]), bulk("hi"));
+    assert_eq!(conn.call(&["ECHO", "hello"]), bulk("hello"));
+    assert_eq!(conn.call(&["SELECT", "0"]), Simple("OK".to_owned()));
+
+    assert_eq!(
+        conn.call(&["SET", "key1", "value1"]),
+        Simple("OK".to_owned())
+    );
+    assert_eq!(conn.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(conn.call(&["GET", "missing"]), Bulk(None));
+    assert_eq!(conn.call(&["SET", "key1", "other", "NX"]), Bulk(None));
+    assert_eq!(conn.call(&["SET", "key2", "value2", "XX"]), Bulk(None));
+    assert_eq!(
+        conn.call(&["SET", "key2", "value2", "NX"]),
+        Simple("OK".to_owned())
+    );
+    assert_eq!(
+        conn.call(&["SET", "key2", "new", "XX"]),
+        Simple("OK".to_owned())
+    );
+    assert_eq!(conn.call(&["GET", "key2"]), bulk("new"));
+
+    // the servers share the store
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(conn.call(&["GET", "key3"]), bulk("value3"));
+
+    assert_eq!(
+        conn.call(&["EXISTS", "key1", "key2", "missing"]),
+        Integer(2)
+    );
+    assert_eq!(conn.call(&["DEL", "key3", "missing"]), Integer(1));
+    assert_eq!(client.get("key3".to_owned())?, None);
+
+    assert_eq!(conn.call(&["INCR", "counter"]), Integer(1));
+    assert_eq!(conn.call(&["INCR", "counter"]), Integer(2));
+    assert_eq!(
+        conn.call(&["INCR", "key1"]),
+        Error("ERR value is not an integer or out of range".to_owned())
+    );
+
+    // expiration
+    assert_eq!(
+        conn.call(&["SET", "temp", "v", "PX", "100"]),
+        Simple("OK".to_owned())
+    );
+    assert_eq!(conn.call(&["GET", "temp"]), bulk("v"));
+    // a deadline does not remove what another client wrote since
+    assert_eq!(
+        conn.call(&["SET", "kept", "v", "PX", "100"]),
+        Simple("OK".to_owned())
+    );
+    client.set("kept".to_owned(), "other".to_owned())?;
+    match conn.call(&["INFO", "keyspace"]) {
+        Bulk(Some(info)) => assert!(info.contains("db0:keys=5,expires=2"), "{}", info),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(conn.call(&["GET", "temp"]), Bulk(None));
+    assert_eq!(client.get("temp".to_owned())?, None);
+    assert_eq!(conn.call(&["GET", "kept"]), bulk("other"));
+    assert_eq!(conn.call(&["DEL", "kept"]), Integer(1));
+    assert_eq!(conn.call(&["DBSIZE"]), Integer(3));
+
+    for i in 0..50 {
+        let key = format!("scan{}", i);
+        assert_eq!(conn.call(&["SET", &key, "v"]), Simple("OK".to_owned()));
+    }
+    let expected: HashSet<String> = (0..50).map(|i| format!("scan{}", i)).collect();
+    assert_eq!(keys(conn.call(&["KEYS", "scan*"])), expected);
+    assert_eq!(
+        keys(conn.call(&["KEYS", "key[12]"])),
+        ["key1", "key2"].iter().map(|key| key.to_string()).collect()
+    );
+    let mut scanned = HashSet::new();
+    let mut cursor = "0".to_owned();
+    for i in 0.. {
+        // keys written meanwhile do not hide the others
+        client.set(format!("during{}", i), "v".to_owned())?;
+        let reply = conn.call(&["SCAN", &cursor, "MATCH", "scan*", "COUNT", "7"]);
+        let mut reply = match reply {
+            Array(reply) => reply,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        scanned.extend(keys(reply.pop().unwrap()));
+        cursor = match reply.pop().unwrap() {
+            Bulk(Some(cursor)) => cursor,
+            reply => panic!("unexpected cursor {:?}", reply),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(scanned, expected);
+
+    assert_eq!(
+        conn.call(&["FLUSHALL"]),
+        Error("ERR unknown command 'flushall'".to_owned())
+    );
+    assert_eq!(
+        conn.call(&["GET"]),
+        Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+
+    // pipelined commands are answered in order
+    conn.writer
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$2\r\np1\r\n$1\r\na\r\n*2\r\n$3\r\nGET\r\n$2\r\np1\r\nPING\r\n",
+        )
+        .unwrap();
+    assert_eq!(conn.read(), Simple("OK".to_owned()));
+    assert_eq!(conn.read(), bulk("a"));
+    assert_eq!(conn.read(), Simple("PONG".to_owned()));
+
+    assert_eq!(conn.call(&["QUIT"]), Simple("OK".to_owned()));
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // a protocol error is answered before the connection is closed
+    let mut conn = Conn::connect(resp_addr);
+    conn.writer.write_all(b"*1\r\n$x\r\n").unwrap();
+    match conn.read() {
+        Error(e) => assert!(e.starts_with("ERR Protocol error"), "{}", e),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(TcpStream::connect(resp_addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_server_resp() -> Result<()> {
+    resp_commands("127.0.0.1:4023", "127.0.0.1:4024", |store| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .with_resp("127.0.0.1:4024".parse().unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4023")))
+    })
+}
+
+#[test]
+fn async_server_resp() -> Result<()> {
+    resp_commands("127.0.0.1:4025", "127.0.0.1:4026", |store| {
+        let server = AsyncKvsServer::new(store, 2).with_resp("127.0.0.1:4026".parse().unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4025")))
+    })
+}
@@ -0,0 +1,212 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsServer, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Protocol, Result,
+    ShutdownHandle,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// `run` should return soon after a shutdown, with idle connections open, and keep the data
+fn graceful_shutdown<F>(addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?);
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let _idle = KvsClient::connect(addr)?;
+
+    let started = Instant::now();
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert!(KvsClient::connect(addr).is_err());
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_server_shutdown() -> Result<()> {
+    graceful_shutdown("127.0.0.1:4015", |store| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4015")))
+    })
+}
+
+#[test]
+fn async_server_shutdown() -> Result<()> {
+    graceful_shutdown("127.0.0.1:4016", |store| {
+        let server = AsyncKvsServer::new(store, 2);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4016")))
+    })
+}
+
+// A server shut down before it runs should return right after binding
+#[test]
+fn shutdown_before_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    server.shutdown_handle().shutdown();
+    server.run("127.0.0.1:4017")
+}
+
+fn frame(opcode: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = ((9 + payload.len()) as u32).to_be_bytes().to_vec();
+    frame.push(opcode);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn read_frame(tcp: &mut TcpStream) -> (u8, u64, Vec<u8>) {
+    let mut header = [0; 13];
+    tcp.read_exact(&mut header).unwrap();
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let mut id = [0; 8];
+    id.copy_from_slice(&header[5..]);
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize - 9];
+    tcp.read_exact(&mut payload).unwrap();
+    (header[4], u64::from_be_bytes(id), payload)
+}
+
+// Binary and JSON clients should share a server, a malformed binary request is answered and skipped
+fn protocols<F>(addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?);
+    thread::sleep(Duration::from_secs(1));
+
+    let mut json = KvsClient::connect_with(addr, Protocol::Json)?;
+    json.set("key1".to_owned(), "value1".to_owned())?;
+    let mut binary = KvsClient::connect_with(addr, Protocol::Binary)?;
+    assert_eq!(binary.get("key1".to_owned())?, Some("value1".to_owned()));
+    let large = "v".repeat(1024 * 1024);
+    binary.set("key2".to_owned(), large.clone())?;
+    assert_eq!(json.get("key2".to_owned())?, Some(large));
+    // JSON requests end where their value does, whatever the strings hold
+    json.set("key\"}{3".to_owned(), "value\\\"]3".to_owned())?;
+    assert_eq!(
+        binary.get("key\"}{3".to_owned())?,
+        Some("value\\\"]3".to_owned())
+    );
+
+    let mut tcp = TcpStream::connect(addr)?;
+    tcp.write_all(b"KVSB\x01")?;
+    let mut hello = [0; 5];
+    tcp.read_exact(&mut hello)?;
+    assert_eq!(&hello, b"KVSB\x01");
+    tcp.write_all(&frame(99, 7, b""))?;
+    tcp.write_all(&frame(1, 8, b"\xff"))?;
+    tcp.write_all(&frame(1, 9, &bincode::serialize("key1").unwrap()))?;
+    // every response has its error as the second variant
+    for &id in &[7, 8] {
+        let (_, resp_id, payload) = read_frame(&mut tcp);
+        assert_eq!(resp_id, id);
+        assert_eq!(&payload[..4], &1u32.to_le_bytes());
+    }
+    let (opcode, id, payload) = read_frame(&mut tcp);
+    assert_eq!((opcode, id), (1, 9));
+    let value: std::result::Result<Option<String>, ()> = bincode::deserialize(&payload).unwrap();
+    assert_eq!(value, Ok(Some("value1".to_owned())));
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn kvs_server_protocols() -> Result<()> {
+    protocols("127.0.0.1:4019", |store| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4019")))
+    })
+}
+
+#[test]
+fn async_server_protocols() -> Result<()> {
+    protocols("127.0.0.1:4020", |store| {
+        let server = AsyncKvsServer::new(store, 2);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4020")))
+    })
+}
+
+// A pipeline should send requests before reading any response and sort out the responses
+fn pipelining<F>(addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?);
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect_with(addr, Protocol::Binary)?;
+    {
+        let mut pipeline = client.pipeline()?;
+        let sets = (0..5000)
+            .map(|i| pipeline.set(format!("key{}", i), format!("value{}", i)))
+            .collect::<Result<Vec<_>>>()?;
+        for set in sets {
+            pipeline.wait(set)?;
+        }
+        let gets = (0..5000)
+            .map(|i| pipeline.get(format!("key{}", i)))
+            .collect::<Result<Vec<_>>>()?;
+        for (i, get) in gets.into_iter().enumerate().rev() {
+            assert_eq!(pipeline.wait(get)?, Some(format!("value{}", i)));
+        }
+
+        let too_large = pipeline.get("k".repeat(64 * 1024 + 1))?;
+        let remove = pipeline.remove("key0".to_owned())?;
+        pipeline.wait(remove)?;
+        assert!(matches!(
+            pipeline.wait(too_large),
+            Err(KvsError::KeyTooLarge { .. })
+        ));
+        // read when the pipeline is dropped
+        let _unread = pipeline.get("key1".to_owned())?;
+    }
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut json = KvsClient::connect_with(addr, Protocol::Json)?;
+    assert!(matches!(json.pipeline(), Err(KvsError::Protocol(_))));
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn kvs_server_pipelining() -> Result<()> {
+    pipelining("127.0.0.1:4021", |store| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4021")))
+    })
+}
+
+#[test]
+fn async_server_pipelining() -> Result<()> {
+    pipelining("127.0.0.1:4022", |store| {
+        let server = AsyncKvsServer::new(store, 2);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4022")))
+    })
+}
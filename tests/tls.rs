@@ -0,0 +1,226 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsServer, ClientTls, Event, KvStore, KvsClient, KvsServer, Protocol, Result, ServerTls,
+    ShutdownHandle,
+};
+use predicates::str::contains;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Generates a CA, a server certificate for localhost and a client certificate signed by it,
+// and another CA signing nothing, as PEM files in a temporary directory.
+struct Certs {
+    dir: TempDir,
+}
+
+impl Certs {
+    fn generate() -> Certs {
+        let dir = TempDir::new().unwrap();
+        let (ca, ca_key) = Certs::ca(&dir, "ca");
+        Certs::ca(&dir, "other-ca");
+        for (name, san) in &[("server", "localhost"), ("client", "client")] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![san.to_string()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            fs::write(dir.path().join(format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(
+                dir.path().join(format!("{}.key", name)),
+                key.serialize_pem(),
+            )
+            .unwrap();
+        }
+        Certs { dir }
+    }
+
+    fn ca(dir: &TempDir, name: &str) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.path().join(format!("{}.pem", name)), cert.pem()).unwrap();
+        (cert, key)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.path().join(file)
+    }
+
+    fn server(&self) -> ServerTls {
+        ServerTls::from_pem_files(self.path("server.pem"), self.path("server.key"))
+            .unwrap()
+            .with_client_ca(self.path("ca.pem"))
+            .unwrap()
+    }
+
+    fn client(&self) -> ClientTls {
+        ClientTls::from_ca_file(self.path("ca.pem"), "localhost")
+            .unwrap()
+            .with_client_cert(self.path("client.pem"), self.path("client.key"))
+            .unwrap()
+    }
+}
+
+fn tls_clients<F>(addr: &'static str, start: F) -> Result<()>
+where
+    F: FnOnce(KvStore, ServerTls) -> (ShutdownHandle, JoinHandle<Result<()>>),
+{
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, server) = start(KvStore::open(temp_dir.path())?, certs.server());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect_tls_with(addr, &certs.client(), Protocol::Binary)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    let mut pipeline = client.pipeline()?;
+    let sets: Vec<_> = (0..100)
+        .map(|i| pipeline.set(format!("key{}", i), format!("value{}", i)))
+        .collect::<Result<_>>()?;
+    for set in sets {
+        pipeline.wait(set)?;
+    }
+    drop(pipeline);
+    assert_eq!(client.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // a subscription keeps streaming over the session
+    let mut events = KvsClient::connect_tls(addr, &certs.client())?.watch("w".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    client.set("w1".to_owned(), "v1".to_owned())?;
+    assert_eq!(
+        events.next().unwrap()?,
+        Event::Set {
+            key: "w1".to_owned(),
+            value: "v1".to_owned()
+        }
+    );
+    drop(events);
+
+    // the server is verified by its CA and its name
+    let other_ca = ClientTls::from_ca_file(certs.path("other-ca.pem"), "localhost")?
+        .with_client_cert(certs.path("client.pem"), certs.path("client.key"))?;
+    assert!(KvsClient::connect_tls(addr, &other_ca).is_err());
+    let wrong_name = ClientTls::from_ca_file(certs.path("ca.pem"), "example.com")?
+        .with_client_cert(certs.path("client.pem"), certs.path("client.key"))?;
+    assert!(KvsClient::connect_tls(addr, &wrong_name).is_err());
+
+    // and clients by theirs, which the server may only refuse after the handshake
+    let anonymous = ClientTls::from_ca_file(certs.path("ca.pem"), "localhost")?;
+    assert!(KvsClient::connect_tls(addr, &anonymous)
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+    assert!(KvsClient::connect_with(addr, Protocol::Binary).is_err());
+
+    // the refused clients did not break the server
+    let mut client = KvsClient::connect_tls(addr, &certs.client())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(TcpStream::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_server_tls() -> Result<()> {
+    tls_clients("127.0.0.1:4031", |store, tls| {
+        let server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap()).with_tls(tls);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4031")))
+    })
+}
+
+#[test]
+fn async_server_tls() -> Result<()> {
+    tls_clients("127.0.0.1:4032", |store, tls| {
+        let server = AsyncKvsServer::new(store, 2).with_tls(tls);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || server.run("127.0.0.1:4032")))
+    })
+}
+
+#[test]
+fn missing_tls_files() {
+    let certs = Certs::generate();
+    let missing = certs.path("missing.pem");
+    assert!(ServerTls::from_pem_files(&missing, certs.path("server.key")).is_err());
+    // a certificate holds no private key
+    assert!(ServerTls::from_pem_files(certs.path("server.pem"), certs.path("server.pem")).is_err());
+    assert!(ClientTls::from_ca_file(&missing, "localhost").is_err());
+    assert!(ClientTls::from_ca_file(certs.path("ca.pem"), "not a name").is_err());
+}
+
+// `kvs-server` and `kvs-client` should talk over TLS with the --tls-* options
+#[test]
+fn cli_tls() {
+    let addr = "127.0.0.1:4033";
+    let certs = Certs::generate();
+    let path = |file: &str| certs.path(file).to_str().unwrap().to_owned();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .args([
+            "--tls-cert",
+            &path("server.pem"),
+            "--tls-key",
+            &path("server.key"),
+        ])
+        .args(["--tls-client-ca", &path("ca.pem")])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str], tls: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).args(tls);
+        cmd
+    };
+    let (ca, cert, key) = (path("ca.pem"), path("client.pem"), path("client.key"));
+    let tls = [
+        "--tls-ca",
+        &ca,
+        "--tls-server-name",
+        "localhost",
+        "--tls-cert",
+        &cert,
+        "--tls-key",
+        &key,
+    ];
+    client(&["set", "key1", "value1"], &tls).assert().success();
+    client(&["get", "key1"], &tls)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // the certificate is for localhost, not the IP of --addr
+    client(
+        &["get", "key1"],
+        &["--tls-ca", &ca, "--tls-cert", &cert, "--tls-key", &key],
+    )
+    .assert()
+    .failure();
+    client(
+        &["get", "key1"],
+        &["--tls-ca", &ca, "--tls-server-name", "localhost"],
+    )
+    .assert()
+    .failure();
+    client(&["get", "key1"], &[]).assert().failure();
+    client(&["get", "key1"], &["--tls-server-name", "localhost"])
+        .assert()
+        .failure()
+        .stderr(contains("--tls-ca"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}